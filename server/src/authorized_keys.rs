@@ -0,0 +1,281 @@
+use crate::crypto::SignatureAlgorithm;
+use crate::file_stamp::FileStamp;
+use crate::transparency::TransparencyLog;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Client signing keys allowed to complete a handshake, each mapped to the
+/// username it authenticates as.
+///
+/// The file format follows OpenSSH's `authorized_keys`: one key per line as
+/// `<algorithm> <hex public key> <username>`, with blank lines and `#`
+/// comments ignored. `<algorithm>` is a wire identifier such as `ML-DSA-65`
+/// or `Dilithium2`. The path may also be a directory, in which case every
+/// regular file in it is read, so devices can be enrolled one file each.
+#[derive(Debug, Default)]
+pub struct AuthorizedKeys {
+    entries: HashMap<(SignatureAlgorithm, Vec<u8>), String>,
+}
+
+impl AuthorizedKeys {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut keys = Self::default();
+        if path.is_dir() {
+            for file in key_files(path)? {
+                keys.parse(&fs::read_to_string(&file)?, &file)?;
+            }
+        } else {
+            keys.parse(&fs::read_to_string(path)?, path)?;
+        }
+        Ok(keys)
+    }
+
+    fn parse(&mut self, contents: &str, source: &Path) -> io::Result<()> {
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: {}", source.display(), index + 1, reason),
+                )
+            };
+
+            let mut fields = line.split_whitespace();
+            let (Some(algorithm), Some(public_key), Some(username), None) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid("expected `<algorithm> <public key> <username>`"));
+            };
+            let algorithm = SignatureAlgorithm::from_name(algorithm)
+                .ok_or_else(|| invalid("unknown signature algorithm"))?;
+            let public_key =
+                hex::decode(public_key).map_err(|_| invalid("public key is not hex"))?;
+            self.entries
+                .insert((algorithm, public_key), username.to_string());
+        }
+        Ok(())
+    }
+
+    /// The username enrolled for `public_key`, if any.
+    pub fn username_for(&self, algorithm: SignatureAlgorithm, public_key: &[u8]) -> Option<&str> {
+        self.entries
+            .get(&(algorithm, public_key.to_vec()))
+            .map(String::as_str)
+    }
+
+    /// Every enrolled key with the username it authenticates as.
+    pub fn iter(&self) -> impl Iterator<Item = (SignatureAlgorithm, &[u8], &str)> {
+        self.entries
+            .iter()
+            .map(|((algorithm, public_key), username)| {
+                (*algorithm, public_key.as_slice(), username.as_str())
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Shared, reloadable view of the authorized keys at one path.
+///
+/// `reload_if_changed` is cheap enough to poll: it only re-reads the keys
+/// when a file under the path has changed. A file that fails to
+/// parse leaves the previous keys in place.
+pub struct AuthorizedKeyStore {
+    path: PathBuf,
+    state: RwLock<(AuthorizedKeys, Option<Vec<FileStamp>>)>,
+    transparency: Option<Arc<TransparencyLog>>,
+}
+
+impl AuthorizedKeyStore {
+    /// Loads the keys at `path`. A missing path authorizes nobody until it
+    /// is created.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let store = Self {
+            path,
+            state: RwLock::new((AuthorizedKeys::default(), None)),
+            transparency: None,
+        };
+        store.reload_if_changed()?;
+        Ok(store)
+    }
+
+    /// Logs the loaded keys to `log`, and from then on every reload's keys
+    /// before they are trusted. A reload that cannot be logged is refused.
+    pub fn with_transparency_log(mut self, log: Option<Arc<TransparencyLog>>) -> io::Result<Self> {
+        if let Some(log) = &log {
+            log.record_enrollments(&self.state.read().unwrap().0)?;
+        }
+        self.transparency = log;
+        Ok(self)
+    }
+
+    pub fn username_for(&self, algorithm: SignatureAlgorithm, public_key: &[u8]) -> Option<String> {
+        self.state
+            .read()
+            .unwrap()
+            .0
+            .username_for(algorithm, public_key)
+            .map(str::to_string)
+    }
+
+    /// Re-reads the keys if they changed on disk. Returns whether they did.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let stamps = key_stamps(&self.path)?;
+        if stamps == self.state.read().unwrap().1 {
+            return Ok(false);
+        }
+        let keys = match stamps {
+            Some(_) => AuthorizedKeys::load(&self.path)?,
+            None => AuthorizedKeys::default(),
+        };
+        if let Some(log) = &self.transparency {
+            log.record_enrollments(&keys)?;
+        }
+        log::info!(
+            "Loaded {} authorized client key(s) from {}",
+            keys.len(),
+            self.path.display()
+        );
+        *self.state.write().unwrap() = (keys, stamps);
+        Ok(true)
+    }
+}
+
+fn key_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Stamps of the path, and of every file in it if it is a directory.
+/// `None` if the path does not exist.
+fn key_stamps(path: &Path) -> io::Result<Option<Vec<FileStamp>>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut stamps = vec![FileStamp::of(&metadata)?];
+    if metadata.is_dir() {
+        for file in key_files(path)? {
+            stamps.push(FileStamp::of(&fs::metadata(file)?)?);
+        }
+    }
+    Ok(Some(stamps))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::SigningIdentity;
+
+    #[test]
+    fn enrolled_keys_map_to_usernames_and_reload() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let alice = SigningIdentity::generate()
+            .public_key(SignatureAlgorithm::MlDsa65)
+            .unwrap()
+            .to_vec();
+        let bob = SigningIdentity::generate()
+            .public_key(SignatureAlgorithm::Dilithium2)
+            .unwrap()
+            .to_vec();
+
+        fs::write(
+            dir.join("alice"),
+            format!("# laptop\nML-DSA-65 {} alice\n", hex::encode(&alice)),
+        )
+        .unwrap();
+        let store = AuthorizedKeyStore::open(dir.clone()).unwrap();
+        assert_eq!(
+            store.username_for(SignatureAlgorithm::MlDsa65, &alice),
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            store.username_for(SignatureAlgorithm::Dilithium2, &alice),
+            None
+        );
+        assert_eq!(
+            store.username_for(SignatureAlgorithm::Dilithium2, &bob),
+            None
+        );
+
+        fs::write(
+            dir.join("bob"),
+            format!("Dilithium2 {} bob\n", hex::encode(&bob)),
+        )
+        .unwrap();
+        fs::remove_file(dir.join("alice")).unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(
+            store.username_for(SignatureAlgorithm::MlDsa65, &alice),
+            None
+        );
+        assert_eq!(
+            store.username_for(SignatureAlgorithm::Dilithium2, &bob),
+            Some("bob".to_string())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_key_file_replaced_within_one_mtime_tick_is_reloaded() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("authorized_keys");
+        let alice = SigningIdentity::generate()
+            .public_key(SignatureAlgorithm::MlDsa65)
+            .unwrap()
+            .to_vec();
+        let bob = SigningIdentity::generate()
+            .public_key(SignatureAlgorithm::MlDsa65)
+            .unwrap()
+            .to_vec();
+        fs::write(&path, format!("ML-DSA-65 {} alice\n", hex::encode(&alice))).unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let store = AuthorizedKeyStore::open(path.clone()).unwrap();
+
+        // Swap in a new file carrying the old one's modification time.
+        let tmp = dir.join("authorized_keys.tmp");
+        fs::write(&tmp, format!("ML-DSA-65 {} bob\n", hex::encode(&bob))).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&tmp)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        fs::rename(&tmp, &path).unwrap();
+
+        assert!(store.reload_if_changed().unwrap());
+        assert_eq!(
+            store.username_for(SignatureAlgorithm::MlDsa65, &alice),
+            None
+        );
+        assert_eq!(
+            store.username_for(SignatureAlgorithm::MlDsa65, &bob),
+            Some("bob".to_string())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+use crate::crypto::{signer_for, CryptoError, SignatureAlgorithm};
+use crate::identity::{fingerprint, SigningIdentity};
+use crate::keystore::{self, KeyEncryptionKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Domain separator for certificate signatures, so a CA signature can never
+/// be replayed as a handshake signature or the other way round.
+const CERTIFICATE_LABEL: &[u8] = b"pqc-vpn server certificate v1";
+
+/// Offline signing key that vouches for server identities. It only ever
+/// signs [`ServerCertificate`]s and should stay off the exit nodes.
+pub struct CertificateAuthority {
+    algorithm: SignatureAlgorithm,
+    public_key: Vec<u8>,
+    secret_key: Zeroizing<Vec<u8>>,
+}
+
+/// Public half of a [`CertificateAuthority`]. Clients configure this and
+/// accept any server holding a certificate it signed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaPublicKey {
+    pub algorithm: SignatureAlgorithm,
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct CaKeyFile {
+    #[zeroize(skip)]
+    algorithm: SignatureAlgorithm,
+    public_key: Vec<u8>,
+    secret_key: Vec<u8>,
+}
+
+/// Binds a hostname to the server's long-term signing keys for a validity
+/// window. `public_keys` doubles as the list of algorithms the server may
+/// authenticate with under this certificate.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerCertificate {
+    pub serial: u64,
+    pub hostname: String,
+    /// Unix seconds.
+    pub not_before: u64,
+    /// Unix seconds.
+    pub not_after: u64,
+    pub public_keys: BTreeMap<SignatureAlgorithm, Vec<u8>>,
+    /// Fingerprint of the issuing CA key.
+    pub issuer: String,
+    pub signature_algorithm: SignatureAlgorithm,
+    pub signature: Vec<u8>,
+}
+
+/// The fields a certificate signature covers, in a fixed order.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    serial: u64,
+    hostname: &'a str,
+    not_before: u64,
+    not_after: u64,
+    public_keys: &'a BTreeMap<SignatureAlgorithm, Vec<u8>>,
+    issuer: &'a str,
+    signature_algorithm: SignatureAlgorithm,
+}
+
+impl fmt::Debug for CertificateAuthority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateAuthority")
+            .field("algorithm", &self.algorithm)
+            .field("fingerprint", &fingerprint(&self.public_key))
+            .finish_non_exhaustive()
+    }
+}
+
+impl CertificateAuthority {
+    pub fn generate(algorithm: SignatureAlgorithm) -> Self {
+        let (public_key, secret_key) = signer_for(algorithm).keypair();
+        Self {
+            algorithm,
+            public_key,
+            secret_key,
+        }
+    }
+
+    /// Reads a CA key written by [`CertificateAuthority::save`], checking
+    /// that its two halves belong together.
+    pub fn load(path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<Self> {
+        let contents = keystore::read_private(path, kek)?;
+        let file: CaKeyFile = serde_json::from_slice(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let ca = Self {
+            algorithm: file.algorithm,
+            public_key: file.public_key.clone(),
+            secret_key: Zeroizing::new(file.secret_key.clone()),
+        };
+        let malformed = |_| io::Error::new(io::ErrorKind::InvalidData, "malformed CA key");
+        let signature = ca.sign(b"pqc-vpn CA key check").map_err(malformed)?;
+        ca.public()
+            .verify_signature(b"pqc-vpn CA key check", &signature)
+            .map_err(malformed)?;
+        Ok(ca)
+    }
+
+    /// Writes the CA key to `path`, readable by the owner only and
+    /// encrypted under `kek` if one is given. Refuses to overwrite an
+    /// existing key.
+    pub fn save(&self, path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<()> {
+        let file = CaKeyFile {
+            algorithm: self.algorithm,
+            public_key: self.public_key.clone(),
+            secret_key: self.secret_key.to_vec(),
+        };
+        keystore::write_private(path, &Zeroizing::new(serde_json::to_vec(&file)?), kek)
+    }
+
+    pub fn public(&self) -> CaPublicKey {
+        CaPublicKey {
+            algorithm: self.algorithm,
+            public_key: self.public_key.clone(),
+        }
+    }
+
+    pub(crate) fn sign(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        signer_for(self.algorithm).sign(&self.secret_key, data)
+    }
+
+    /// Signs a certificate for `hostname` covering `identity`'s keys for
+    /// `algorithms`, valid from `not_before` to `not_after`.
+    pub fn issue(
+        &self,
+        serial: u64,
+        hostname: &str,
+        identity: &SigningIdentity,
+        algorithms: &[SignatureAlgorithm],
+        not_before: u64,
+        not_after: u64,
+    ) -> Result<ServerCertificate, CryptoError> {
+        if not_after <= not_before {
+            return Err(CryptoError::CertificateError("empty validity window"));
+        }
+        let public_keys = algorithms
+            .iter()
+            .map(|&algorithm| {
+                identity
+                    .public_key(algorithm)
+                    .map(|key| (algorithm, key.to_vec()))
+                    .ok_or_else(|| {
+                        CryptoError::UnsupportedAlgorithm(algorithm.as_str().to_string())
+                    })
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        if public_keys.is_empty() {
+            return Err(CryptoError::CertificateError("no algorithms allowed"));
+        }
+        let mut certificate = ServerCertificate {
+            serial,
+            hostname: hostname.to_ascii_lowercase(),
+            not_before,
+            not_after,
+            public_keys,
+            issuer: fingerprint(&self.public_key),
+            signature_algorithm: self.algorithm,
+            signature: Vec::new(),
+        };
+        certificate.signature = self.sign(&certificate.signed_bytes())?;
+        Ok(certificate)
+    }
+}
+
+impl CaPublicKey {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+
+    pub(crate) fn verify_signature(
+        &self,
+        data: &[u8],
+        signature: &[u8],
+    ) -> Result<(), CryptoError> {
+        signer_for(self.algorithm).verify(data, signature, &self.public_key)
+    }
+}
+
+impl ServerCertificate {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes the certificate next to `path` and renames it into place, so
+    /// a server polling the file never reads half of it.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut staging = path.as_os_str().to_owned();
+        staging.push(".tmp");
+        fs::write(&staging, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&staging, path)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let fields = SignedFields {
+            serial: self.serial,
+            hostname: &self.hostname,
+            not_before: self.not_before,
+            not_after: self.not_after,
+            public_keys: &self.public_keys,
+            issuer: &self.issuer,
+            signature_algorithm: self.signature_algorithm,
+        };
+        let mut bytes = CERTIFICATE_LABEL.to_vec();
+        bytes.extend(serde_json::to_vec(&fields).expect("certificate fields serialize"));
+        bytes
+    }
+
+    /// Checks that `ca` issued this certificate, that it names `hostname`
+    /// and that `now` (Unix seconds) falls inside its validity window.
+    pub fn verify(&self, ca: &CaPublicKey, hostname: &str, now: u64) -> Result<(), CryptoError> {
+        if self.issuer != ca.fingerprint() || self.signature_algorithm != ca.algorithm {
+            return Err(CryptoError::CertificateError("issued by a different CA"));
+        }
+        ca.verify_signature(&self.signed_bytes(), &self.signature)
+            .map_err(|_| CryptoError::CertificateError("bad CA signature"))?;
+        if !self.hostname.eq_ignore_ascii_case(hostname) {
+            return Err(CryptoError::CertificateError("hostname mismatch"));
+        }
+        if now < self.not_before {
+            return Err(CryptoError::CertificateError("not yet valid"));
+        }
+        if now >= self.not_after {
+            return Err(CryptoError::CertificateError("expired"));
+        }
+        Ok(())
+    }
+
+    /// The certified key for `algorithm`, if the certificate allows it.
+    pub fn public_key(&self, algorithm: SignatureAlgorithm) -> Option<&[u8]> {
+        self.public_keys.get(&algorithm).map(Vec::as_slice)
+    }
+
+    /// Whether every key in the certificate belongs to `identity`.
+    pub fn matches(&self, identity: &SigningIdentity) -> bool {
+        self.public_keys
+            .iter()
+            .all(|(&algorithm, key)| identity.public_key(algorithm) == Some(key.as_slice()))
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn certificates_verify_only_for_their_ca_host_and_window() {
+        let ca = CertificateAuthority::generate(SignatureAlgorithm::Dilithium5);
+        let identity = SigningIdentity::generate();
+        let certificate = ca
+            .issue(
+                7,
+                "Exit-3.vpn.example",
+                &identity,
+                &[SignatureAlgorithm::MlDsa65],
+                1_000,
+                2_000,
+            )
+            .unwrap();
+        assert!(certificate.matches(&identity));
+        assert!(!certificate.matches(&SigningIdentity::generate()));
+        certificate
+            .verify(&ca.public(), "exit-3.vpn.example", 1_500)
+            .unwrap();
+
+        for (hostname, now) in [("exit-4.vpn.example", 1_500), ("exit-3.vpn.example", 999)] {
+            assert!(matches!(
+                certificate.verify(&ca.public(), hostname, now),
+                Err(CryptoError::CertificateError(_))
+            ));
+        }
+        assert!(certificate
+            .verify(&ca.public(), "exit-3.vpn.example", 2_000)
+            .is_err());
+        let other_ca = CertificateAuthority::generate(SignatureAlgorithm::Dilithium5);
+        assert!(certificate
+            .verify(&other_ca.public(), "exit-3.vpn.example", 1_500)
+            .is_err());
+
+        // Widening the certificate after signing breaks the signature.
+        let mut tampered = certificate.clone();
+        tampered.not_after = u64::MAX;
+        assert!(tampered
+            .verify(&ca.public(), "exit-3.vpn.example", 1_500)
+            .is_err());
+    }
+
+    #[test]
+    fn ca_keys_round_trip_through_disk() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let ca = CertificateAuthority::generate(SignatureAlgorithm::Dilithium3);
+        ca.save(&dir.join("ca.key"), None).unwrap();
+        assert!(ca.save(&dir.join("ca.key"), None).is_err());
+        ca.public().save(&dir.join("ca.pub")).unwrap();
+
+        let loaded = CertificateAuthority::load(&dir.join("ca.key"), None).unwrap();
+        assert_eq!(loaded.public(), ca.public());
+        assert_eq!(CaPublicKey::load(&dir.join("ca.pub")).unwrap(), ca.public());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
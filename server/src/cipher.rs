@@ -0,0 +1,91 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use serde::{Deserialize, Serialize};
+
+/// AEAD identifiers as they appear on the wire. AES-256-GCM is the default
+/// for clients that do not ask for anything else; the ChaCha20 variants are
+/// for hosts without AES hardware.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AeadAlgorithm {
+    #[default]
+    #[serde(rename = "AES-256-GCM")]
+    Aes256Gcm,
+    #[serde(rename = "ChaCha20-Poly1305")]
+    ChaCha20Poly1305,
+    #[serde(rename = "XChaCha20-Poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl AeadAlgorithm {
+    pub const ALL: [AeadAlgorithm; 3] = [
+        AeadAlgorithm::Aes256Gcm,
+        AeadAlgorithm::ChaCha20Poly1305,
+        AeadAlgorithm::XChaCha20Poly1305,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AeadAlgorithm::Aes256Gcm => "AES-256-GCM",
+            AeadAlgorithm::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+            AeadAlgorithm::XChaCha20Poly1305 => "XChaCha20-Poly1305",
+        }
+    }
+}
+
+/// A keyed AEAD instance. Nonces are always derived from the frame sequence
+/// number, right-aligned and zero-padded to the algorithm's nonce size.
+pub enum SessionCipher {
+    // Boxed: the expanded AES key schedule dwarfs the ChaCha20 states.
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
+
+impl SessionCipher {
+    pub fn new(algorithm: AeadAlgorithm, key: &[u8; 32]) -> Self {
+        match algorithm {
+            AeadAlgorithm::Aes256Gcm => {
+                SessionCipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into())))
+            }
+            AeadAlgorithm::ChaCha20Poly1305 => {
+                SessionCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into()))
+            }
+            AeadAlgorithm::XChaCha20Poly1305 => {
+                SessionCipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key.into()))
+            }
+        }
+    }
+
+    pub fn encrypt(&self, seq: u64, payload: Payload) -> Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            SessionCipher::Aes256Gcm(cipher) => cipher.encrypt(&nonce::<12>(seq).into(), payload),
+            SessionCipher::ChaCha20Poly1305(cipher) => {
+                cipher.encrypt(&nonce::<12>(seq).into(), payload)
+            }
+            SessionCipher::XChaCha20Poly1305(cipher) => {
+                cipher.encrypt(&nonce::<24>(seq).into(), payload)
+            }
+        }
+    }
+
+    pub fn decrypt(&self, seq: u64, payload: Payload) -> Result<Vec<u8>, aes_gcm::aead::Error> {
+        match self {
+            SessionCipher::Aes256Gcm(cipher) => cipher.decrypt(&nonce::<12>(seq).into(), payload),
+            SessionCipher::ChaCha20Poly1305(cipher) => {
+                cipher.decrypt(&nonce::<12>(seq).into(), payload)
+            }
+            SessionCipher::XChaCha20Poly1305(cipher) => {
+                cipher.decrypt(&nonce::<24>(seq).into(), payload)
+            }
+        }
+    }
+}
+
+fn nonce<const N: usize>(seq: u64) -> [u8; N] {
+    let mut nonce_bytes = [0u8; N];
+    nonce_bytes[N - 8..].copy_from_slice(&seq.to_be_bytes());
+    nonce_bytes
+}
@@ -0,0 +1,596 @@
+use crate::certs::{unix_time, CaPublicKey, CertificateAuthority, ServerCertificate};
+use crate::crypto::SignatureAlgorithm;
+use crate::identity::{fingerprint, SigningIdentity};
+use crate::keyfile::{KeyAlgorithm, KeyFile, KeyFormat, KeyPair, PublicKey};
+use crate::keystore::{self, KeyEncryptionKey};
+use crate::revocation::{RevocationList, RevokedKey};
+use crate::rotation;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const CERTS_USAGE: &str = "\
+usage: quantum-vpn-server certs <command>
+
+  init-ca --key <ca.key> --public <ca.pub> [--algorithm Dilithium5]
+      Generate an offline CA key and write its public half for clients.
+  issue --ca <ca.key> --identity <identity.json> --hostname <name>
+        --serial <n> --out <cert.json> [--days 90] [--algorithms ML-DSA-65,...]
+      Certify a server identity's keys for one hostname.
+  show <cert.json> [--ca <ca.pub> --hostname <name>]
+      Print a certificate and, given a CA, check it.
+  revoke --ca <ca.key> --list <revoked.json> (--fingerprint <sha256:...> | --public-key <hex>)
+         [--reason <text>]
+      Add a client or server key to the signed revocation list, creating it
+      if needed. Servers polling the list drop sessions that used the key.
+  revocations <revoked.json> [--ca <ca.pub>]
+      Print a revocation list and, given a CA, check its signature.";
+
+const KEYGEN_USAGE: &str = "\
+usage: quantum-vpn-server keygen <command>
+
+  generate --algorithm <name> --out <key> [--public <pub>] [--format pem|der]
+      Write a new keypair as PKCS#8, and optionally its public half as SPKI.
+  show <file>
+      Print a key file's algorithm, OID and fingerprint.
+  convert <in> --out <out> --format pem|der
+      Re-encode a private or public key file.
+  check <private key> <public key>
+      Confirm that the two files hold the same keypair.
+  export-identity --identity <identity.json> --out-dir <dir> [--format pem|der]
+      Write each key of a server identity file to its own key file.
+  rotate-passphrase <file>...
+      Re-encrypt private key files from the current key to the new one.
+  promote-identity --identity <identity.json> --next <next.json>
+                   [--certificate <cert.json> --next-certificate <next-cert.json>]
+      End an identity rotation: the next identity (and its certificate)
+      becomes the current one, and the old identity is kept as .retired.
+
+Private keys are read and written encrypted when VPN_KEY_PASSPHRASE or
+VPN_KEK (64 hex digits) is set, or VPN_KEY_PASSPHRASE_FD / VPN_KEK_FD name a
+file descriptor to read it from. rotate-passphrase takes the new key from
+the same variables prefixed VPN_NEW_ instead of VPN_.";
+
+/// Runs `quantum-vpn-server keygen ...`. `args` excludes the program name
+/// and the `keygen` word itself.
+pub fn keygen(args: &[String]) -> io::Result<()> {
+    let Some((command, rest)) = args.split_first() else {
+        return Err(usage(KEYGEN_USAGE));
+    };
+    let flags = Flags::parse(rest, KEYGEN_USAGE)?;
+    let format = match flags.optional("format") {
+        None | Some("pem") => KeyFormat::Pem,
+        Some("der") => KeyFormat::Der,
+        Some(other) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown key format {}", other),
+            ))
+        }
+    };
+    let kek = KeyEncryptionKey::from_env()?;
+    let kek = kek.as_ref();
+    match command.as_str() {
+        "generate" => {
+            let name = flags.required("algorithm")?;
+            let algorithm = KeyAlgorithm::from_name(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown algorithm {}", name),
+                )
+            })?;
+            let key_pair = KeyPair::generate(algorithm);
+            key_pair.save(Path::new(flags.required("out")?), format, kek)?;
+            if let Some(public) = flags.optional("public") {
+                key_pair.public().save(Path::new(public), format)?;
+            }
+            print_public_key(&key_pair.public());
+            Ok(())
+        }
+        "show" => {
+            match KeyFile::load(Path::new(flags.positional(0)?), kek)? {
+                KeyFile::Private(key_pair) => {
+                    println!("type:        private key (halves match)");
+                    print_public_key(&key_pair.public());
+                }
+                KeyFile::Public(public_key) => {
+                    println!("type:        public key");
+                    print_public_key(&public_key);
+                }
+            }
+            Ok(())
+        }
+        "convert" => {
+            if flags.optional("format").is_none() {
+                return Err(usage(KEYGEN_USAGE));
+            }
+            let out = Path::new(flags.required("out")?);
+            match KeyFile::load(Path::new(flags.positional(0)?), kek)? {
+                KeyFile::Private(key_pair) => key_pair.save(out, format, kek),
+                KeyFile::Public(public_key) => public_key.save(out, format),
+            }
+        }
+        "check" => {
+            let key_pair = KeyPair::load(Path::new(flags.positional(0)?), kek)?;
+            let public_key = PublicKey::load(Path::new(flags.positional(1)?))?;
+            if key_pair.public() != public_key {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the private key does not belong to that public key",
+                ));
+            }
+            println!(
+                "ok: {} {}",
+                public_key.algorithm.as_str(),
+                public_key.fingerprint()
+            );
+            Ok(())
+        }
+        "export-identity" => {
+            let identity = SigningIdentity::load(Path::new(flags.required("identity")?), kek)?;
+            let out_dir = Path::new(flags.required("out-dir")?);
+            fs::create_dir_all(out_dir)?;
+            let extension = match format {
+                KeyFormat::Pem => "pem",
+                KeyFormat::Der => "der",
+            };
+            for algorithm in identity.algorithms() {
+                let Some(key_pair) = identity.key_pair(algorithm) else {
+                    continue;
+                };
+                let path = out_dir.join(format!("{}.{}", algorithm.as_str(), extension));
+                key_pair.save(&path, format, kek)?;
+                println!("{}", path.display());
+            }
+            Ok(())
+        }
+        "rotate-passphrase" => {
+            let new_kek = KeyEncryptionKey::new_from_env()?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "set VPN_NEW_KEY_PASSPHRASE or VPN_NEW_KEK to the new key",
+                )
+            })?;
+            if flags.positional.is_empty() {
+                return Err(usage(KEYGEN_USAGE));
+            }
+            for path in &flags.positional {
+                keystore::rotate(Path::new(path), kek, &new_kek)?;
+                println!("{}", path);
+            }
+            Ok(())
+        }
+        "promote-identity" => {
+            let certificates = match (
+                flags.optional("certificate"),
+                flags.optional("next-certificate"),
+            ) {
+                (Some(certificate), Some(next_certificate)) => {
+                    Some((certificate, next_certificate))
+                }
+                (None, None) => None,
+                _ => return Err(usage(KEYGEN_USAGE)),
+            };
+            let next_certificate = match certificates {
+                Some((certificate, next_certificate)) => {
+                    let next_certificate = ServerCertificate::load(Path::new(next_certificate))?;
+                    let next = SigningIdentity::load(Path::new(flags.required("next")?), kek)?;
+                    if !next_certificate.matches(&next) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "the next certificate does not certify the next identity",
+                        ));
+                    }
+                    Some((certificate, next_certificate))
+                }
+                None => None,
+            };
+            // Promote before replacing the certificate, so a failed promotion
+            // leaves the live certificate matching the live identity.
+            let retired = rotation::promote(
+                Path::new(flags.required("identity")?),
+                Path::new(flags.required("next")?),
+            )?;
+            println!("retired identity kept at {}", retired.display());
+            if let Some((certificate, next_certificate)) = next_certificate {
+                next_certificate.save(Path::new(certificate))?;
+            }
+            Ok(())
+        }
+        _ => Err(usage(KEYGEN_USAGE)),
+    }
+}
+
+fn print_public_key(public_key: &PublicKey) {
+    println!("algorithm:   {}", public_key.algorithm.as_str());
+    println!("oid:         {}", public_key.algorithm.oid());
+    println!("fingerprint: {}", public_key.fingerprint());
+    if let KeyAlgorithm::Signature(algorithm) = public_key.algorithm {
+        println!(
+            "enroll:      {} {} <username>",
+            algorithm.as_str(),
+            hex::encode(&public_key.public_key)
+        );
+    }
+}
+
+/// Runs `quantum-vpn-server certs ...`. `args` excludes the program name
+/// and the `certs` word itself.
+pub fn certs(args: &[String]) -> io::Result<()> {
+    let Some((command, rest)) = args.split_first() else {
+        return Err(usage(CERTS_USAGE));
+    };
+    let flags = Flags::parse(rest, CERTS_USAGE)?;
+    let kek = KeyEncryptionKey::from_env()?;
+    let kek = kek.as_ref();
+    match command.as_str() {
+        "init-ca" => {
+            let algorithm = match flags.optional("algorithm") {
+                Some(name) => parse_algorithm(name)?,
+                None => SignatureAlgorithm::Dilithium5,
+            };
+            let ca = CertificateAuthority::generate(algorithm);
+            ca.save(Path::new(flags.required("key")?), kek)?;
+            ca.public().save(Path::new(flags.required("public")?))?;
+            println!("{} CA {}", algorithm.as_str(), ca.public().fingerprint());
+            Ok(())
+        }
+        "issue" => {
+            let ca = CertificateAuthority::load(Path::new(flags.required("ca")?), kek)?;
+            let identity = SigningIdentity::load(Path::new(flags.required("identity")?), kek)?;
+            let serial = parse_number(flags.required("serial")?, "serial")?;
+            let days = match flags.optional("days") {
+                Some(days) => parse_number(days, "days")?,
+                None => 90,
+            };
+            let algorithms = match flags.optional("algorithms") {
+                Some(names) => names
+                    .split(',')
+                    .map(parse_algorithm)
+                    .collect::<io::Result<Vec<_>>>()?,
+                None => identity.algorithms().collect(),
+            };
+            let now = unix_time();
+            let certificate = ca
+                .issue(
+                    serial,
+                    flags.required("hostname")?,
+                    &identity,
+                    &algorithms,
+                    now,
+                    now.saturating_add(days.saturating_mul(24 * 60 * 60)),
+                )
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            certificate.save(Path::new(flags.required("out")?))?;
+            print_certificate(&certificate);
+            Ok(())
+        }
+        "show" => {
+            let path = flags.positional(0)?;
+            let certificate = ServerCertificate::load(Path::new(path))?;
+            print_certificate(&certificate);
+            if let Some(ca) = flags.optional("ca") {
+                let ca = CaPublicKey::load(Path::new(ca))?;
+                let hostname = flags.optional("hostname").unwrap_or(&certificate.hostname);
+                match certificate.verify(&ca, hostname, unix_time()) {
+                    Ok(()) => println!("status:      valid"),
+                    Err(e) => println!("status:      {}", e),
+                }
+            }
+            Ok(())
+        }
+        "revoke" => {
+            let ca = CertificateAuthority::load(Path::new(flags.required("ca")?), kek)?;
+            let path = Path::new(flags.required("list")?);
+            let fingerprint = match (flags.optional("fingerprint"), flags.optional("public-key")) {
+                (Some(fingerprint), None) => fingerprint.to_string(),
+                (None, Some(public_key)) => {
+                    fingerprint(&hex::decode(public_key).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidInput, "--public-key is not hex")
+                    })?)
+                }
+                _ => return Err(usage(CERTS_USAGE)),
+            };
+            let (number, mut revoked) = match RevocationList::load(path, &ca.public()) {
+                Ok(list) => (list.number, list.revoked),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (0, Vec::new()),
+                Err(e) => return Err(e),
+            };
+            if !revoked.iter().any(|key| key.fingerprint == fingerprint) {
+                revoked.push(RevokedKey {
+                    fingerprint: fingerprint.clone(),
+                    revoked_at: unix_time(),
+                    reason: flags.optional("reason").map(str::to_string),
+                });
+            }
+            let list = RevocationList::issue(&ca, number + 1, revoked)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            list.save(path)?;
+            println!("revoked {} in list {}", fingerprint, list.number);
+            Ok(())
+        }
+        "revocations" => {
+            let path = Path::new(flags.positional(0)?);
+            let list: RevocationList = serde_json::from_slice(&fs::read(path)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            println!("number:      {}", list.number);
+            println!("issued at:   {}", list.issued_at);
+            println!(
+                "issuer:      {} {}",
+                list.signature_algorithm.as_str(),
+                list.issuer
+            );
+            for key in &list.revoked {
+                println!(
+                    "revoked:     {} at {}{}",
+                    key.fingerprint,
+                    key.revoked_at,
+                    key.reason
+                        .as_ref()
+                        .map(|reason| format!(" ({})", reason))
+                        .unwrap_or_default()
+                );
+            }
+            if let Some(ca) = flags.optional("ca") {
+                match list.verify(&CaPublicKey::load(Path::new(ca))?) {
+                    Ok(()) => println!("status:      valid"),
+                    Err(e) => println!("status:      {}", e),
+                }
+            }
+            Ok(())
+        }
+        _ => Err(usage(CERTS_USAGE)),
+    }
+}
+
+fn print_certificate(certificate: &ServerCertificate) {
+    println!("serial:      {}", certificate.serial);
+    println!("hostname:    {}", certificate.hostname);
+    println!("not before:  {}", certificate.not_before);
+    println!("not after:   {}", certificate.not_after);
+    println!(
+        "issuer:      {} {}",
+        certificate.signature_algorithm.as_str(),
+        certificate.issuer
+    );
+    for (algorithm, key) in &certificate.public_keys {
+        println!("key:         {} {}", algorithm.as_str(), fingerprint(key));
+    }
+}
+
+/// `--name value` pairs plus bare positional arguments.
+struct Flags<'a> {
+    named: HashMap<&'a str, &'a str>,
+    positional: Vec<&'a str>,
+    usage: &'static str,
+}
+
+impl<'a> Flags<'a> {
+    fn parse(args: &'a [String], usage_text: &'static str) -> io::Result<Self> {
+        let mut flags = Self {
+            named: HashMap::new(),
+            positional: Vec::new(),
+            usage: usage_text,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = args.next().ok_or_else(|| usage(usage_text))?;
+                    flags.named.insert(name, value);
+                }
+                None => flags.positional.push(arg),
+            }
+        }
+        Ok(flags)
+    }
+
+    fn optional(&self, name: &str) -> Option<&'a str> {
+        self.named.get(name).copied()
+    }
+
+    fn required(&self, name: &str) -> io::Result<&'a str> {
+        self.optional(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("missing --{}\n\n{}", name, self.usage),
+            )
+        })
+    }
+
+    fn positional(&self, index: usize) -> io::Result<&'a str> {
+        self.positional
+            .get(index)
+            .copied()
+            .ok_or_else(|| usage(self.usage))
+    }
+}
+
+fn usage(text: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, text.to_string())
+}
+
+fn parse_algorithm(name: &str) -> io::Result<SignatureAlgorithm> {
+    SignatureAlgorithm::from_name(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unknown signature algorithm {}", name),
+        )
+    })
+}
+
+fn parse_number(value: &str, name: &str) -> io::Result<u64> {
+    value.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--{} must be a number", name),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::revocation::Revocations;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn path(dir: &Path, name: &str) -> String {
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn generated_keys_survive_conversion_and_check_out() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let (key, public) = (path(&dir, "client.key"), path(&dir, "client.pub"));
+        let (der_key, der_public) = (path(&dir, "client.key.der"), path(&dir, "client.pub.der"));
+
+        keygen(&args(&[
+            "generate",
+            "--algorithm",
+            "ML-DSA-65",
+            "--out",
+            &key,
+            "--public",
+            &public,
+        ]))
+        .unwrap();
+        keygen(&args(&[
+            "convert", &key, "--out", &der_key, "--format", "der",
+        ]))
+        .unwrap();
+        keygen(&args(&[
+            "convert",
+            &public,
+            "--out",
+            &der_public,
+            "--format",
+            "der",
+        ]))
+        .unwrap();
+        keygen(&args(&["check", &der_key, &der_public])).unwrap();
+        keygen(&args(&["check", &key, &der_public])).unwrap();
+        assert_eq!(
+            KeyPair::load(Path::new(&der_key), None).unwrap().public(),
+            PublicKey::load(Path::new(&public)).unwrap()
+        );
+
+        // A key from another pair does not check out against this one.
+        let other = path(&dir, "other.key");
+        keygen(&args(&[
+            "generate",
+            "--algorithm",
+            "ML-DSA-65",
+            "--out",
+            &other,
+        ]))
+        .unwrap();
+        let err = keygen(&args(&["check", &other, &public])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn exported_identity_keys_load_back_as_the_same_identity() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let identity_path = dir.join("identity.json");
+        let identity = SigningIdentity::load_or_generate(&identity_path, &[], None).unwrap();
+        let out_dir = path(&dir, "keys");
+
+        keygen(&args(&[
+            "export-identity",
+            "--identity",
+            identity_path.to_str().unwrap(),
+            "--out-dir",
+            &out_dir,
+        ]))
+        .unwrap();
+
+        let key_pairs = identity
+            .algorithms()
+            .map(|algorithm| {
+                let file = Path::new(&out_dir).join(format!("{}.pem", algorithm.as_str()));
+                KeyPair::load(&file, None).unwrap()
+            })
+            .collect();
+        let loaded = SigningIdentity::from_key_pairs(key_pairs).unwrap();
+        assert!(identity.algorithms().eq(loaded.algorithms()));
+        for algorithm in identity.algorithms() {
+            assert_eq!(loaded.public_key(algorithm), identity.public_key(algorithm));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn issued_certificates_and_revocations_verify_against_the_ca() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let (ca_key, ca_public) = (path(&dir, "ca.key"), path(&dir, "ca.pub"));
+        let (identity_path, cert, list) = (
+            dir.join("identity.json"),
+            path(&dir, "cert.json"),
+            path(&dir, "revoked.json"),
+        );
+        let identity = SigningIdentity::load_or_generate(&identity_path, &[], None).unwrap();
+
+        certs(&args(&[
+            "init-ca",
+            "--key",
+            &ca_key,
+            "--public",
+            &ca_public,
+            "--algorithm",
+            "ML-DSA-65",
+        ]))
+        .unwrap();
+        certs(&args(&[
+            "issue",
+            "--ca",
+            &ca_key,
+            "--identity",
+            identity_path.to_str().unwrap(),
+            "--hostname",
+            "vpn.example.com",
+            "--serial",
+            "7",
+            "--out",
+            &cert,
+        ]))
+        .unwrap();
+        let ca = CaPublicKey::load(Path::new(&ca_public)).unwrap();
+        let certificate = ServerCertificate::load(Path::new(&cert)).unwrap();
+        assert_eq!(certificate.serial, 7);
+        assert!(certificate.matches(&identity));
+        certificate
+            .verify(&ca, "vpn.example.com", unix_time())
+            .unwrap();
+        assert!(certificate
+            .verify(&ca, "other.example.com", unix_time())
+            .is_err());
+
+        let revoked = identity.public_key(SignatureAlgorithm::MlDsa65).unwrap();
+        for _ in 0..2 {
+            certs(&args(&[
+                "revoke",
+                "--ca",
+                &ca_key,
+                "--list",
+                &list,
+                "--public-key",
+                &hex::encode(revoked),
+            ]))
+            .unwrap();
+        }
+        let revocations = RevocationList::load(Path::new(&list), &ca).unwrap();
+        assert_eq!(revocations.number, 2);
+        assert_eq!(revocations.revoked.len(), 1);
+        assert!(revocations.is_revoked(revoked));
+        certs(&args(&["revocations", &list, "--ca", &ca_public])).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
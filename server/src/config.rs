@@ -0,0 +1,232 @@
+use crate::crypto::{AlgorithmSuite, PresharedKey, SignatureAlgorithm};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the config file; `config.toml` otherwise.
+pub const CONFIG_PATH_ENV: &str = "VPN_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Server settings read from a TOML file. Every field has a default, so a
+/// missing file or a partial one is fine.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Where the long-term signing identity lives. Generated on first run.
+    pub identity_key_path: PathBuf,
+    /// PKCS#8 key files (PEM or DER) to use as the identity instead of
+    /// `identity_key_path`.
+    pub identity_key_files: Vec<PathBuf>,
+    /// The identity to rotate to. While set, the server publishes its
+    /// fingerprints and signs handshakes with it as well as the current
+    /// one. Generated on first use like `identity_key_path`.
+    pub next_identity_key_path: Option<PathBuf>,
+    /// Key files for the next identity, as `identity_key_files` is for the
+    /// current one.
+    pub next_identity_key_files: Vec<PathBuf>,
+    /// How often to check the identity and certificate files for changes,
+    /// in seconds.
+    pub identity_reload_secs: u64,
+    /// CA-issued certificate for the identity, sent in every server hello.
+    pub certificate_path: Option<PathBuf>,
+    /// `authorized_keys` file, or a directory of them, listing the client
+    /// signing keys allowed to connect.
+    pub authorized_keys_path: PathBuf,
+    /// How often to check `authorized_keys_path` for changes, in seconds.
+    pub authorized_keys_reload_secs: u64,
+    /// CA-signed list of revoked client and server keys.
+    pub revocation_list_path: Option<PathBuf>,
+    /// Public key of the CA that signs `revocation_list_path`.
+    pub revocation_ca_path: Option<PathBuf>,
+    /// How often to check `revocation_list_path` for changes, in seconds.
+    pub revocation_reload_secs: u64,
+    /// Append-only Merkle tree log of every enrolled, revoked and server
+    /// key, served under `/transparency`.
+    pub transparency_log_path: Option<PathBuf>,
+    /// How long a resumption ticket stays redeemable, in seconds.
+    pub ticket_lifetime_secs: u64,
+    /// Deployment-wide PSK (64 hex digits) mixed into every handshake.
+    pub psk: Option<PresharedKey>,
+    /// Per-user PSKs keyed by `authorized_keys` username. These take
+    /// precedence over `psk`.
+    pub user_psks: HashMap<String, PresharedKey>,
+    /// Suites offered to clients, most preferred first.
+    pub suites: Vec<AlgorithmSuite>,
+    /// Lowest NIST security category a handshake may negotiate.
+    pub min_security_level: u8,
+    /// Per-user minimums keyed by `authorized_keys` username. These can
+    /// only raise the listener's minimum, never lower it.
+    pub user_min_security_levels: HashMap<String, u8>,
+    /// Reject clients that do not add an Ed25519 signature alongside the
+    /// post-quantum one.
+    pub require_dual_signatures: bool,
+    /// Addresses to accept connections on, each with its own suite policy.
+    pub listeners: Vec<ListenerConfig>,
+}
+
+/// One listening socket. Unset fields fall back to the top-level settings.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerConfig {
+    pub bind: SocketAddr,
+    /// Replaces the top-level `suites` on this listener.
+    #[serde(default)]
+    pub suites: Option<Vec<AlgorithmSuite>>,
+    /// Raises the top-level `min_security_level` on this listener.
+    #[serde(default)]
+    pub min_security_level: Option<u8>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            identity_key_path: PathBuf::from("keys/server_identity.json"),
+            identity_key_files: Vec::new(),
+            next_identity_key_path: None,
+            next_identity_key_files: Vec::new(),
+            identity_reload_secs: 10,
+            certificate_path: None,
+            authorized_keys_path: PathBuf::from("authorized_keys"),
+            authorized_keys_reload_secs: 10,
+            revocation_list_path: None,
+            revocation_ca_path: None,
+            revocation_reload_secs: 10,
+            transparency_log_path: None,
+            ticket_lifetime_secs: 12 * 60 * 60,
+            psk: None,
+            user_psks: HashMap::new(),
+            suites: AlgorithmSuite::DEFAULT_OFFER.to_vec(),
+            min_security_level: 1,
+            user_min_security_levels: HashMap::new(),
+            require_dual_signatures: false,
+            listeners: vec![ListenerConfig {
+                bind: SocketAddr::from(([0, 0, 0, 0], 8000)),
+                suites: None,
+                min_security_level: None,
+            }],
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the file named by `VPN_CONFIG`, falling back to defaults when
+    /// it does not exist.
+    pub fn from_env() -> io::Result<Self> {
+        let path = std::env::var_os(CONFIG_PATH_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+        Self::load(&path)
+    }
+
+    /// The PSK `username`'s handshakes must be bound to, if any.
+    pub fn psk_for(&self, username: &str) -> Option<&PresharedKey> {
+        self.user_psks.get(username).or(self.psk.as_ref())
+    }
+
+    /// The listener bound to `addr`.
+    pub fn listener(&self, addr: SocketAddr) -> Option<&ListenerConfig> {
+        self.listeners.iter().find(|listener| listener.bind == addr)
+    }
+
+    /// The minimum security level for handshakes on `listener`, raised
+    /// further by `username`'s own minimum once the client is known.
+    pub fn min_security_level(&self, listener: &ListenerConfig, username: Option<&str>) -> u8 {
+        let user_level = username
+            .and_then(|username| self.user_min_security_levels.get(username))
+            .copied()
+            .unwrap_or(0);
+        self.min_security_level
+            .max(listener.min_security_level.unwrap_or(0))
+            .max(user_level)
+    }
+
+    /// Suites offered on `listener`, leaving out any below its minimum.
+    pub fn suites(&self, listener: &ListenerConfig) -> Vec<AlgorithmSuite> {
+        let min_level = self.min_security_level(listener, None);
+        listener
+            .suites
+            .as_ref()
+            .unwrap_or(&self.suites)
+            .iter()
+            .copied()
+            .filter(|suite| suite.security_level() >= min_level)
+            .collect()
+    }
+
+    /// Every signature algorithm some listener offers, so the server
+    /// identity can hold a key for each.
+    pub fn signature_algorithms(&self) -> Vec<SignatureAlgorithm> {
+        let mut algorithms: Vec<_> = self
+            .listeners
+            .iter()
+            .flat_map(|listener| self.suites(listener))
+            .map(|suite| suite.signature)
+            .collect();
+        algorithms.sort();
+        algorithms.dedup();
+        algorithms
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                toml::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listener_and_user_minimums_only_raise_the_floor() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+            suites = [
+                { kem = "Kyber1024", signature = "Dilithium5" },
+                { kem = "ML-KEM-768", signature = "ML-DSA-65" },
+                { kem = "Kyber512", signature = "Dilithium2" },
+            ]
+            min_security_level = 1
+
+            [user_min_security_levels]
+            auditor = 5
+
+            [[listeners]]
+            bind = "0.0.0.0:8000"
+
+            [[listeners]]
+            bind = "0.0.0.0:8443"
+            min_security_level = 3
+            "#,
+        )
+        .unwrap();
+
+        let iot = config.listener("0.0.0.0:8000".parse().unwrap()).unwrap();
+        let compliance = config.listener("0.0.0.0:8443".parse().unwrap()).unwrap();
+        assert!(config.listener("0.0.0.0:9000".parse().unwrap()).is_none());
+
+        assert_eq!(config.suites(iot).len(), 3);
+        assert_eq!(
+            config.suites(compliance),
+            vec![AlgorithmSuite::LEVEL5, AlgorithmSuite::FIPS]
+        );
+        assert_eq!(config.min_security_level(iot, Some("alice")), 1);
+        assert_eq!(config.min_security_level(compliance, Some("alice")), 3);
+        assert_eq!(config.min_security_level(iot, Some("auditor")), 5);
+        assert_eq!(
+            config.signature_algorithms(),
+            vec![
+                SignatureAlgorithm::Dilithium2,
+                SignatureAlgorithm::Dilithium5,
+                SignatureAlgorithm::MlDsa65
+            ]
+        );
+    }
+}
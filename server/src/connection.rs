@@ -0,0 +1,284 @@
+use serde::Serialize;
+use std::fmt;
+use thiserror::Error;
+
+/// Where a WebSocket connection is in its lifecycle.
+///
+/// ```text
+/// AwaitingHandshake --handshake/resume--> AwaitingAuth --frame, auth--> Established
+///                                                                  |    ^
+///                                                  handshake/resume|    |done or failed
+///                                                                  v    |
+///                                                                 Rekeying
+/// any state --close--> Closing
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Only a handshake or resumption may start the connection.
+    AwaitingHandshake,
+    /// Keys are agreed; the client must prove it holds the traffic key
+    /// with an encrypted frame, then authenticate, before any traffic.
+    AwaitingAuth,
+    /// Tunnel, proxy and stats traffic flows.
+    Established,
+    /// A fresh handshake is replacing the established session's keys.
+    Rekeying,
+    /// The connection is shutting down; nothing more is processed.
+    Closing,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::AwaitingHandshake => "awaiting_handshake",
+            ConnectionState::AwaitingAuth => "awaiting_auth",
+            ConnectionState::Established => "established",
+            ConnectionState::Rekeying => "rekeying",
+            ConnectionState::Closing => "closing",
+        }
+    }
+
+    /// Whether a message of `kind` may be processed in this state.
+    pub fn permits(&self, kind: MessageKind) -> bool {
+        use MessageKind::*;
+        match self {
+            ConnectionState::AwaitingHandshake => matches!(kind, Handshake | Resume),
+            ConnectionState::AwaitingAuth => matches!(kind, Auth | EncryptedFrame),
+            ConnectionState::Established => matches!(
+                kind,
+                Handshake | Resume | Stats | HttpProxy | TunnelData | EncryptedFrame
+            ),
+            ConnectionState::Rekeying | ConnectionState::Closing => false,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The kinds of message a client can send, as far as the state machine is
+/// concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Handshake,
+    Resume,
+    Auth,
+    Stats,
+    HttpProxy,
+    TunnelData,
+    EncryptedFrame,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::Handshake => "handshake",
+            MessageKind::Resume => "resume",
+            MessageKind::Auth => "auth",
+            MessageKind::Stats => "stats",
+            MessageKind::HttpProxy => "http_proxy",
+            MessageKind::TunnelData => "tunnel_data",
+            MessageKind::EncryptedFrame => "encrypted_frame",
+        }
+    }
+}
+
+impl fmt::Display for MessageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why a client message was refused without being processed.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolViolation {
+    #[error("{kind} is not allowed while {state}")]
+    UnexpectedMessage {
+        state: ConnectionState,
+        kind: MessageKind,
+    },
+    #[error("message could not be parsed")]
+    MalformedMessage,
+}
+
+impl ProtocolViolation {
+    /// Stable identifier for the `code` field of error frames.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProtocolViolation::UnexpectedMessage { .. } => "unexpected_message",
+            ProtocolViolation::MalformedMessage => "malformed_message",
+        }
+    }
+}
+
+/// Per-connection state machine. Every refused message is counted.
+#[derive(Debug)]
+pub struct Connection {
+    state: ConnectionState,
+    rejected_messages: u64,
+    key_confirmed: bool,
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Connection {
+    pub fn new() -> Self {
+        Self {
+            state: ConnectionState::AwaitingHandshake,
+            rejected_messages: 0,
+            key_confirmed: false,
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn rejected_messages(&self) -> u64 {
+        self.rejected_messages
+    }
+
+    /// Admits a message of `kind`, or counts and returns the violation.
+    pub fn check(&mut self, kind: MessageKind) -> Result<(), ProtocolViolation> {
+        if self.state.permits(kind) {
+            return Ok(());
+        }
+        self.rejected_messages += 1;
+        Err(ProtocolViolation::UnexpectedMessage {
+            state: self.state,
+            kind,
+        })
+    }
+
+    /// Counts a message that could not be parsed at all.
+    pub fn malformed(&mut self) -> ProtocolViolation {
+        self.rejected_messages += 1;
+        ProtocolViolation::MalformedMessage
+    }
+
+    /// A handshake or resumption was admitted. On an established
+    /// connection this starts a rekey.
+    pub fn handshake_started(&mut self) {
+        if self.state == ConnectionState::Established {
+            self.state = ConnectionState::Rekeying;
+        }
+    }
+
+    /// The handshake produced a session: a first handshake moves on to
+    /// authentication, a rekey goes straight back to established.
+    pub fn handshake_completed(&mut self) {
+        self.state = match self.state {
+            ConnectionState::AwaitingHandshake => ConnectionState::AwaitingAuth,
+            ConnectionState::Rekeying => ConnectionState::Established,
+            state => state,
+        };
+    }
+
+    /// The handshake was refused. A failed rekey keeps the existing session.
+    pub fn handshake_failed(&mut self) {
+        if self.state == ConnectionState::Rekeying {
+            self.state = ConnectionState::Established;
+        }
+    }
+
+    /// A frame from the client decrypted under the session's traffic key,
+    /// so whoever sent the handshake also holds its keys.
+    pub fn key_confirmed(&mut self) {
+        self.key_confirmed = true;
+    }
+
+    /// Admits the client's `auth`. Returns false, leaving the state alone,
+    /// until the traffic key has been confirmed.
+    pub fn authenticated(&mut self) -> bool {
+        if self.state != ConnectionState::AwaitingAuth || !self.key_confirmed {
+            return false;
+        }
+        self.state = ConnectionState::Established;
+        true
+    }
+
+    pub fn close(&mut self) {
+        self.state = ConnectionState::Closing;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traffic_requires_handshake_and_auth() {
+        let mut connection = Connection::new();
+        for kind in [
+            MessageKind::Auth,
+            MessageKind::Stats,
+            MessageKind::HttpProxy,
+            MessageKind::TunnelData,
+            MessageKind::EncryptedFrame,
+        ] {
+            assert_eq!(
+                connection.check(kind),
+                Err(ProtocolViolation::UnexpectedMessage {
+                    state: ConnectionState::AwaitingHandshake,
+                    kind
+                })
+            );
+        }
+
+        connection.check(MessageKind::Handshake).unwrap();
+        connection.handshake_started();
+        connection.handshake_completed();
+        assert_eq!(connection.state(), ConnectionState::AwaitingAuth);
+        assert!(connection.check(MessageKind::HttpProxy).is_err());
+        assert!(connection.check(MessageKind::Handshake).is_err());
+
+        // Auth alone does not establish the connection: the client must
+        // first show it holds the traffic key.
+        connection.check(MessageKind::Auth).unwrap();
+        assert!(!connection.authenticated());
+        assert_eq!(connection.state(), ConnectionState::AwaitingAuth);
+        connection.check(MessageKind::EncryptedFrame).unwrap();
+        connection.key_confirmed();
+        assert!(connection.authenticated());
+        assert_eq!(connection.state(), ConnectionState::Established);
+        connection.check(MessageKind::HttpProxy).unwrap();
+        connection.check(MessageKind::EncryptedFrame).unwrap();
+        assert!(connection.check(MessageKind::Auth).is_err());
+
+        assert_eq!(connection.rejected_messages(), 8);
+    }
+
+    #[test]
+    fn rekey_returns_to_established_and_close_refuses_everything() {
+        let mut connection = Connection::new();
+        connection.handshake_started();
+        connection.handshake_completed();
+        connection.key_confirmed();
+        assert!(connection.authenticated());
+
+        connection.check(MessageKind::Resume).unwrap();
+        connection.handshake_started();
+        assert_eq!(connection.state(), ConnectionState::Rekeying);
+        assert!(connection.check(MessageKind::TunnelData).is_err());
+        connection.handshake_failed();
+        assert_eq!(connection.state(), ConnectionState::Established);
+
+        connection.handshake_started();
+        connection.handshake_completed();
+        assert_eq!(connection.state(), ConnectionState::Established);
+
+        connection.close();
+        assert!(connection.check(MessageKind::Stats).is_err());
+        assert_eq!(connection.malformed(), ProtocolViolation::MalformedMessage);
+        assert_eq!(connection.rejected_messages(), 3);
+    }
+}
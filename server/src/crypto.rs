@@ -1,20 +1,39 @@
-use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Key, Nonce,
+use crate::certs::{CaPublicKey, ServerCertificate};
+use crate::cipher::{AeadAlgorithm, SessionCipher};
+use crate::identity::{fingerprint, SigningIdentity};
+use crate::replay::{ReplayRejection, ReplayStats, ReplayWindow};
+use crate::revocation::Revocations;
+use crate::rotation::NextIdentity;
+use aes_gcm::aead::Payload;
+use ed25519_dalek::{
+    Signature as Ed25519Signature, Signer as _, SigningKey as Ed25519SigningKey,
+    VerifyingKey as Ed25519VerifyingKey,
 };
-use pqcrypto_dilithium::dilithium2::{
-    detached_sign, keypair as dilithium_keypair, verify_detached_signature,
+use hkdf::Hkdf;
+use mysten_mldsa_native_rs::{
+    Signature as MlDsaSignature, SigningKeySeed as MlDsaSeed, VerifyingKey as MlDsaVerifyingKey,
+    RND_LENGTH as ML_DSA_RND_LENGTH, SEED_LENGTH as ML_DSA_SEED_LENGTH,
 };
-use pqcrypto_kyber::kyber768::{decapsulate, encapsulate, keypair as kyber_keypair};
 use pqcrypto_traits::kem::{
     Ciphertext, PublicKey as KemPublicKey, SecretKey as KemSecretKey, SharedSecret,
 };
 use pqcrypto_traits::sign::{
     DetachedSignature, PublicKey as SignPublicKey, SecretKey as SignSecretKey,
 };
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
 use thiserror::Error;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519Secret};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum CryptoError {
     #[error("Key exchange failed")]
     KeyExchangeError,
@@ -24,109 +43,2859 @@ pub enum CryptoError {
     EncryptionError(String),
     #[error("Decryption failed: {0}")]
     DecryptionError(String),
+    #[error("Unsupported algorithm suite: {0}")]
+    UnsupportedAlgorithm(String),
+    #[error("Frame rejected by replay window: {0:?}")]
+    ReplayError(ReplayRejection),
+    #[error("Server key {0} does not match the pinned fingerprint")]
+    PinMismatchError(String),
+    #[error("Resumption ticket rejected: {0}")]
+    TicketError(&'static str),
+    #[error("Pre-shared key rejected: {0}")]
+    PskError(&'static str),
+    #[error("Malformed {0} key")]
+    KeyError(&'static str),
+    #[error("{0} is below the required security level {1}")]
+    SecurityLevelError(String, u8),
+    #[error("Server certificate rejected: {0}")]
+    CertificateError(&'static str),
+    #[error("Key {0} has been revoked")]
+    RevokedKeyError(String),
+}
+
+/// KEM identifiers as they appear on the wire.
+///
+/// `MlKem768` is served by the same PQClean code as `Kyber768`: the
+/// `pqcrypto-kyber` 0.8 build already implements FIPS 203 encapsulation and
+/// decapsulation, so the standardized path only adds the FIPS 203 input
+/// checks on the peer's encapsulation key. `X25519` is the classical half of
+/// hybrid mode and is never negotiated as a suite's KEM on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KemAlgorithm {
+    #[serde(rename = "Kyber512")]
+    Kyber512,
+    #[serde(rename = "Kyber768")]
+    Kyber768,
+    #[serde(rename = "Kyber1024")]
+    Kyber1024,
+    #[serde(rename = "ML-KEM-768")]
+    MlKem768,
+    #[serde(rename = "X25519")]
+    X25519,
+}
+
+impl KemAlgorithm {
+    pub const ALL: [KemAlgorithm; 5] = [
+        KemAlgorithm::Kyber512,
+        KemAlgorithm::Kyber768,
+        KemAlgorithm::Kyber1024,
+        KemAlgorithm::MlKem768,
+        KemAlgorithm::X25519,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KemAlgorithm::Kyber512 => "Kyber512",
+            KemAlgorithm::Kyber768 => "Kyber768",
+            KemAlgorithm::Kyber1024 => "Kyber1024",
+            KemAlgorithm::MlKem768 => "ML-KEM-768",
+            KemAlgorithm::X25519 => "X25519",
+        }
+    }
+
+    /// NIST security category, or 0 for classical algorithms.
+    pub fn security_level(&self) -> u8 {
+        match self {
+            KemAlgorithm::Kyber512 => 1,
+            KemAlgorithm::Kyber768 | KemAlgorithm::MlKem768 => 3,
+            KemAlgorithm::Kyber1024 => 5,
+            KemAlgorithm::X25519 => 0,
+        }
+    }
+}
+
+/// Signature identifiers as they appear on the wire. `Dilithium2`, `3` and
+/// `5` are the round-3 schemes old clients use; `MlDsa65` is FIPS 204
+/// ML-DSA-65; `Ed25519` is the classical scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum SignatureAlgorithm {
+    #[serde(rename = "Dilithium2")]
+    Dilithium2,
+    #[serde(rename = "Dilithium3")]
+    Dilithium3,
+    #[serde(rename = "Dilithium5")]
+    Dilithium5,
+    #[serde(rename = "ML-DSA-65")]
+    MlDsa65,
+    #[serde(rename = "Falcon-512")]
+    Falcon512,
+    #[serde(rename = "Falcon-1024")]
+    Falcon1024,
+    #[serde(rename = "SPHINCS+-SHA2-128s")]
+    SphincsSha2128s,
+    #[serde(rename = "Ed25519")]
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Dilithium2 => "Dilithium2",
+            SignatureAlgorithm::Dilithium3 => "Dilithium3",
+            SignatureAlgorithm::Dilithium5 => "Dilithium5",
+            SignatureAlgorithm::MlDsa65 => "ML-DSA-65",
+            SignatureAlgorithm::Falcon512 => "Falcon-512",
+            SignatureAlgorithm::Falcon1024 => "Falcon-1024",
+            SignatureAlgorithm::SphincsSha2128s => "SPHINCS+-SHA2-128s",
+            SignatureAlgorithm::Ed25519 => "Ed25519",
+        }
+    }
+
+    /// NIST security category, or 0 for classical algorithms.
+    pub fn security_level(&self) -> u8 {
+        match self {
+            SignatureAlgorithm::Falcon512 | SignatureAlgorithm::SphincsSha2128s => 1,
+            SignatureAlgorithm::Dilithium2 => 2,
+            SignatureAlgorithm::Dilithium3 | SignatureAlgorithm::MlDsa65 => 3,
+            SignatureAlgorithm::Dilithium5 | SignatureAlgorithm::Falcon1024 => 5,
+            SignatureAlgorithm::Ed25519 => 0,
+        }
+    }
+
+    pub const ALL: [SignatureAlgorithm; 8] = [
+        SignatureAlgorithm::Dilithium2,
+        SignatureAlgorithm::Dilithium3,
+        SignatureAlgorithm::Dilithium5,
+        SignatureAlgorithm::MlDsa65,
+        SignatureAlgorithm::Falcon512,
+        SignatureAlgorithm::Falcon1024,
+        SignatureAlgorithm::SphincsSha2128s,
+        SignatureAlgorithm::Ed25519,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.as_str() == name)
+    }
+}
+
+/// The KEM and signature pair a handshake runs with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AlgorithmSuite {
+    pub kem: KemAlgorithm,
+    pub signature: SignatureAlgorithm,
+}
+
+impl AlgorithmSuite {
+    /// Pre-standard suite spoken by clients that do not send a `suite`.
+    pub const LEGACY: Self = Self {
+        kem: KemAlgorithm::Kyber768,
+        signature: SignatureAlgorithm::Dilithium2,
+    };
+    pub const FIPS: Self = Self {
+        kem: KemAlgorithm::MlKem768,
+        signature: SignatureAlgorithm::MlDsa65,
+    };
+    /// NIST level 1, for links where handshake size matters most.
+    pub const LEVEL1: Self = Self {
+        kem: KemAlgorithm::Kyber512,
+        signature: SignatureAlgorithm::Dilithium2,
+    };
+    /// NIST level 5, for deployments that require it.
+    pub const LEVEL5: Self = Self {
+        kem: KemAlgorithm::Kyber1024,
+        signature: SignatureAlgorithm::Dilithium5,
+    };
+    /// What a server offers unless configured otherwise, most preferred
+    /// first.
+    pub const DEFAULT_OFFER: [Self; 2] = [Self::FIPS, Self::LEGACY];
+
+    pub fn name(&self) -> String {
+        format!("{} + {}", self.kem.as_str(), self.signature.as_str())
+    }
+
+    /// The weaker of the two halves' NIST security categories.
+    pub fn security_level(&self) -> u8 {
+        self.kem
+            .security_level()
+            .min(self.signature.security_level())
+    }
+}
+
+impl Default for AlgorithmSuite {
+    fn default() -> Self {
+        Self::LEGACY
+    }
+}
+
+/// How the session secret is agreed. `Hybrid` feeds both the Kyber768 and
+/// an X25519 shared secret into the key schedule, so the session stays
+/// secure as long as either primitive holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyExchangeMode {
+    #[default]
+    PqOnly,
+    Hybrid,
+}
+
+impl KeyExchangeMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyExchangeMode::PqOnly => "pq_only",
+            KeyExchangeMode::Hybrid => "hybrid",
+        }
+    }
+}
+
+/// How each side authenticates the handshake. `Dual` adds an Ed25519
+/// signature next to the suite's post-quantum one, and both must verify, so
+/// authentication holds as long as either scheme does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    #[default]
+    PqOnly,
+    Dual,
+}
+
+impl AuthMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthMode::PqOnly => "pq_only",
+            AuthMode::Dual => "dual",
+        }
+    }
+}
+
+/// First message on every connection: the server's ephemeral KEM and X25519
+/// keys alongside its long-term signing keys. `kyber_public_key` is a valid
+/// encapsulation key for both KEM identifiers. The ephemeral keys are
+/// authenticated by the server's signature over the handshake transcript.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerHello {
+    pub kyber_public_key: Vec<u8>,
+    pub dilithium_public_key: Vec<u8>,
+    pub ml_dsa_public_key: Vec<u8>,
+    pub x25519_public_key: Vec<u8>,
+    pub supported_modes: Vec<KeyExchangeMode>,
+    pub supported_suites: Vec<AlgorithmSuite>,
+    pub supported_aeads: Vec<AeadAlgorithm>,
+    /// Long-term keys for the other signature algorithms in
+    /// `supported_suites`, such as Dilithium5 for level 5 suites, plus the
+    /// Ed25519 key if the server supports dual signatures.
+    #[serde(default)]
+    pub signing_keys: BTreeMap<SignatureAlgorithm, Vec<u8>>,
+    /// CA-issued certificate for the keys above, if the server has one.
+    /// It is checked on its own rather than through the transcript.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<ServerCertificate>,
+    /// Keys the server is rotating to, present during the overlap window.
+    /// Kept out of the transcript like the certificate; each is instead
+    /// endorsed by the current key for its algorithm.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub next_signing_keys: BTreeMap<SignatureAlgorithm, NextSigningKey>,
+}
+
+/// A key from the server's next identity, with the current key's signature
+/// over it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NextSigningKey {
+    pub public_key: Vec<u8>,
+    pub endorsement: Vec<u8>,
+}
+
+/// What the current key signs to endorse `next_key` as its successor.
+pub(crate) fn endorsement_bytes(algorithm: SignatureAlgorithm, next_key: &[u8]) -> Vec<u8> {
+    let mut bytes = b"pqc-vpn next identity v1".to_vec();
+    bytes.extend_from_slice(algorithm.as_str().as_bytes());
+    bytes.push(0);
+    bytes.extend_from_slice(next_key);
+    bytes
+}
+
+impl ServerHello {
+    /// The server's long-term key for `algorithm`, if the hello carries one.
+    pub fn signing_key(&self, algorithm: SignatureAlgorithm) -> Option<&[u8]> {
+        match algorithm {
+            SignatureAlgorithm::Dilithium2 => Some(&self.dilithium_public_key),
+            SignatureAlgorithm::MlDsa65 => Some(&self.ml_dsa_public_key),
+            _ => self.signing_keys.get(&algorithm).map(Vec::as_slice),
+        }
+    }
+
+    /// Checks the server's long-term key for `algorithm` against a
+    /// fingerprint the client pinned out of band. The handshake signature
+    /// then ties that key to this connection's ephemeral keys. During a
+    /// rotation the pin may also name the next key, which signs the
+    /// handshake as well.
+    pub fn check_pin(
+        &self,
+        algorithm: SignatureAlgorithm,
+        pinned_fingerprint: &str,
+    ) -> Result<(), CryptoError> {
+        let key = self
+            .signing_key(algorithm)
+            .ok_or_else(|| CryptoError::UnsupportedAlgorithm(algorithm.as_str().to_string()))?;
+        let actual = fingerprint(key);
+        if actual != pinned_fingerprint
+            && self.next_fingerprint(algorithm).as_deref() != Some(pinned_fingerprint)
+        {
+            return Err(CryptoError::PinMismatchError(actual));
+        }
+        Ok(())
+    }
+
+    /// Fingerprint of the key the server will rotate to for `algorithm`.
+    /// Only trustworthy once `process_server_response` has accepted the
+    /// handshake, which checks the current key's endorsement of it; clients
+    /// then add it to their pins so they keep connecting after the switch.
+    pub fn next_fingerprint(&self, algorithm: SignatureAlgorithm) -> Option<String> {
+        self.next_signing_keys
+            .get(&algorithm)
+            .map(|next| fingerprint(&next.public_key))
+    }
+
+    /// Alternative to pinning: checks the hello's certificate against a
+    /// trusted CA and that it certifies the key for `algorithm` this hello
+    /// carries. `now` is in Unix seconds.
+    pub fn check_certificate(
+        &self,
+        ca: &CaPublicKey,
+        hostname: &str,
+        algorithm: SignatureAlgorithm,
+        now: u64,
+    ) -> Result<(), CryptoError> {
+        let certificate = self
+            .certificate
+            .as_ref()
+            .ok_or(CryptoError::CertificateError("no certificate"))?;
+        certificate.verify(ca, hostname, now)?;
+        match (
+            certificate.public_key(algorithm),
+            self.signing_key(algorithm),
+        ) {
+            (Some(certified), Some(presented)) if certified == presented => Ok(()),
+            (None, _) => Err(CryptoError::CertificateError("algorithm not allowed")),
+            _ => Err(CryptoError::CertificateError("key not certified")),
+        }
+    }
+}
+
+/// Sent by the client after it has received the server's public keys.
+/// Clients that predate hybrid mode omit `mode` and get `PqOnly`; clients
+/// that predate suite negotiation omit `suite` and get `AlgorithmSuite::LEGACY`;
+/// clients that predate AEAD negotiation omit `aead` and get AES-256-GCM;
+/// clients that predate dual signatures omit `auth` and get `PqOnly`.
+///
+/// `signature` covers the client-auth transcript, which binds the server
+/// hello and every other request field. With `Dual`,
+/// `client_ed25519_signature` covers the same transcript.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeRequest {
+    pub client_public_key: Vec<u8>,
+    pub client_signing_key: Vec<u8>,
+    pub signature: Vec<u8>,
+    #[serde(default)]
+    pub suite: AlgorithmSuite,
+    #[serde(default)]
+    pub mode: KeyExchangeMode,
+    #[serde(default)]
+    pub aead: AeadAlgorithm,
+    #[serde(default)]
+    pub client_x25519_public_key: Option<Vec<u8>>,
+    #[serde(default)]
+    pub auth: AuthMode,
+    #[serde(default)]
+    pub client_ed25519_key: Option<Vec<u8>>,
+    #[serde(default)]
+    pub client_ed25519_signature: Option<Vec<u8>>,
+    /// Present when the client mixes a pre-shared key into the handshake.
+    /// Proves it holds the PSK the server has configured for it, so a
+    /// mismatch is refused up front instead of surfacing as garbled traffic.
+    #[serde(default)]
+    pub psk_binder: Option<Vec<u8>>,
+}
+
+/// Sent by the server once it has encapsulated against the client's KEM key.
+/// `signature` is the server's signature, under the negotiated suite, over
+/// the transcript hash up to and including the ciphertext. In `Dual` auth
+/// mode `ed25519_signature` covers the same hash, and so does
+/// `next_signature`, by the next key, while the hello carries one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandshakeResponse {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub suite: AlgorithmSuite,
+    pub mode: KeyExchangeMode,
+    pub aead: AeadAlgorithm,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+    #[serde(default)]
+    pub auth: AuthMode,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ed25519_signature: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_signature: Option<Vec<u8>>,
+}
+
+/// Running SHA-256 over every handshake field. Each field is length-prefixed
+/// so that no two different transcripts can hash the same.
+#[derive(Clone)]
+pub struct Transcript {
+    hasher: Sha256,
+}
+
+impl Transcript {
+    const LABEL: &'static [u8] = b"pqc-vpn handshake v1";
+
+    pub fn new(hello: &ServerHello, request: &HandshakeRequest) -> Self {
+        let mut transcript = Self::before_binder(hello, request);
+        if let Some(psk_binder) = &request.psk_binder {
+            transcript.append(psk_binder);
+        }
+        transcript
+    }
+
+    /// Everything up to the PSK binder, which is computed over this.
+    fn before_binder(hello: &ServerHello, request: &HandshakeRequest) -> Self {
+        let mut transcript = Self::with_label(Self::LABEL);
+        transcript.append_hello(hello);
+        transcript.append(&request.client_public_key);
+        transcript.append(&request.client_signing_key);
+        transcript.append(&request.signature);
+        transcript.append_request_parameters(hello, request);
+        if request.auth == AuthMode::Dual {
+            transcript.append(
+                request
+                    .client_ed25519_signature
+                    .as_deref()
+                    .unwrap_or_default(),
+            );
+        }
+        transcript
+    }
+
+    /// What the client signs in either auth mode: the hello and every
+    /// request field except the signatures and the PSK binder.
+    fn client_auth(hello: &ServerHello, request: &HandshakeRequest) -> Self {
+        let mut transcript = Self::with_label(b"pqc-vpn client auth v1");
+        transcript.append_hello(hello);
+        transcript.append(&request.client_public_key);
+        transcript.append(&request.client_signing_key);
+        transcript.append_request_parameters(hello, request);
+        transcript
+    }
+
+    fn append_hello(&mut self, hello: &ServerHello) {
+        self.append(&hello.kyber_public_key);
+        self.append(&hello.dilithium_public_key);
+        self.append(&hello.ml_dsa_public_key);
+        self.append(&hello.x25519_public_key);
+        for mode in &hello.supported_modes {
+            self.append(mode.as_str().as_bytes());
+        }
+        for suite in &hello.supported_suites {
+            self.append(suite.name().as_bytes());
+        }
+        for aead in &hello.supported_aeads {
+            self.append(aead.as_str().as_bytes());
+        }
+    }
+
+    /// The negotiated parameters and, in `Dual` auth mode, both sides'
+    /// Ed25519 keys.
+    fn append_request_parameters(&mut self, hello: &ServerHello, request: &HandshakeRequest) {
+        self.append(request.suite.name().as_bytes());
+        self.append(request.mode.as_str().as_bytes());
+        self.append(request.aead.as_str().as_bytes());
+        if let Some(client_x25519_public_key) = &request.client_x25519_public_key {
+            self.append(client_x25519_public_key);
+        }
+        // Keys from `signing_keys` are bound only once negotiated, so the
+        // transcript of clients that predate the field is unchanged.
+        if let Some(server_signing_key) = hello.signing_keys.get(&request.suite.signature) {
+            self.append(server_signing_key);
+        }
+        // Only dual-signature handshakes add fields, so the transcript of
+        // clients that predate them is unchanged.
+        if request.auth == AuthMode::Dual {
+            self.append(request.auth.as_str().as_bytes());
+            self.append(request.client_ed25519_key.as_deref().unwrap_or_default());
+            self.append(
+                hello
+                    .signing_key(SignatureAlgorithm::Ed25519)
+                    .unwrap_or_default(),
+            );
+        }
+    }
+
+    /// An empty transcript for some other exchange, domain-separated by
+    /// `label`.
+    pub fn with_label(label: &[u8]) -> Self {
+        let mut transcript = Self {
+            hasher: Sha256::new(),
+        };
+        transcript.append(label);
+        transcript
+    }
+
+    pub fn append(&mut self, data: &[u8]) {
+        self.hasher.update((data.len() as u32).to_be_bytes());
+        self.hasher.update(data);
+    }
+
+    pub fn hash(&self) -> [u8; 32] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+/// Which end of the tunnel a `CryptoSession` belongs to. Decides which of
+/// the two traffic secrets is used for sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Output of the HKDF key schedule: one traffic secret per direction plus an
+/// exporter secret for deriving further keying material. Wiped on drop and
+/// deliberately not `Clone`.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct SessionKeys {
+    pub client_to_server: [u8; 32],
+    pub server_to_client: [u8; 32],
+    pub exporter_secret: [u8; 32],
+}
+
+impl SessionKeys {
+    /// HKDF-SHA256 with the transcript hash as salt and the KEM shared
+    /// secret (concatenated with the X25519 secret in hybrid mode) as input
+    /// keying material.
+    pub fn derive(shared_secret: &[u8], transcript_hash: &[u8; 32]) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(transcript_hash), shared_secret);
+        let mut keys = Self {
+            client_to_server: [0; 32],
+            server_to_client: [0; 32],
+            exporter_secret: [0; 32],
+        };
+        expand(&hkdf, b"pqc-vpn c2s traffic", &mut keys.client_to_server);
+        expand(&hkdf, b"pqc-vpn s2c traffic", &mut keys.server_to_client);
+        expand(&hkdf, b"pqc-vpn exporter", &mut keys.exporter_secret);
+        keys
+    }
+}
+
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SessionKeys { .. }")
+    }
+}
+
+/// Expands straight into `okm` so the key never passes through a temporary.
+fn expand(hkdf: &Hkdf<Sha256>, info: &[u8], okm: &mut [u8; 32]) {
+    // 32 bytes is always within HKDF-SHA256's output limit.
+    hkdf.expand(info, okm).expect("valid HKDF output length");
+}
+
+/// Optional symmetric key mixed into the key schedule next to the KEM
+/// output, as in WireGuard. Sessions stay confidential against anyone
+/// without the PSK even if the KEM is broken.
+#[derive(Clone)]
+pub struct PresharedKey(Zeroizing<[u8; 32]>);
+
+impl PresharedKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(Zeroizing::new(key))
+    }
+
+    /// Parses 64 hex digits, as PSKs appear in configuration.
+    pub fn from_hex(hex_key: &str) -> Option<Self> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        hex::decode_to_slice(hex_key.trim(), key.as_mut()).ok()?;
+        Some(Self(key))
+    }
+
+    /// HKDF keyed by the PSK over the transcript up to the binder.
+    fn binder(&self, hello: &ServerHello, request: &HandshakeRequest) -> [u8; 32] {
+        let hkdf = Hkdf::<Sha256>::new(
+            Some(&Transcript::before_binder(hello, request).hash()),
+            self.0.as_ref(),
+        );
+        let mut binder = [0u8; 32];
+        expand(&hkdf, b"pqc-vpn psk binder", &mut binder);
+        binder
+    }
+}
+
+impl<'de> Deserialize<'de> for PresharedKey {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex_key = Zeroizing::new(String::deserialize(deserializer)?);
+        Self::from_hex(&hex_key)
+            .ok_or_else(|| serde::de::Error::custom("PSK must be 64 hex digits"))
+    }
+}
+
+impl fmt::Debug for PresharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PresharedKey(..)")
+    }
+}
+
+/// Secret values handed out by `pqcrypto`, which are plain `Copy` byte
+/// arrays with no `Zeroize` support of their own.
+pub(crate) trait PqcleanSecret: Copy {}
+
+/// Overwrites a `pqcrypto` secret with zeros in place.
+pub(crate) fn wipe_pqclean<T: PqcleanSecret>(secret: &mut T) {
+    // SAFETY: every `PqcleanSecret` is a newtype around a single `[u8; N]`,
+    // so the value is exactly `size_of::<T>()` initialized bytes and all
+    // zeros is a valid value.
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(secret as *mut T as *mut u8, std::mem::size_of::<T>())
+    };
+    bytes.zeroize();
+}
+
+/// A key encapsulation mechanism. Keys, ciphertexts and shared secrets are
+/// passed as bytes in the algorithm's wire encoding.
+pub trait Kem: Send + Sync {
+    fn algorithm(&self) -> KemAlgorithm;
+
+    /// Returns `(public key, secret key)`.
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>);
+
+    /// Returns `(shared secret, ciphertext)` for the holder of `public_key`.
+    fn encapsulate(&self, public_key: &[u8]) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), CryptoError>;
+
+    fn decapsulate(
+        &self,
+        secret_key: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, CryptoError>;
+}
+
+/// Checks detached signatures under one signature algorithm.
+pub trait Verifier: Send + Sync {
+    fn algorithm(&self) -> SignatureAlgorithm;
+
+    fn verify(&self, data: &[u8], signature: &[u8], public_key: &[u8]) -> Result<(), CryptoError>;
+}
+
+/// Creates keys and detached signatures under one signature algorithm.
+pub trait Signer: Verifier {
+    /// Returns `(public key, secret key)`.
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>);
+
+    fn sign(&self, secret_key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError>;
+}
+
+/// The implementation behind a negotiated KEM identifier.
+pub fn kem_for(algorithm: KemAlgorithm) -> &'static dyn Kem {
+    match algorithm {
+        KemAlgorithm::Kyber512 => &Kyber512Kem,
+        KemAlgorithm::Kyber768 => &Kyber768Kem,
+        KemAlgorithm::Kyber1024 => &Kyber1024Kem,
+        KemAlgorithm::MlKem768 => &MlKem768Kem,
+        KemAlgorithm::X25519 => &X25519Kem,
+    }
+}
+
+/// The implementation behind a negotiated signature identifier.
+pub fn signer_for(algorithm: SignatureAlgorithm) -> &'static dyn Signer {
+    match algorithm {
+        SignatureAlgorithm::Dilithium2 => &Dilithium2Signer,
+        SignatureAlgorithm::Dilithium3 => &Dilithium3Signer,
+        SignatureAlgorithm::Dilithium5 => &Dilithium5Signer,
+        SignatureAlgorithm::MlDsa65 => &MlDsa65Signer,
+        SignatureAlgorithm::Falcon512 => &Falcon512Signer,
+        SignatureAlgorithm::Falcon1024 => &Falcon1024Signer,
+        SignatureAlgorithm::SphincsSha2128s => &SphincsSha2128sSigner,
+        SignatureAlgorithm::Ed25519 => &Ed25519Signer,
+    }
+}
+
+/// Round-3 Kyber at one parameter set, from `pqcrypto-kyber`.
+macro_rules! kyber_kem {
+    ($name:ident, $module:ident, $algorithm:expr) => {
+        struct $name;
+
+        impl PqcleanSecret for pqcrypto_kyber::$module::SecretKey {}
+        impl PqcleanSecret for pqcrypto_kyber::$module::SharedSecret {}
+
+        impl Kem for $name {
+            fn algorithm(&self) -> KemAlgorithm {
+                $algorithm
+            }
+
+            fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+                let (public_key, mut secret_key) = pqcrypto_kyber::$module::keypair();
+                let secret = Zeroizing::new(secret_key.as_bytes().to_vec());
+                wipe_pqclean(&mut secret_key);
+                (public_key.as_bytes().to_vec(), secret)
+            }
+
+            fn encapsulate(
+                &self,
+                public_key: &[u8],
+            ) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), CryptoError> {
+                let pk = pqcrypto_kyber::$module::PublicKey::from_bytes(public_key)
+                    .map_err(|_| CryptoError::KeyExchangeError)?;
+                let (mut shared_secret, ciphertext) = pqcrypto_kyber::$module::encapsulate(&pk);
+                let secret = Zeroizing::new(shared_secret.as_bytes().to_vec());
+                wipe_pqclean(&mut shared_secret);
+                Ok((secret, ciphertext.as_bytes().to_vec()))
+            }
+
+            fn decapsulate(
+                &self,
+                secret_key: &[u8],
+                ciphertext: &[u8],
+            ) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+                let ct = pqcrypto_kyber::$module::Ciphertext::from_bytes(ciphertext)
+                    .map_err(|_| CryptoError::KeyExchangeError)?;
+                let mut sk = pqcrypto_kyber::$module::SecretKey::from_bytes(secret_key)
+                    .map_err(|_| CryptoError::KeyExchangeError)?;
+                let mut shared_secret = pqcrypto_kyber::$module::decapsulate(&ct, &sk);
+                wipe_pqclean(&mut sk);
+                let secret = Zeroizing::new(shared_secret.as_bytes().to_vec());
+                wipe_pqclean(&mut shared_secret);
+                Ok(secret)
+            }
+        }
+    };
+}
+
+kyber_kem!(Kyber512Kem, kyber512, KemAlgorithm::Kyber512);
+kyber_kem!(Kyber768Kem, kyber768, KemAlgorithm::Kyber768);
+kyber_kem!(Kyber1024Kem, kyber1024, KemAlgorithm::Kyber1024);
+
+/// Kyber768 with the FIPS 203 checks on the encapsulation key.
+struct MlKem768Kem;
+
+impl Kem for MlKem768Kem {
+    fn algorithm(&self) -> KemAlgorithm {
+        KemAlgorithm::MlKem768
+    }
+
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+        Kyber768Kem.keypair()
+    }
+
+    fn encapsulate(&self, public_key: &[u8]) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), CryptoError> {
+        if !ml_kem_encapsulation_key_is_valid(public_key) {
+            return Err(CryptoError::KeyExchangeError);
+        }
+        Kyber768Kem.encapsulate(public_key)
+    }
+
+    fn decapsulate(
+        &self,
+        secret_key: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+        Kyber768Kem.decapsulate(secret_key, ciphertext)
+    }
+}
+
+/// X25519 used as a KEM: the ciphertext is an ephemeral public key. Since
+/// Diffie-Hellman is symmetric, either side of a static exchange can also
+/// "decapsulate" the other's public key with its own secret.
+struct X25519Kem;
+
+impl Kem for X25519Kem {
+    fn algorithm(&self) -> KemAlgorithm {
+        KemAlgorithm::X25519
+    }
+
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+        let secret = X25519Secret::random_from_rng(rand::rngs::OsRng);
+        (
+            X25519PublicKey::from(&secret).as_bytes().to_vec(),
+            Zeroizing::new(secret.to_bytes().to_vec()),
+        )
+    }
+
+    fn encapsulate(&self, public_key: &[u8]) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), CryptoError> {
+        let (ciphertext, ephemeral_secret) = self.keypair();
+        Ok((self.decapsulate(&ephemeral_secret, public_key)?, ciphertext))
+    }
+
+    /// Rejects the all-zero output of low-order peer points.
+    fn decapsulate(
+        &self,
+        secret_key: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+        let secret: Zeroizing<[u8; 32]> = Zeroizing::new(
+            secret_key
+                .try_into()
+                .map_err(|_| CryptoError::KeyExchangeError)?,
+        );
+        let peer: [u8; 32] = ciphertext
+            .try_into()
+            .map_err(|_| CryptoError::KeyExchangeError)?;
+        let dh = X25519Secret::from(*secret).diffie_hellman(&X25519PublicKey::from(peer));
+        if !dh.was_contributory() {
+            return Err(CryptoError::KeyExchangeError);
+        }
+        Ok(Zeroizing::new(dh.as_bytes().to_vec()))
+    }
+}
+
+/// A PQClean round-3 signature scheme at one parameter set: Dilithium from
+/// `pqcrypto-dilithium`, Falcon from `pqcrypto-falcon` or SPHINCS+ from
+/// `pqcrypto-sphincsplus`. These predate FIPS 204/205 and their final
+/// encodings, so none of them interoperates with ML-DSA or SLH-DSA keys.
+macro_rules! pqclean_signer {
+    ($name:ident, $crate_name:ident::$module:ident, $algorithm:expr) => {
+        struct $name;
+
+        impl PqcleanSecret for $crate_name::$module::SecretKey {}
+
+        impl Verifier for $name {
+            fn algorithm(&self) -> SignatureAlgorithm {
+                $algorithm
+            }
+
+            fn verify(
+                &self,
+                data: &[u8],
+                signature: &[u8],
+                public_key: &[u8],
+            ) -> Result<(), CryptoError> {
+                let sig = $crate_name::$module::DetachedSignature::from_bytes(signature)
+                    .map_err(|_| CryptoError::SignatureError)?;
+                let pk = $crate_name::$module::PublicKey::from_bytes(public_key)
+                    .map_err(|_| CryptoError::SignatureError)?;
+                $crate_name::$module::verify_detached_signature(&sig, data, &pk)
+                    .map_err(|_| CryptoError::SignatureError)
+            }
+        }
+
+        impl Signer for $name {
+            fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+                let (public_key, mut secret_key) = $crate_name::$module::keypair();
+                let secret = Zeroizing::new(secret_key.as_bytes().to_vec());
+                wipe_pqclean(&mut secret_key);
+                (public_key.as_bytes().to_vec(), secret)
+            }
+
+            fn sign(&self, secret_key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+                let mut sk = $crate_name::$module::SecretKey::from_bytes(secret_key)
+                    .map_err(|_| CryptoError::KeyError($algorithm.as_str()))?;
+                let signature = $crate_name::$module::detached_sign(data, &sk)
+                    .as_bytes()
+                    .to_vec();
+                wipe_pqclean(&mut sk);
+                Ok(signature)
+            }
+        }
+    };
+}
+
+pqclean_signer!(
+    Dilithium2Signer,
+    pqcrypto_dilithium::dilithium2,
+    SignatureAlgorithm::Dilithium2
+);
+pqclean_signer!(
+    Dilithium3Signer,
+    pqcrypto_dilithium::dilithium3,
+    SignatureAlgorithm::Dilithium3
+);
+pqclean_signer!(
+    Dilithium5Signer,
+    pqcrypto_dilithium::dilithium5,
+    SignatureAlgorithm::Dilithium5
+);
+pqclean_signer!(
+    Falcon512Signer,
+    pqcrypto_falcon::falcon512,
+    SignatureAlgorithm::Falcon512
+);
+pqclean_signer!(
+    Falcon1024Signer,
+    pqcrypto_falcon::falcon1024,
+    SignatureAlgorithm::Falcon1024
+);
+pqclean_signer!(
+    SphincsSha2128sSigner,
+    pqcrypto_sphincsplus::sphincssha2128ssimple,
+    SignatureAlgorithm::SphincsSha2128s
+);
+
+/// FIPS 204 ML-DSA-65. The secret key is the 32-byte seed, expanded for
+/// each signature.
+struct MlDsa65Signer;
+
+impl Verifier for MlDsa65Signer {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::MlDsa65
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8], public_key: &[u8]) -> Result<(), CryptoError> {
+        let sig = MlDsaSignature::from_bytes(signature).map_err(|_| CryptoError::SignatureError)?;
+        let pk =
+            MlDsaVerifyingKey::from_bytes(public_key).map_err(|_| CryptoError::SignatureError)?;
+        pk.verify(data, b"", &sig)
+            .map_err(|_| CryptoError::SignatureError)
+    }
+}
+
+impl Signer for MlDsa65Signer {
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+        let mut seed = Zeroizing::new(vec![0u8; ML_DSA_SEED_LENGTH]);
+        rand::rngs::OsRng.fill_bytes(&mut seed);
+        let public_key = ml_dsa_public_key(&seed).expect("seed has the ML-DSA seed length");
+        (public_key, seed)
+    }
+
+    fn sign(&self, secret_key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let (signing_key, _) = MlDsaSeed::from_bytes(secret_key)
+            .map_err(|_| CryptoError::KeyError(SignatureAlgorithm::MlDsa65.as_str()))?
+            .expand();
+        // Hedged signing: fresh randomness per signature.
+        let mut rnd = [0u8; ML_DSA_RND_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut rnd);
+        Ok(signing_key
+            .sign(data, b"", &rnd)
+            .expect("empty context is within the ML-DSA limit")
+            .as_bytes()
+            .to_vec())
+    }
+}
+
+/// The ML-DSA-65 public key expanded from a 32-byte seed.
+pub(crate) fn ml_dsa_public_key(seed: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    let (_, public_key) = MlDsaSeed::from_bytes(seed)
+        .map_err(|_| CryptoError::KeyError(SignatureAlgorithm::MlDsa65.as_str()))?
+        .expand();
+    Ok(public_key.as_bytes().to_vec())
+}
+
+/// Classical Ed25519 with strict verification. The secret key is the
+/// 32-byte seed.
+struct Ed25519Signer;
+
+impl Verifier for Ed25519Signer {
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::Ed25519
+    }
+
+    fn verify(&self, data: &[u8], signature: &[u8], public_key: &[u8]) -> Result<(), CryptoError> {
+        let sig =
+            Ed25519Signature::from_slice(signature).map_err(|_| CryptoError::SignatureError)?;
+        let pk = public_key
+            .try_into()
+            .ok()
+            .and_then(|pk| Ed25519VerifyingKey::from_bytes(pk).ok())
+            .ok_or(CryptoError::SignatureError)?;
+        pk.verify_strict(data, &sig)
+            .map_err(|_| CryptoError::SignatureError)
+    }
+}
+
+impl Signer for Ed25519Signer {
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+        let signing_key = Ed25519SigningKey::generate(&mut rand::rngs::OsRng);
+        (
+            signing_key.verifying_key().as_bytes().to_vec(),
+            Zeroizing::new(signing_key.to_bytes().to_vec()),
+        )
+    }
+
+    fn sign(&self, secret_key: &[u8], data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let seed: Zeroizing<[u8; 32]> = Zeroizing::new(
+            secret_key
+                .try_into()
+                .map_err(|_| CryptoError::KeyError(SignatureAlgorithm::Ed25519.as_str()))?,
+        );
+        Ok(Ed25519SigningKey::from_bytes(&seed)
+            .sign(data)
+            .to_bytes()
+            .to_vec())
+    }
+}
+
+/// Every encrypted binary frame starts with this many bytes of header: the
+/// format version, the frame type, then the big-endian stream id, key epoch
+/// and sequence number. The sequence number is also the AEAD nonce, and the
+/// whole header is authenticated as associated data.
+pub const FRAME_HEADER_LEN: usize = 18;
+/// The frame format `encrypt_frame` writes and `decrypt_frame` accepts.
+pub const FRAME_VERSION: u8 = 1;
+const TAG_LEN: usize = 16;
+
+/// What an encrypted frame carries. Authenticated with the frame, so a
+/// payload cannot be replayed as a different kind of message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameType {
+    /// Tunnelled IP packets.
+    Data = 0,
+    /// Proxied HTTP requests and responses.
+    Proxy = 1,
+    /// Session control messages.
+    Control = 2,
+}
+
+impl FrameType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(FrameType::Data),
+            1 => Some(FrameType::Proxy),
+            2 => Some(FrameType::Control),
+            _ => None,
+        }
+    }
+}
+
+/// The cleartext header in front of every encrypted frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub stream: u32,
+    pub epoch: u32,
+    pub seq: u64,
+}
+
+impl FrameHeader {
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_LEN] {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header[0] = FRAME_VERSION;
+        header[1] = self.frame_type as u8;
+        header[2..6].copy_from_slice(&self.stream.to_be_bytes());
+        header[6..10].copy_from_slice(&self.epoch.to_be_bytes());
+        header[10..].copy_from_slice(&self.seq.to_be_bytes());
+        header
+    }
+
+    /// Reads the header at the start of `frame`.
+    pub fn parse(frame: &[u8]) -> Result<Self, CryptoError> {
+        let header = frame
+            .get(..FRAME_HEADER_LEN)
+            .ok_or_else(|| CryptoError::DecryptionError("frame too short".to_string()))?;
+        if header[0] != FRAME_VERSION {
+            return Err(CryptoError::DecryptionError(format!(
+                "unsupported frame version {}",
+                header[0]
+            )));
+        }
+        let frame_type = FrameType::from_byte(header[1]).ok_or_else(|| {
+            CryptoError::DecryptionError(format!("unknown frame type {}", header[1]))
+        })?;
+        Ok(Self {
+            frame_type,
+            stream: u32::from_be_bytes(header[2..6].try_into().unwrap()),
+            epoch: u32::from_be_bytes(header[6..10].try_into().unwrap()),
+            seq: u64::from_be_bytes(header[10..].try_into().unwrap()),
+        })
+    }
+}
+
+/// A decrypted frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub stream: u32,
+    pub payload: Vec<u8>,
+}
+
+/// When a sending direction ratchets to a fresh key. Whichever limit is hit
+/// first triggers the rekey.
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub max_bytes: u64,
+    pub max_messages: u64,
+    pub max_age: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            max_bytes: 1 << 30,
+            max_messages: 1 << 24,
+            max_age: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// A direction's traffic secret for one epoch, and the AEAD it keys. The
+/// AEAD key and epoch `n + 1`'s secret are separate HKDF-Expands of epoch
+/// `n`'s secret, so both sides can step forward without another round trip
+/// and no AEAD key is ever used as key material. The secret and the
+/// cipher's expanded key schedule are both wiped on drop.
+struct EpochKey {
+    epoch: u32,
+    aead: AeadAlgorithm,
+    secret: Zeroizing<[u8; 32]>,
+    cipher: SessionCipher,
+}
+
+impl EpochKey {
+    fn new(epoch: u32, aead: AeadAlgorithm, secret: Zeroizing<[u8; 32]>) -> Self {
+        let mut key = Zeroizing::new([0; 32]);
+        expand(&Self::hkdf(&secret), b"pqc-vpn traffic key", &mut key);
+        Self {
+            epoch,
+            aead,
+            cipher: SessionCipher::new(aead, &key),
+            secret,
+        }
+    }
+
+    fn next(&self) -> Result<Self, CryptoError> {
+        let epoch = self
+            .epoch
+            .checked_add(1)
+            .ok_or_else(|| CryptoError::EncryptionError("key epochs exhausted".to_string()))?;
+        let mut secret = Zeroizing::new([0; 32]);
+        expand(&Self::hkdf(&self.secret), b"pqc-vpn rekey", &mut secret);
+        Ok(Self::new(epoch, self.aead, secret))
+    }
+
+    fn hkdf(secret: &[u8; 32]) -> Hkdf<Sha256> {
+        Hkdf::<Sha256>::from_prk(secret).expect("traffic secret is a valid PRK")
+    }
+}
+
+/// Sending direction: its own key and sequence counter, so sending never
+/// consumes the receive side's nonces and the two directions never share a
+/// key/nonce pair. Sequence numbers keep counting across rekeys.
+struct SendState {
+    current: EpochKey,
+    next_seq: u64,
+    epoch_bytes: u64,
+    epoch_messages: u64,
+    epoch_started: Instant,
+}
+
+impl SendState {
+    fn needs_rekey(&self, policy: &RekeyPolicy, len: usize) -> bool {
+        self.epoch_bytes + len as u64 > policy.max_bytes
+            || self.epoch_messages >= policy.max_messages
+            || self.epoch_started.elapsed() >= policy.max_age
+    }
+
+    fn rekey(&mut self) -> Result<(), CryptoError> {
+        self.current = self.current.next()?;
+        self.epoch_bytes = 0;
+        self.epoch_messages = 0;
+        self.epoch_started = Instant::now();
+        Ok(())
+    }
+}
+
+/// Receiving direction: the current key, the previous one (kept so frames
+/// still in flight across a rekey decrypt), the next one once a frame has
+/// claimed it, and the replay window.
+struct ReceiveState {
+    current: EpochKey,
+    previous: Option<EpochKey>,
+    /// Derived once and kept, so forged frames claiming the next epoch
+    /// cost no more than a failed tag check each.
+    next: Option<EpochKey>,
+    window: ReplayWindow,
 }
 
 pub struct CryptoSession {
-    shared_key: Vec<u8>,
-    cipher: Aes256Gcm,
-    nonce_counter: u64,
+    role: Role,
+    send: SendState,
+    recv: ReceiveState,
+    rekey_policy: RekeyPolicy,
+    exporter_secret: Zeroizing<[u8; 32]>,
+    /// Exported from the key schedule and mixed into every frame's
+    /// associated data, so a frame only authenticates in the session that
+    /// sent it.
+    frame_binding: [u8; 32],
 }
 
-impl CryptoSession {
-    pub fn new(shared_key: Vec<u8>) -> Result<Self, CryptoError> {
-        let key = Key::<Aes256Gcm>::from_slice(&shared_key);
-        let cipher = Aes256Gcm::new(key);
+impl fmt::Debug for CryptoSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptoSession")
+            .field("aead", &self.aead())
+            .field("epochs", &self.epochs())
+            .field("next_seq", &self.send.next_seq)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CryptoSession {
+    pub fn new(keys: &SessionKeys, role: Role, aead: AeadAlgorithm) -> Self {
+        let (send_key, recv_key) = match role {
+            Role::Client => (&keys.client_to_server, &keys.server_to_client),
+            Role::Server => (&keys.server_to_client, &keys.client_to_server),
+        };
+
+        let mut frame_binding = [0u8; 32];
+        let exporter = Hkdf::<Sha256>::from_prk(&keys.exporter_secret)
+            .expect("exporter secret is a valid PRK");
+        expand(&exporter, b"pqc-vpn frame binding", &mut frame_binding);
+
+        Self {
+            role,
+            send: SendState {
+                current: EpochKey::new(0, aead, Zeroizing::new(*send_key)),
+                next_seq: 0,
+                epoch_bytes: 0,
+                epoch_messages: 0,
+                epoch_started: Instant::now(),
+            },
+            recv: ReceiveState {
+                current: EpochKey::new(0, aead, Zeroizing::new(*recv_key)),
+                previous: None,
+                next: None,
+                window: ReplayWindow::new(),
+            },
+            rekey_policy: RekeyPolicy::default(),
+            exporter_secret: Zeroizing::new(keys.exporter_secret),
+            frame_binding,
+        }
+    }
+
+    pub fn with_rekey_policy(mut self, policy: RekeyPolicy) -> Self {
+        self.rekey_policy = policy;
+        self
+    }
+
+    pub fn aead(&self) -> AeadAlgorithm {
+        self.send.current.aead
+    }
+
+    /// Key epochs currently in use as `(send, receive)`.
+    pub fn epochs(&self) -> (u32, u32) {
+        (self.send.current.epoch, self.recv.current.epoch)
+    }
 
-        Ok(Self {
-            shared_key,
-            cipher,
-            nonce_counter: 0,
-        })
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.recv.window.stats()
     }
 
+    /// Derives `out.len()` bytes of keying material bound to this session
+    /// and `label`, without exposing the traffic keys.
+    pub fn export_keying_material(&self, label: &[u8], out: &mut [u8]) -> Result<(), CryptoError> {
+        Hkdf::<Sha256>::from_prk(self.exporter_secret.as_ref())
+            .map_err(|_| CryptoError::KeyExchangeError)?
+            .expand(label, out)
+            .map_err(|_| CryptoError::KeyExchangeError)
+    }
+
+    /// Encrypts `data` as a [`FrameType::Data`] frame on stream 0.
     pub fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let mut nonce_bytes = [0u8; 12];
-        nonce_bytes[4..12].copy_from_slice(&self.nonce_counter.to_be_bytes());
-        self.nonce_counter = self.nonce_counter.wrapping_add(1);
+        self.encrypt_frame(FrameType::Data, 0, data)
+    }
+
+    /// Decrypts a frame of any type and stream and returns its payload.
+    pub fn decrypt(&mut self, frame: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.decrypt_frame(frame).map(|frame| frame.payload)
+    }
+
+    /// Encrypts `data` into a complete frame: header followed by ciphertext.
+    /// Ratchets the send key first if the rekey policy says so, and fails
+    /// rather than ever reusing a sequence number.
+    pub fn encrypt_frame(
+        &mut self,
+        frame_type: FrameType,
+        stream: u32,
+        data: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        if self.send.needs_rekey(&self.rekey_policy, data.len()) {
+            self.send.rekey()?;
+        }
+        let seq = self.send.next_seq;
+        self.send.next_seq = seq.checked_add(1).ok_or_else(|| {
+            CryptoError::EncryptionError("sequence numbers exhausted".to_string())
+        })?;
 
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        self.cipher
-            .encrypt(nonce, data)
-            .map_err(|e| CryptoError::EncryptionError(e.to_string()))
+        let header = FrameHeader {
+            frame_type,
+            stream,
+            epoch: self.send.current.epoch,
+            seq,
+        }
+        .to_bytes();
+        let aad = self.associated_data(&header, self.role);
+
+        let ciphertext = self
+            .send
+            .current
+            .cipher
+            .encrypt(
+                seq,
+                Payload {
+                    msg: data,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| CryptoError::EncryptionError(e.to_string()))?;
+        self.send.epoch_bytes += data.len() as u64;
+        self.send.epoch_messages += 1;
+
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + ciphertext.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(&ciphertext);
+        Ok(frame)
     }
 
-    pub fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let mut nonce_bytes = [0u8; 12];
-        nonce_bytes[4..12].copy_from_slice(&self.nonce_counter.to_be_bytes());
-        self.nonce_counter = self.nonce_counter.wrapping_add(1);
+    /// Decrypts a frame produced by the peer's `encrypt_frame`. Frames may
+    /// arrive out of order within the replay window; authentic duplicates
+    /// and frames older than the window are rejected and counted. The first
+    /// authentic frame of the next epoch switches the receive key; the
+    /// previous key stays usable for frames that were already in flight.
+    /// Frames from any later epoch are refused without deriving its key.
+    pub fn decrypt_frame(&mut self, frame: &[u8]) -> Result<Frame, CryptoError> {
+        if frame.len() < FRAME_HEADER_LEN + TAG_LEN {
+            return Err(CryptoError::DecryptionError("frame too short".to_string()));
+        }
+        let header = FrameHeader::parse(frame)?;
+        let (header_bytes, ciphertext) = frame.split_at(FRAME_HEADER_LEN);
+        let (epoch, seq) = (header.epoch, header.seq);
+        let replayed = self.recv.window.check(seq).err();
+
+        let peer = match self.role {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        };
+        let aad = self.associated_data(header_bytes, peer);
+        let recv = &mut self.recv;
+        let advancing = epoch > recv.current.epoch;
+        let key = if epoch == recv.current.epoch {
+            &recv.current
+        } else if let Some(previous) = recv.previous.as_ref().filter(|k| k.epoch == epoch) {
+            previous
+        } else if recv.current.epoch.checked_add(1) == Some(epoch) {
+            match &mut recv.next {
+                Some(next) => next,
+                next => next.insert(recv.current.next()?),
+            }
+        } else {
+            return Err(CryptoError::DecryptionError(format!(
+                "unknown key epoch {}",
+                epoch
+            )));
+        };
+        let payload = key
+            .cipher
+            .decrypt(
+                seq,
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|e| CryptoError::DecryptionError(e.to_string()))?;
+
+        if let Some(rejection) = replayed {
+            recv.window.record(rejection);
+            return Err(CryptoError::ReplayError(rejection));
+        }
+        if advancing {
+            let next = recv.next.take().expect("next epoch key was derived");
+            recv.previous = Some(std::mem::replace(&mut recv.current, next));
+        }
+        recv.window.accept(seq);
+        Ok(Frame {
+            frame_type: header.frame_type,
+            stream: header.stream,
+            payload,
+        })
+    }
 
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        self.cipher
-            .decrypt(nonce, data)
-            .map_err(|e| CryptoError::DecryptionError(e.to_string()))
+    /// The header as sent, then this session's frame binding and the
+    /// sender's role, which never go on the wire.
+    fn associated_data(&self, header: &[u8], sender: Role) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(header.len() + self.frame_binding.len() + 1);
+        aad.extend_from_slice(header);
+        aad.extend_from_slice(&self.frame_binding);
+        aad.push(sender as u8);
+        aad
     }
 }
 
+/// One ephemeral KEM keypair. The secret half is wiped on drop.
+struct KemKeyPair {
+    public_key: Vec<u8>,
+    secret_key: Zeroizing<Vec<u8>>,
+}
+
+/// Ephemeral KEM and X25519 keys for one handshake, generated per
+/// algorithm on first use. The secret halves are wiped on drop.
 pub struct KeyExchange {
-    kyber_secret_key: Vec<u8>,
-    kyber_public_key: Vec<u8>,
-    dilithium_secret_key: Vec<u8>,
-    dilithium_public_key: Vec<u8>,
+    kem_keys: Mutex<HashMap<KemAlgorithm, Arc<KemKeyPair>>>,
+    identity: Arc<SigningIdentity>,
+    next_identity: Option<Arc<NextIdentity>>,
+    suites: Vec<AlgorithmSuite>,
+    certificate: Option<Arc<ServerCertificate>>,
+    revocations: Option<Arc<dyn Revocations>>,
+}
+
+impl fmt::Debug for KeyExchange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyExchange")
+            .field(
+                "kyber_public_key",
+                &self.kem_keypair(KemAlgorithm::Kyber768).public_key,
+            )
+            .field(
+                "x25519_public_key",
+                &self.kem_keypair(KemAlgorithm::X25519).public_key,
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl KeyExchange {
+    /// Fresh ephemeral keys under a throwaway signing identity.
     pub fn new() -> Self {
-        let (kyber_public_key, kyber_secret_key) = kyber_keypair();
-        let (dilithium_public_key, dilithium_secret_key) = dilithium_keypair();
+        Self::with_identity(Arc::new(SigningIdentity::generate()))
+    }
 
-        Self {
-            kyber_secret_key: kyber_secret_key.as_bytes().to_vec(),
-            kyber_public_key: kyber_public_key.as_bytes().to_vec(),
-            dilithium_secret_key: dilithium_secret_key.as_bytes().to_vec(),
-            dilithium_public_key: dilithium_public_key.as_bytes().to_vec(),
-        }
+    /// Fresh ephemeral KEM and X25519 keys, authenticated by a long-term
+    /// signing identity shared across connections.
+    pub fn with_identity(identity: Arc<SigningIdentity>) -> Self {
+        let key_exchange = Self {
+            kem_keys: Mutex::new(HashMap::new()),
+            identity,
+            next_identity: None,
+            suites: AlgorithmSuite::DEFAULT_OFFER.to_vec(),
+            certificate: None,
+            revocations: None,
+        };
+        // The server hello always carries these two.
+        key_exchange.kem_keypair(KemAlgorithm::Kyber768);
+        key_exchange.kem_keypair(KemAlgorithm::X25519);
+        key_exchange
+    }
+
+    /// Suites to offer, most preferred first. Suites the identity has no
+    /// signing key for are left out of the hello.
+    pub fn with_suites(mut self, suites: Vec<AlgorithmSuite>) -> Self {
+        self.suites = suites;
+        self
+    }
+
+    /// Certificate to send in the hello alongside the identity's keys.
+    pub fn with_certificate(mut self, certificate: Option<Arc<ServerCertificate>>) -> Self {
+        self.certificate = certificate;
+        self
+    }
+
+    /// Identity being rotated to. Its keys go in the hello and it signs
+    /// each handshake alongside the current identity.
+    pub fn with_next_identity(mut self, next_identity: Option<Arc<NextIdentity>>) -> Self {
+        self.next_identity = next_identity;
+        self
+    }
+
+    /// Keys to refuse whatever they sign: client keys on the server, and
+    /// server keys on a client.
+    pub fn with_revocations(mut self, revocations: Option<Arc<dyn Revocations>>) -> Self {
+        self.revocations = revocations;
+        self
+    }
+
+    /// This handshake's keypair for `algorithm`, generated on first use.
+    fn kem_keypair(&self, algorithm: KemAlgorithm) -> Arc<KemKeyPair> {
+        self.kem_keys
+            .lock()
+            .unwrap()
+            .entry(algorithm)
+            .or_insert_with(|| {
+                let (public_key, secret_key) = kem_for(algorithm).keypair();
+                Arc::new(KemKeyPair {
+                    public_key,
+                    secret_key,
+                })
+            })
+            .clone()
     }
 
     pub fn get_public_keys(&self) -> (Vec<u8>, Vec<u8>) {
         (
-            self.kyber_public_key.clone(),
-            self.dilithium_public_key.clone(),
+            self.kem_keypair(KemAlgorithm::Kyber768).public_key.clone(),
+            self.identity
+                .public_key(SignatureAlgorithm::Dilithium2)
+                .unwrap_or_default()
+                .to_vec(),
         )
     }
 
+    /// Server-side check of a signature by a client key, refusing keys on
+    /// the server's revocation list.
     pub fn verify_client_signature(
         &self,
+        algorithm: SignatureAlgorithm,
         data: &[u8],
         signature: &[u8],
         client_public_key: &[u8],
     ) -> Result<(), CryptoError> {
-        let sig = pqcrypto_dilithium::dilithium2::DetachedSignature::from_bytes(signature)
-            .map_err(|_| CryptoError::SignatureError)?;
-        let pk = pqcrypto_dilithium::dilithium2::PublicKey::from_bytes(client_public_key)
-            .map_err(|_| CryptoError::SignatureError)?;
+        self.refuse_revoked(client_public_key)?;
+        signer_for(algorithm).verify(data, signature, client_public_key)
+    }
+
+    /// Client-side check of a signature by one of the server's keys from
+    /// its hello, refusing server keys on the client's revocation list.
+    pub fn verify_server_signature(
+        &self,
+        algorithm: SignatureAlgorithm,
+        data: &[u8],
+        signature: &[u8],
+        server_public_key: &[u8],
+    ) -> Result<(), CryptoError> {
+        self.refuse_revoked(server_public_key)?;
+        signer_for(algorithm).verify(data, signature, server_public_key)
+    }
+
+    fn refuse_revoked(&self, public_key: &[u8]) -> Result<(), CryptoError> {
+        match &self.revocations {
+            Some(revocations) if revocations.is_revoked(public_key) => {
+                Err(CryptoError::RevokedKeyError(fingerprint(public_key)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Server-side check of the client's signatures on `request`: the
+    /// suite's signature alone, or in `Dual` auth mode both it and the
+    /// Ed25519 one over the client-auth transcript.
+    pub fn verify_client_request(&self, request: &HandshakeRequest) -> Result<(), CryptoError> {
+        let hash = Transcript::client_auth(&self.server_hello(), request).hash();
+        match request.auth {
+            AuthMode::PqOnly => self.verify_client_signature(
+                request.suite.signature,
+                &hash,
+                &request.signature,
+                &request.client_signing_key,
+            ),
+            AuthMode::Dual => {
+                let (Some(ed25519_key), Some(ed25519_signature)) = (
+                    &request.client_ed25519_key,
+                    &request.client_ed25519_signature,
+                ) else {
+                    return Err(CryptoError::SignatureError);
+                };
+                self.verify_client_signature(
+                    request.suite.signature,
+                    &hash,
+                    &request.signature,
+                    &request.client_signing_key,
+                )?;
+                self.verify_client_signature(
+                    SignatureAlgorithm::Ed25519,
+                    &hash,
+                    ed25519_signature,
+                    ed25519_key,
+                )
+            }
+        }
+    }
+
+    pub fn sign_data(
+        &self,
+        algorithm: SignatureAlgorithm,
+        data: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.identity.sign(algorithm, data)
+    }
+
+    fn signing_public_key(&self, algorithm: SignatureAlgorithm) -> Result<&[u8], CryptoError> {
+        self.identity
+            .public_key(algorithm)
+            .ok_or_else(|| CryptoError::UnsupportedAlgorithm(algorithm.as_str().to_string()))
+    }
+
+    pub fn server_hello(&self) -> ServerHello {
+        ServerHello {
+            kyber_public_key: self.kem_keypair(KemAlgorithm::Kyber768).public_key.clone(),
+            dilithium_public_key: self
+                .signing_public_key(SignatureAlgorithm::Dilithium2)
+                .unwrap_or_default()
+                .to_vec(),
+            ml_dsa_public_key: self
+                .signing_public_key(SignatureAlgorithm::MlDsa65)
+                .unwrap_or_default()
+                .to_vec(),
+            x25519_public_key: self.kem_keypair(KemAlgorithm::X25519).public_key.clone(),
+            supported_modes: vec![KeyExchangeMode::Hybrid, KeyExchangeMode::PqOnly],
+            supported_suites: self
+                .suites
+                .iter()
+                .copied()
+                .filter(|suite| self.identity.public_key(suite.signature).is_some())
+                .collect(),
+            supported_aeads: AeadAlgorithm::ALL.to_vec(),
+            signing_keys: self
+                .suites
+                .iter()
+                .map(|suite| suite.signature)
+                .chain([SignatureAlgorithm::Ed25519])
+                .filter(|algorithm| {
+                    !matches!(
+                        algorithm,
+                        SignatureAlgorithm::Dilithium2 | SignatureAlgorithm::MlDsa65
+                    )
+                })
+                .filter_map(|algorithm| {
+                    Some((algorithm, self.identity.public_key(algorithm)?.to_vec()))
+                })
+                .collect(),
+            certificate: self.certificate.as_deref().cloned(),
+            next_signing_keys: self
+                .next_identity
+                .iter()
+                .flat_map(|next| next.keys.iter())
+                .filter(|(algorithm, _)| {
+                    self.suites
+                        .iter()
+                        .any(|suite| suite.signature == **algorithm)
+                })
+                .map(|(&algorithm, key)| (algorithm, key.clone()))
+                .collect(),
+        }
+    }
+
+    /// Builds the client's handshake message for the given suite, mode and
+    /// AEAD. Fails if the identity has no key for the suite's signature.
+    pub fn handshake_request(
+        &self,
+        hello: &ServerHello,
+        suite: AlgorithmSuite,
+        mode: KeyExchangeMode,
+        aead: AeadAlgorithm,
+    ) -> Result<HandshakeRequest, CryptoError> {
+        let kem_keys = self.kem_keypair(suite.kem);
+        let mut request = HandshakeRequest {
+            client_public_key: kem_keys.public_key.clone(),
+            client_signing_key: self.signing_public_key(suite.signature)?.to_vec(),
+            signature: Vec::new(),
+            suite,
+            mode,
+            aead,
+            client_x25519_public_key: match mode {
+                KeyExchangeMode::PqOnly => None,
+                KeyExchangeMode::Hybrid => {
+                    Some(self.kem_keypair(KemAlgorithm::X25519).public_key.clone())
+                }
+            },
+            auth: AuthMode::PqOnly,
+            client_ed25519_key: None,
+            client_ed25519_signature: None,
+            psk_binder: None,
+        };
+        // Signing the transcript rather than just the KEM key ties the
+        // request to this server hello, so it cannot be replayed on
+        // another connection.
+        let hash = Transcript::client_auth(hello, &request).hash();
+        request.signature = self.sign_data(suite.signature, &hash)?;
+        Ok(request)
+    }
+
+    /// Switches a request to `Dual` auth mode, signing the client-auth
+    /// transcript with both the suite's key and the Ed25519 key. Must come
+    /// after every other change to the request except `bind_psk`.
+    pub fn sign_dual(
+        &self,
+        hello: &ServerHello,
+        request: &mut HandshakeRequest,
+    ) -> Result<(), CryptoError> {
+        if hello.signing_key(SignatureAlgorithm::Ed25519).is_none() {
+            return Err(CryptoError::UnsupportedAlgorithm(
+                SignatureAlgorithm::Ed25519.as_str().to_string(),
+            ));
+        }
+        request.auth = AuthMode::Dual;
+        request.client_ed25519_key = Some(
+            self.signing_public_key(SignatureAlgorithm::Ed25519)?
+                .to_vec(),
+        );
+        let hash = Transcript::client_auth(hello, request).hash();
+        request.signature = self.sign_data(request.suite.signature, &hash)?;
+        request.client_ed25519_signature =
+            Some(self.sign_data(SignatureAlgorithm::Ed25519, &hash)?);
+        Ok(())
+    }
+
+    /// Commits a request to `psk`. Must be the last change to the request
+    /// before it is sent.
+    pub fn bind_psk(
+        &self,
+        hello: &ServerHello,
+        request: &mut HandshakeRequest,
+        psk: &PresharedKey,
+    ) {
+        request.psk_binder = None;
+        request.psk_binder = Some(psk.binder(hello, request).to_vec());
+    }
+
+    /// Appends the X25519 shared secret to the KEM secret when the mode
+    /// asks for it, then the PSK if there is one.
+    fn combine_secrets(
+        &self,
+        mode: KeyExchangeMode,
+        kem_secret: &[u8],
+        peer_x25519_public_key: Option<&[u8]>,
+        psk: Option<&PresharedKey>,
+    ) -> Result<Zeroizing<Vec<u8>>, CryptoError> {
+        // Sized up front so appending never reallocates and strands a copy.
+        let mut ikm = Zeroizing::new(Vec::with_capacity(kem_secret.len() + 64));
+        ikm.extend_from_slice(kem_secret);
+        if mode == KeyExchangeMode::Hybrid {
+            let dh = kem_for(KemAlgorithm::X25519).decapsulate(
+                &self.kem_keypair(KemAlgorithm::X25519).secret_key,
+                peer_x25519_public_key.ok_or(CryptoError::KeyExchangeError)?,
+            )?;
+            ikm.extend_from_slice(&dh);
+        }
+        if let Some(psk) = psk {
+            ikm.extend_from_slice(psk.0.as_ref());
+        }
+        Ok(ikm)
+    }
+
+    /// Server half of the handshake: encapsulates against the client's KEM
+    /// key and returns the signed response to send back together with the
+    /// server's session. `psk` is whatever the server has configured for
+    /// this client; the request must be bound to exactly that.
+    pub fn process_client_key(
+        &self,
+        request: &HandshakeRequest,
+        psk: Option<&PresharedKey>,
+    ) -> Result<(HandshakeResponse, CryptoSession), CryptoError> {
+        let hello = self.server_hello();
+        if !hello.supported_suites.contains(&request.suite) {
+            return Err(CryptoError::UnsupportedAlgorithm(request.suite.name()));
+        }
+        if !hello.supported_aeads.contains(&request.aead) {
+            return Err(CryptoError::UnsupportedAlgorithm(
+                request.aead.as_str().to_string(),
+            ));
+        }
+        match (psk, &request.psk_binder) {
+            (None, None) => {}
+            (Some(_), None) => return Err(CryptoError::PskError("client did not present a PSK")),
+            (None, Some(_)) => return Err(CryptoError::PskError("no PSK configured for client")),
+            (Some(psk), Some(binder)) => {
+                if !bool::from(psk.binder(&hello, request).ct_eq(binder.as_slice())) {
+                    return Err(CryptoError::PskError("PSK mismatch"));
+                }
+            }
+        }
+
+        let (shared_secret, ciphertext) =
+            kem_for(request.suite.kem).encapsulate(&request.client_public_key)?;
+        let ikm = self.combine_secrets(
+            request.mode,
+            &shared_secret,
+            request.client_x25519_public_key.as_deref(),
+            psk,
+        )?;
+
+        let mut transcript = Transcript::new(&hello, request);
+        transcript.append(&ciphertext);
+        let signature = self.sign_data(request.suite.signature, &transcript.hash())?;
+        let next_signature = match &self.next_identity {
+            Some(next)
+                if hello
+                    .next_signing_keys
+                    .contains_key(&request.suite.signature) =>
+            {
+                Some(
+                    next.identity
+                        .sign(request.suite.signature, &transcript.hash())?,
+                )
+            }
+            _ => None,
+        };
+        let ed25519_signature = match request.auth {
+            AuthMode::PqOnly => None,
+            AuthMode::Dual => {
+                Some(self.sign_data(SignatureAlgorithm::Ed25519, &transcript.hash())?)
+            }
+        };
+        transcript.append(&signature);
+        if let Some(ed25519_signature) = &ed25519_signature {
+            transcript.append(ed25519_signature);
+        }
+
+        let keys = SessionKeys::derive(&ikm, &transcript.hash());
+        let response = HandshakeResponse {
+            message_type: "handshake_response".to_string(),
+            suite: request.suite,
+            mode: request.mode,
+            aead: request.aead,
+            ciphertext,
+            signature,
+            auth: request.auth,
+            ed25519_signature,
+            next_signature,
+        };
+        Ok((
+            response,
+            CryptoSession::new(&keys, Role::Server, request.aead),
+        ))
+    }
+
+    /// Client half of the handshake: checks the server's signature over the
+    /// transcript and decapsulates the ciphertext into the client's session.
+    /// `psk` must be the key the request was bound to, if any.
+    pub fn process_server_response(
+        &self,
+        hello: &ServerHello,
+        request: &HandshakeRequest,
+        response: &HandshakeResponse,
+        psk: Option<&PresharedKey>,
+    ) -> Result<CryptoSession, CryptoError> {
+        if psk.is_some() != request.psk_binder.is_some() {
+            return Err(CryptoError::PskError("request was not bound to this PSK"));
+        }
+        if response.mode != request.mode
+            || response.suite != request.suite
+            || response.aead != request.aead
+            || response.auth != request.auth
+        {
+            return Err(CryptoError::KeyExchangeError);
+        }
+        let server_key = hello.signing_key(response.suite.signature).ok_or_else(|| {
+            CryptoError::UnsupportedAlgorithm(response.suite.signature.as_str().to_string())
+        })?;
+        let mut transcript = Transcript::new(hello, request);
+        transcript.append(&response.ciphertext);
+        self.verify_server_signature(
+            response.suite.signature,
+            &transcript.hash(),
+            &response.signature,
+            server_key,
+        )?;
+        // A hello that names a next key must carry its endorsement and a
+        // signature by it, so a client pinned to either key is covered.
+        if let Some(next) = hello.next_signing_keys.get(&response.suite.signature) {
+            self.verify_server_signature(
+                response.suite.signature,
+                &endorsement_bytes(response.suite.signature, &next.public_key),
+                &next.endorsement,
+                server_key,
+            )?;
+            self.verify_server_signature(
+                response.suite.signature,
+                &transcript.hash(),
+                response
+                    .next_signature
+                    .as_deref()
+                    .ok_or(CryptoError::SignatureError)?,
+                &next.public_key,
+            )?;
+        }
+        let ed25519_signature = match response.auth {
+            AuthMode::PqOnly => None,
+            AuthMode::Dual => {
+                let ed25519_signature = response
+                    .ed25519_signature
+                    .as_deref()
+                    .ok_or(CryptoError::SignatureError)?;
+                self.verify_server_signature(
+                    SignatureAlgorithm::Ed25519,
+                    &transcript.hash(),
+                    ed25519_signature,
+                    hello
+                        .signing_key(SignatureAlgorithm::Ed25519)
+                        .ok_or(CryptoError::SignatureError)?,
+                )?;
+                Some(ed25519_signature)
+            }
+        };
+        transcript.append(&response.signature);
+        if let Some(ed25519_signature) = ed25519_signature {
+            transcript.append(ed25519_signature);
+        }
+
+        let shared_secret = kem_for(response.suite.kem).decapsulate(
+            &self.kem_keypair(response.suite.kem).secret_key,
+            &response.ciphertext,
+        )?;
+        let ikm = self.combine_secrets(
+            response.mode,
+            &shared_secret,
+            Some(&hello.x25519_public_key),
+            psk,
+        )?;
 
-        verify_detached_signature(&sig, data, &pk).map_err(|_| CryptoError::SignatureError)
+        let keys = SessionKeys::derive(&ikm, &transcript.hash());
+        Ok(CryptoSession::new(&keys, Role::Client, response.aead))
     }
+}
+
+/// FIPS 203 section 7.2 modulus check: every 12-bit coefficient of the
+/// encoded vector in the encapsulation key must already be reduced mod q.
+fn ml_kem_encapsulation_key_is_valid(encapsulation_key: &[u8]) -> bool {
+    const Q: u16 = 3329;
+    const POLYVEC_BYTES: usize = 3 * 384;
+
+    encapsulation_key.len() == POLYVEC_BYTES + 32
+        && encapsulation_key[..POLYVEC_BYTES].chunks_exact(3).all(|b| {
+            let low = u16::from(b[0]) | (u16::from(b[1] & 0x0f) << 8);
+            let high = u16::from(b[1] >> 4) | (u16::from(b[2]) << 4);
+            low < Q && high < Q
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn sign_data(&self, data: &[u8]) -> Vec<u8> {
-        let sk = pqcrypto_dilithium::dilithium2::SecretKey::from_bytes(&self.dilithium_secret_key)
+    /// Runs both halves of the handshake and returns `(client, server)`.
+    fn run_handshake(
+        suite: AlgorithmSuite,
+        mode: KeyExchangeMode,
+        aead: AeadAlgorithm,
+    ) -> (CryptoSession, CryptoSession) {
+        let server = KeyExchange::new().with_suites(vec![suite]);
+        let client = KeyExchange::new();
+        let hello = server.server_hello();
+
+        let request = client.handshake_request(&hello, suite, mode, aead).unwrap();
+        server.verify_client_request(&request).unwrap();
+        let (response, mut server_session) = server.process_client_key(&request, None).unwrap();
+
+        // Round-trip through JSON the way the response travels over the socket.
+        let response: HandshakeResponse =
+            serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        assert_eq!(response.message_type, "handshake_response");
+        assert_eq!(response.suite, suite);
+        assert_eq!(response.mode, mode);
+        assert_eq!(response.aead, aead);
+        let mut client_session = client
+            .process_server_response(&hello, &request, &response, None)
+            .unwrap();
+
+        let ciphertext = server_session.encrypt(b"hello from the server").unwrap();
+        assert_eq!(
+            client_session.decrypt(&ciphertext).unwrap(),
+            b"hello from the server"
+        );
+
+        let mut server_exported = [0u8; 32];
+        let mut client_exported = [0u8; 32];
+        server_session
+            .export_keying_material(b"test", &mut server_exported)
+            .unwrap();
+        client_session
+            .export_keying_material(b"test", &mut client_exported)
             .unwrap();
-        detached_sign(data, &sk).as_bytes().to_vec()
+        assert_eq!(server_exported, client_exported);
+
+        (client_session, server_session)
     }
 
-    pub fn process_client_key(&self, client_public_key: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let pk = pqcrypto_kyber::kyber768::PublicKey::from_bytes(client_public_key)
-            .map_err(|_| CryptoError::KeyExchangeError)?;
-        let (_ciphertext, shared_secret) = encapsulate(&pk);
-        Ok(shared_secret.as_bytes().to_vec())
+    struct RevokedServerKey(Vec<u8>);
+
+    impl Revocations for RevokedServerKey {
+        fn is_revoked(&self, public_key: &[u8]) -> bool {
+            public_key == self.0
+        }
     }
 
-    pub fn process_server_response(&self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
-        let ct = pqcrypto_kyber::kyber768::Ciphertext::from_bytes(ciphertext)
-            .map_err(|_| CryptoError::KeyExchangeError)?;
-        let sk = pqcrypto_kyber::kyber768::SecretKey::from_bytes(&self.kyber_secret_key)
-            .map_err(|_| CryptoError::KeyExchangeError)?;
-        let shared_secret = decapsulate(&ct, &sk);
-        Ok(shared_secret.as_bytes().to_vec())
+    #[test]
+    fn client_refuses_a_response_signed_by_a_revoked_server_key() {
+        let suite = AlgorithmSuite::FIPS;
+        let server = KeyExchange::new().with_suites(vec![suite]);
+        let hello = server.server_hello();
+        let server_key = hello.signing_key(suite.signature).unwrap().to_vec();
+        let client =
+            KeyExchange::new().with_revocations(Some(Arc::new(RevokedServerKey(server_key))));
+
+        let request = client
+            .handshake_request(
+                &hello,
+                suite,
+                KeyExchangeMode::PqOnly,
+                AeadAlgorithm::Aes256Gcm,
+            )
+            .unwrap();
+        let (response, _) = server.process_client_key(&request, None).unwrap();
+        assert!(matches!(
+            client.process_server_response(&hello, &request, &response, None),
+            Err(CryptoError::RevokedKeyError(_))
+        ));
+    }
+
+    #[test]
+    fn handshake_yields_matching_sessions() {
+        run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::Aes256Gcm,
+        );
+        run_handshake(
+            AlgorithmSuite::FIPS,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::Aes256Gcm,
+        );
+    }
+
+    #[test]
+    fn hybrid_handshake_yields_matching_sessions() {
+        run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::Hybrid,
+            AeadAlgorithm::Aes256Gcm,
+        );
+        run_handshake(
+            AlgorithmSuite::FIPS,
+            KeyExchangeMode::Hybrid,
+            AeadAlgorithm::Aes256Gcm,
+        );
+    }
+
+    #[test]
+    fn falcon_and_sphincs_suites_yield_matching_sessions() {
+        for signature in [
+            SignatureAlgorithm::Falcon512,
+            SignatureAlgorithm::Falcon1024,
+            SignatureAlgorithm::SphincsSha2128s,
+        ] {
+            let suite = AlgorithmSuite {
+                kem: KemAlgorithm::MlKem768,
+                signature,
+            };
+            run_handshake(suite, KeyExchangeMode::PqOnly, AeadAlgorithm::Aes256Gcm);
+        }
+    }
+
+    #[test]
+    fn every_security_level_yields_matching_sessions() {
+        for (suite, level) in [
+            (AlgorithmSuite::LEVEL1, 1),
+            (AlgorithmSuite::LEGACY, 2),
+            (AlgorithmSuite::FIPS, 3),
+            (AlgorithmSuite::LEVEL5, 5),
+        ] {
+            assert_eq!(suite.security_level(), level);
+            run_handshake(suite, KeyExchangeMode::Hybrid, AeadAlgorithm::Aes256Gcm);
+        }
+    }
+
+    #[test]
+    fn server_only_accepts_the_suites_it_offers() {
+        let server = KeyExchange::new().with_suites(vec![AlgorithmSuite::LEVEL5]);
+        let client = KeyExchange::new();
+        let hello = server.server_hello();
+        assert_eq!(hello.supported_suites, vec![AlgorithmSuite::LEVEL5]);
+        assert!(hello.signing_key(SignatureAlgorithm::Dilithium5).is_some());
+
+        let request = client
+            .handshake_request(
+                &hello,
+                AlgorithmSuite::LEGACY,
+                KeyExchangeMode::PqOnly,
+                AeadAlgorithm::Aes256Gcm,
+            )
+            .unwrap();
+        assert!(matches!(
+            server.process_client_key(&request, None),
+            Err(CryptoError::UnsupportedAlgorithm(_))
+        ));
+
+        // Suites the identity cannot sign for are never offered.
+        let identity = Arc::new(SigningIdentity::generate_for(&[]));
+        let server = KeyExchange::with_identity(identity)
+            .with_suites(vec![AlgorithmSuite::LEVEL5, AlgorithmSuite::FIPS]);
+        assert_eq!(
+            server.server_hello().supported_suites,
+            vec![AlgorithmSuite::FIPS]
+        );
+    }
+
+    #[test]
+    fn interleaved_traffic_keeps_both_directions_in_sync() {
+        let (mut client, mut server) = run_handshake(
+            AlgorithmSuite::FIPS,
+            KeyExchangeMode::Hybrid,
+            AeadAlgorithm::Aes256Gcm,
+        );
+
+        // The server already sent one frame during the handshake check.
+        let up1 = client.encrypt(b"up 1").unwrap();
+        let down1 = server.encrypt(b"down 1").unwrap();
+        let up2 = client.encrypt(b"up 2").unwrap();
+        assert_eq!(server.decrypt(&up1).unwrap(), b"up 1");
+        let down2 = server.encrypt(b"down 2").unwrap();
+        let down3 = server.encrypt(b"down 3").unwrap();
+        assert_eq!(client.decrypt(&down1).unwrap(), b"down 1");
+        assert_eq!(server.decrypt(&up2).unwrap(), b"up 2");
+        assert_eq!(client.decrypt(&down2).unwrap(), b"down 2");
+        let up3 = client.encrypt(b"up 3").unwrap();
+        assert_eq!(client.decrypt(&down3).unwrap(), b"down 3");
+        assert_eq!(server.decrypt(&up3).unwrap(), b"up 3");
+    }
+
+    #[test]
+    fn directions_use_separate_keys() {
+        let (mut client, mut server) = run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::Aes256Gcm,
+        );
+
+        // The server sent one frame during the handshake check; burn one
+        // client nonce too so both senders are at counter 1. Equal plaintexts
+        // must still encrypt differently because the keys differ.
+        client.encrypt(b"warm-up").unwrap();
+        let up = client.encrypt(b"same plaintext").unwrap();
+        let down = server.encrypt(b"same plaintext").unwrap();
+        assert_ne!(up, down);
+        assert_eq!(client.decrypt(&down).unwrap(), b"same plaintext");
+
+        // A frame reflected back at its sender must not decrypt, even though
+        // its sequence number is still fresh for the client's receive side.
+        let own = client.encrypt(b"reflect me").unwrap();
+        assert!(client.decrypt(&own).is_err());
+    }
+
+    #[test]
+    fn replay_window_drops_duplicates_and_tolerates_reordering() {
+        let (mut client, mut server) = run_handshake(
+            AlgorithmSuite::FIPS,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::XChaCha20Poly1305,
+        );
+
+        let frames: Vec<_> = (0..5).map(|i| client.encrypt(&[i]).unwrap()).collect();
+        assert_eq!(server.decrypt(&frames[3]).unwrap(), [3]);
+        assert_eq!(server.decrypt(&frames[0]).unwrap(), [0]);
+        assert_eq!(server.decrypt(&frames[4]).unwrap(), [4]);
+        assert_eq!(server.decrypt(&frames[2]).unwrap(), [2]);
+        assert!(matches!(
+            server.decrypt(&frames[3]),
+            Err(CryptoError::ReplayError(ReplayRejection::Duplicate))
+        ));
+        assert_eq!(server.decrypt(&frames[1]).unwrap(), [1]);
+        assert!(server.decrypt(&frames[0]).is_err());
+        assert_eq!(server.replay_stats().duplicates_dropped, 2);
+    }
+
+    #[test]
+    fn replay_window_drops_frames_behind_the_window() {
+        let (mut client, mut server) = run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::Aes256Gcm,
+        );
+
+        let stale = client.encrypt(b"stale").unwrap();
+        for _ in 0..crate::replay::WINDOW_SIZE {
+            client.encrypt(b"skipped").unwrap();
+        }
+        let latest = client.encrypt(b"latest").unwrap();
+        assert_eq!(server.decrypt(&latest).unwrap(), b"latest");
+        assert!(matches!(
+            server.decrypt(&stale),
+            Err(CryptoError::ReplayError(ReplayRejection::TooOld))
+        ));
+        assert_eq!(server.replay_stats().too_old_dropped, 1);
+    }
+
+    #[test]
+    fn tampered_sequence_number_fails_authentication() {
+        let (mut client, mut server) = run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::Aes256Gcm,
+        );
+
+        let mut frame = client.encrypt(b"payload").unwrap();
+        frame[FRAME_HEADER_LEN - 1] ^= 1;
+        assert!(matches!(
+            server.decrypt(&frame),
+            Err(CryptoError::DecryptionError(_))
+        ));
+        // The forged sequence number must not have advanced the window.
+        frame[FRAME_HEADER_LEN - 1] ^= 1;
+        assert_eq!(server.decrypt(&frame).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn forged_frames_neither_count_as_replays_nor_ratchet_ahead() {
+        let (mut client, mut server) = run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::Aes256Gcm,
+        );
+
+        let frame = client.encrypt(b"payload").unwrap();
+        assert_eq!(server.decrypt(&frame).unwrap(), b"payload");
+        let mut forged = frame.clone();
+        *forged.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            server.decrypt(&forged),
+            Err(CryptoError::DecryptionError(_))
+        ));
+        assert_eq!(server.replay_stats().duplicates_dropped, 0);
+
+        // A forged frame for the next epoch derives its key once; any later
+        // epoch is refused outright.
+        for epoch in [1u32, 1, 2, 5] {
+            let mut forged = frame.clone();
+            forged[6..10].copy_from_slice(&epoch.to_be_bytes());
+            assert!(server.decrypt(&forged).is_err());
+            assert_eq!(server.epochs().1, 0);
+        }
+        assert_eq!(server.recv.next.as_ref().map(|next| next.epoch), Some(1));
+
+        client.send.rekey().unwrap();
+        let frame = client.encrypt(b"rekeyed").unwrap();
+        assert_eq!(server.decrypt(&frame).unwrap(), b"rekeyed");
+        assert_eq!(server.epochs().1, 1);
+        assert!(server.recv.next.is_none());
+    }
+
+    #[test]
+    fn frame_type_and_stream_are_authenticated() {
+        let (mut client, mut server) = run_handshake(
+            AlgorithmSuite::FIPS,
+            KeyExchangeMode::Hybrid,
+            AeadAlgorithm::ChaCha20Poly1305,
+        );
+
+        let frame = client.encrypt_frame(FrameType::Proxy, 7, b"GET /").unwrap();
+        // Relabelling the frame as another type or stream, or as another
+        // format version, makes it fail to authenticate or parse.
+        for byte in [0, 1, 5] {
+            let mut forged = frame.clone();
+            forged[byte] ^= 1;
+            assert!(matches!(
+                server.decrypt_frame(&forged),
+                Err(CryptoError::DecryptionError(_))
+            ));
+        }
+        assert_eq!(
+            server.decrypt_frame(&frame).unwrap(),
+            Frame {
+                frame_type: FrameType::Proxy,
+                stream: 7,
+                payload: b"GET /".to_vec(),
+            }
+        );
+
+        // A frame spliced in from another session never authenticates.
+        let (mut other_client, _) = run_handshake(
+            AlgorithmSuite::FIPS,
+            KeyExchangeMode::Hybrid,
+            AeadAlgorithm::ChaCha20Poly1305,
+        );
+        other_client.send.next_seq = 1;
+        let spliced = other_client
+            .encrypt_frame(FrameType::Proxy, 7, b"GET /")
+            .unwrap();
+        assert!(server.decrypt_frame(&spliced).is_err());
+    }
+
+    #[test]
+    fn rekeys_after_message_threshold_without_dropping_in_flight_frames() {
+        let (client, mut server) = run_handshake(
+            AlgorithmSuite::FIPS,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::ChaCha20Poly1305,
+        );
+        let mut client = client.with_rekey_policy(RekeyPolicy {
+            max_messages: 2,
+            ..RekeyPolicy::default()
+        });
+
+        let frames: Vec<_> = (0..5).map(|i| client.encrypt(&[i]).unwrap()).collect();
+        assert_eq!(client.epochs().0, 2);
+
+        // The first frame of the new epoch arrives before the last of the old.
+        assert_eq!(server.decrypt(&frames[0]).unwrap(), [0]);
+        assert_eq!(server.decrypt(&frames[2]).unwrap(), [2]);
+        assert_eq!(server.epochs().1, 1);
+        assert_eq!(server.decrypt(&frames[1]).unwrap(), [1]);
+        assert_eq!(server.decrypt(&frames[4]).unwrap(), [4]);
+        assert_eq!(server.decrypt(&frames[3]).unwrap(), [3]);
+        assert_eq!(server.epochs().1, 2);
+    }
+
+    #[test]
+    fn traffic_secrets_key_the_aead_only_through_hkdf() {
+        let keys = SessionKeys::derive(b"shared secret", &[0; 32]);
+        let aead = AeadAlgorithm::Aes256Gcm;
+        let epoch = EpochKey::new(0, aead, Zeroizing::new(keys.client_to_server));
+        let next = epoch.next().unwrap();
+        assert_ne!(*next.secret, *epoch.secret);
+
+        // Neither epoch's AEAD key is a traffic secret.
+        for key in [&epoch, &next] {
+            let sealed = key
+                .cipher
+                .encrypt(
+                    0,
+                    Payload {
+                        msg: b"frame",
+                        aad: b"header",
+                    },
+                )
+                .unwrap();
+            for secret in [&epoch.secret, &next.secret] {
+                let opened = SessionCipher::new(aead, secret).decrypt(
+                    0,
+                    Payload {
+                        msg: &sealed,
+                        aad: b"header",
+                    },
+                );
+                assert!(opened.is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn rekeys_after_byte_threshold() {
+        let (client, mut server) = run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::Hybrid,
+            AeadAlgorithm::Aes256Gcm,
+        );
+        let mut client = client.with_rekey_policy(RekeyPolicy {
+            max_bytes: 10,
+            ..RekeyPolicy::default()
+        });
+
+        let first = client.encrypt(&[0u8; 8]).unwrap();
+        let second = client.encrypt(&[1u8; 8]).unwrap();
+        assert_eq!(client.epochs().0, 1);
+        assert_ne!(
+            FrameHeader::parse(&first).unwrap().epoch,
+            FrameHeader::parse(&second).unwrap().epoch
+        );
+        assert_eq!(server.decrypt(&second).unwrap(), [1u8; 8]);
+        assert_eq!(server.decrypt(&first).unwrap(), [0u8; 8]);
+    }
+
+    #[test]
+    fn encrypt_fails_instead_of_wrapping_sequence_numbers() {
+        let (mut client, _) = run_handshake(
+            AlgorithmSuite::LEGACY,
+            KeyExchangeMode::PqOnly,
+            AeadAlgorithm::Aes256Gcm,
+        );
+        client.send.next_seq = u64::MAX;
+        assert!(matches!(
+            client.encrypt(b"one too many"),
+            Err(CryptoError::EncryptionError(_))
+        ));
+    }
+
+    #[test]
+    fn every_aead_carries_interleaved_traffic() {
+        for aead in AeadAlgorithm::ALL {
+            let (mut client, mut server) =
+                run_handshake(AlgorithmSuite::FIPS, KeyExchangeMode::Hybrid, aead);
+            assert_eq!(client.aead(), aead);
+            assert_eq!(server.aead(), aead);
+
+            let up = client.encrypt(b"up").unwrap();
+            let down = server.encrypt(b"down").unwrap();
+            assert_eq!(client.decrypt(&down).unwrap(), b"down");
+            assert_eq!(server.decrypt(&up).unwrap(), b"up");
+        }
+    }
+
+    #[test]
+    fn every_kem_agrees_on_a_shared_secret() {
+        for algorithm in KemAlgorithm::ALL {
+            let kem = kem_for(algorithm);
+            assert_eq!(kem.algorithm(), algorithm);
+            let (public_key, secret_key) = kem.keypair();
+            let (shared_secret, ciphertext) = kem.encapsulate(&public_key).unwrap();
+            assert_eq!(
+                kem.decapsulate(&secret_key, &ciphertext).unwrap(),
+                shared_secret
+            );
+            assert!(kem.encapsulate(&public_key[1..]).is_err());
+        }
+    }
+
+    #[test]
+    fn every_signer_verifies_only_its_own_signatures() {
+        for algorithm in SignatureAlgorithm::ALL {
+            let signer = signer_for(algorithm);
+            assert_eq!(signer.algorithm(), algorithm);
+            let (public_key, secret_key) = signer.keypair();
+            let signature = signer.sign(&secret_key, b"transcript").unwrap();
+            signer
+                .verify(b"transcript", &signature, &public_key)
+                .unwrap();
+            assert!(signer.verify(b"tampered", &signature, &public_key).is_err());
+            let (other_public_key, _) = signer.keypair();
+            assert!(signer
+                .verify(b"transcript", &signature, &other_public_key)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn legacy_request_defaults_to_legacy_suite() {
+        let server = KeyExchange::new();
+        let client = KeyExchange::new();
+        let hello = server.server_hello();
+        let mut request = serde_json::to_value(
+            client
+                .handshake_request(
+                    &hello,
+                    AlgorithmSuite::LEGACY,
+                    KeyExchangeMode::PqOnly,
+                    AeadAlgorithm::Aes256Gcm,
+                )
+                .unwrap(),
+        )
+        .unwrap();
+        request.as_object_mut().unwrap().remove("suite");
+        request.as_object_mut().unwrap().remove("mode");
+        request.as_object_mut().unwrap().remove("aead");
+        request.as_object_mut().unwrap().remove("auth");
+
+        let request: HandshakeRequest = serde_json::from_value(request).unwrap();
+        assert_eq!(request.suite, AlgorithmSuite::LEGACY);
+        assert_eq!(request.aead, AeadAlgorithm::Aes256Gcm);
+        assert!(server.process_client_key(&request, None).is_ok());
+    }
+
+    #[test]
+    fn ml_kem_rejects_unreduced_encapsulation_key() {
+        let server = KeyExchange::new();
+        let client = KeyExchange::new();
+        let hello = server.server_hello();
+
+        let mut request = client
+            .handshake_request(
+                &hello,
+                AlgorithmSuite::FIPS,
+                KeyExchangeMode::PqOnly,
+                AeadAlgorithm::Aes256Gcm,
+            )
+            .unwrap();
+        request.client_public_key[0] = 0xff;
+        request.client_public_key[1] |= 0x0f;
+        assert!(server.process_client_key(&request, None).is_err());
+    }
+
+    #[test]
+    fn hybrid_handshake_requires_client_x25519_key() {
+        let server = KeyExchange::new();
+        let client = KeyExchange::new();
+        let hello = server.server_hello();
+
+        let mut request = client
+            .handshake_request(
+                &hello,
+                AlgorithmSuite::FIPS,
+                KeyExchangeMode::Hybrid,
+                AeadAlgorithm::Aes256Gcm,
+            )
+            .unwrap();
+        request.client_x25519_public_key = None;
+        assert!(server.process_client_key(&request, None).is_err());
+
+        request.client_x25519_public_key = Some(vec![0u8; 32]);
+        assert!(server.process_client_key(&request, None).is_err());
+    }
+
+    #[test]
+    fn handshake_rejects_forged_response() {
+        let server = KeyExchange::new();
+        let client = KeyExchange::new();
+        let impostor = KeyExchange::new();
+        let hello = server.server_hello();
+
+        let request = client
+            .handshake_request(
+                &hello,
+                AlgorithmSuite::FIPS,
+                KeyExchangeMode::Hybrid,
+                AeadAlgorithm::Aes256Gcm,
+            )
+            .unwrap();
+        let (mut response, _) = impostor.process_client_key(&request, None).unwrap();
+        assert!(client
+            .process_server_response(&hello, &request, &response, None)
+            .is_err());
+
+        let (genuine, _) = server.process_client_key(&request, None).unwrap();
+        response.signature = genuine.signature;
+        assert!(client
+            .process_server_response(&hello, &request, &response, None)
+            .is_err());
+    }
+
+    #[test]
+    fn persisted_identity_matches_pinned_fingerprint() {
+        let path = std::env::temp_dir()
+            .join(uuid::Uuid::new_v4().to_string())
+            .join("identity.json");
+        let identity = SigningIdentity::load_or_generate(&path, &[], None).unwrap();
+        let pinned = identity.fingerprint(SignatureAlgorithm::MlDsa65).unwrap();
+        assert!(identity
+            .public_key(SignatureAlgorithm::Dilithium5)
+            .is_none());
+
+        // Offering a level 5 suite later adds a key without replacing the others.
+        let upgraded =
+            SigningIdentity::load_or_generate(&path, &[SignatureAlgorithm::Dilithium5], None)
+                .unwrap();
+        let level5_pin = upgraded
+            .fingerprint(SignatureAlgorithm::Dilithium5)
+            .unwrap();
+
+        // A restarted server presents the same keys under fresh ephemeral ones.
+        let reloaded = Arc::new(SigningIdentity::load_or_generate(&path, &[], None).unwrap());
+        assert_eq!(
+            reloaded.fingerprint(SignatureAlgorithm::Dilithium5),
+            Some(level5_pin)
+        );
+        let first = KeyExchange::with_identity(reloaded.clone()).server_hello();
+        let second = KeyExchange::with_identity(reloaded).server_hello();
+        assert_ne!(first.kyber_public_key, second.kyber_public_key);
+        first
+            .check_pin(SignatureAlgorithm::MlDsa65, &pinned)
+            .unwrap();
+        second
+            .check_pin(SignatureAlgorithm::MlDsa65, &pinned)
+            .unwrap();
+
+        let impostor = KeyExchange::new().server_hello();
+        assert!(matches!(
+            impostor.check_pin(SignatureAlgorithm::MlDsa65, &pinned),
+            Err(CryptoError::PinMismatchError(_))
+        ));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn hello_certificate_vouches_only_for_its_own_keys() {
+        let ca = crate::certs::CertificateAuthority::generate(SignatureAlgorithm::Dilithium5);
+        let identity = Arc::new(SigningIdentity::generate());
+        let certificate = ca
+            .issue(
+                1,
+                "exit-1.vpn.example",
+                &identity,
+                &[SignatureAlgorithm::MlDsa65],
+                0,
+                u64::MAX,
+            )
+            .unwrap();
+        let hello = KeyExchange::with_identity(identity)
+            .with_certificate(Some(Arc::new(certificate.clone())))
+            .server_hello();
+        // The certificate survives the trip to the client.
+        let hello: ServerHello =
+            serde_json::from_str(&serde_json::to_string(&hello).unwrap()).unwrap();
+        hello
+            .check_certificate(
+                &ca.public(),
+                "exit-1.vpn.example",
+                SignatureAlgorithm::MlDsa65,
+                1,
+            )
+            .unwrap();
+        assert!(hello
+            .check_certificate(
+                &ca.public(),
+                "exit-1.vpn.example",
+                SignatureAlgorithm::Dilithium2,
+                1,
+            )
+            .is_err());
+
+        // A stolen certificate does not vouch for an impostor's keys.
+        let impostor = KeyExchange::new()
+            .with_certificate(Some(Arc::new(certificate)))
+            .server_hello();
+        assert!(matches!(
+            impostor.check_certificate(
+                &ca.public(),
+                "exit-1.vpn.example",
+                SignatureAlgorithm::MlDsa65,
+                1,
+            ),
+            Err(CryptoError::CertificateError("key not certified"))
+        ));
+        assert!(KeyExchange::new()
+            .server_hello()
+            .check_certificate(
+                &ca.public(),
+                "exit-1.vpn.example",
+                SignatureAlgorithm::MlDsa65,
+                1
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn rotation_overlap_serves_clients_pinned_to_either_key() {
+        let current = Arc::new(SigningIdentity::generate());
+        let next = Arc::new(SigningIdentity::generate());
+        let endorsed = NextIdentity::endorse(&current, next.clone()).unwrap();
+        let server = KeyExchange::with_identity(current.clone())
+            .with_next_identity(Some(Arc::new(endorsed)))
+            .with_suites(vec![AlgorithmSuite::FIPS]);
+        let client = KeyExchange::new();
+        let hello: ServerHello =
+            serde_json::from_str(&serde_json::to_string(&server.server_hello()).unwrap()).unwrap();
+
+        let next_pin = next.fingerprint(SignatureAlgorithm::MlDsa65).unwrap();
+        assert_eq!(
+            hello.next_fingerprint(SignatureAlgorithm::MlDsa65),
+            Some(next_pin.clone())
+        );
+        for pin in [
+            current.fingerprint(SignatureAlgorithm::MlDsa65).unwrap(),
+            next_pin,
+        ] {
+            hello.check_pin(SignatureAlgorithm::MlDsa65, &pin).unwrap();
+        }
+        assert!(hello
+            .check_pin(
+                SignatureAlgorithm::MlDsa65,
+                &SigningIdentity::generate()
+                    .fingerprint(SignatureAlgorithm::MlDsa65)
+                    .unwrap()
+            )
+            .is_err());
+
+        let request = client
+            .handshake_request(
+                &hello,
+                AlgorithmSuite::FIPS,
+                KeyExchangeMode::Hybrid,
+                AeadAlgorithm::Aes256Gcm,
+            )
+            .unwrap();
+        let (response, mut server_session) = server.process_client_key(&request, None).unwrap();
+        let mut client_session = client
+            .process_server_response(&hello, &request, &response, None)
+            .unwrap();
+        let frame = client_session.encrypt(b"overlap").unwrap();
+        assert_eq!(server_session.decrypt(&frame).unwrap(), b"overlap");
+
+        // Old clients ignore the next key, so the session keys must not
+        // depend on it.
+        let mut legacy_hello = hello.clone();
+        legacy_hello.next_signing_keys.clear();
+        assert!(client
+            .process_server_response(&legacy_hello, &request, &response, None)
+            .is_ok());
+
+        // A next key the current one did not endorse, or a missing next
+        // signature, is refused.
+        let mut swapped = hello.clone();
+        swapped
+            .next_signing_keys
+            .get_mut(&SignatureAlgorithm::MlDsa65)
+            .unwrap()
+            .public_key = SigningIdentity::generate()
+            .public_key(SignatureAlgorithm::MlDsa65)
+            .unwrap()
+            .to_vec();
+        assert!(client
+            .process_server_response(&swapped, &request, &response, None)
+            .is_err());
+        let (mut response, _) = server.process_client_key(&request, None).unwrap();
+        response.next_signature = None;
+        assert!(client
+            .process_server_response(&hello, &request, &response, None)
+            .is_err());
+    }
+
+    #[test]
+    fn session_keys_are_wiped_on_drop() {
+        let mut keys = std::mem::ManuallyDrop::new(SessionKeys::derive(&[7; 32], &[9; 32]));
+        assert_ne!(keys.client_to_server, [0; 32]);
+        assert_ne!(keys.server_to_client, [0; 32]);
+        assert_ne!(keys.exporter_secret, [0; 32]);
+
+        // SAFETY: `keys` is not used as a `SessionKeys` again; the fields are
+        // plain byte arrays that stay in place after `Drop` has run.
+        unsafe { std::mem::ManuallyDrop::drop(&mut keys) };
+        assert_eq!(keys.client_to_server, [0; 32]);
+        assert_eq!(keys.server_to_client, [0; 32]);
+        assert_eq!(keys.exporter_secret, [0; 32]);
+    }
+
+    #[test]
+    fn pqclean_secrets_are_wiped() {
+        use pqcrypto_kyber::kyber768::{encapsulate, keypair as kyber_keypair};
+        let (pk, mut sk) = kyber_keypair();
+        let (mut shared_secret, _) = encapsulate(&pk);
+        assert!(sk.as_bytes().iter().any(|&b| b != 0));
+        assert!(shared_secret.as_bytes().iter().any(|&b| b != 0));
+
+        wipe_pqclean(&mut sk);
+        wipe_pqclean(&mut shared_secret);
+        assert!(sk.as_bytes().iter().all(|&b| b == 0));
+        assert!(shared_secret.as_bytes().iter().all(|&b| b == 0));
+
+        use pqcrypto_traits::sign::SecretKey as _;
+        let (_, mut sk) = pqcrypto_dilithium::dilithium2::keypair();
+        wipe_pqclean(&mut sk);
+        assert!(sk.as_bytes().iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn debug_output_omits_secrets() {
+        let keys = SessionKeys::derive(&[7; 32], &[9; 32]);
+        let printed = format!("{:?}", keys);
+        assert!(!printed.contains(&format!("{:?}", keys.client_to_server)));
+        assert!(!printed.contains(&format!("{:?}", keys.exporter_secret)));
+
+        let session = CryptoSession::new(&keys, Role::Client, AeadAlgorithm::Aes256Gcm);
+        let printed = format!("{:?}", session);
+        assert!(!printed.contains(&format!("{:?}", keys.client_to_server)));
+        assert!(!printed.contains(&format!("{:?}", keys.server_to_client)));
+        assert!(!printed.contains(&format!("{:?}", keys.exporter_secret)));
+
+        let key_exchange = KeyExchange::new();
+        let printed = format!("{:?}", key_exchange);
+        for algorithm in [KemAlgorithm::Kyber768, KemAlgorithm::X25519] {
+            let secret_key = &key_exchange.kem_keypair(algorithm).secret_key;
+            assert!(!printed.contains(&format!("{:?}", secret_key.as_slice())));
+        }
+    }
+
+    #[test]
+    fn psk_is_mixed_in_and_mismatches_fail_cleanly() {
+        let server = KeyExchange::new();
+        let client = KeyExchange::new();
+        let hello = server.server_hello();
+        let psk = PresharedKey::from_hex(&"11".repeat(32)).unwrap();
+        let other_psk = PresharedKey::new([0x22; 32]);
+        let request = || {
+            client
+                .handshake_request(
+                    &hello,
+                    AlgorithmSuite::FIPS,
+                    KeyExchangeMode::Hybrid,
+                    AeadAlgorithm::Aes256Gcm,
+                )
+                .unwrap()
+        };
+
+        let mut bound = request();
+        client.bind_psk(&hello, &mut bound, &psk);
+        let (response, mut server_session) = server.process_client_key(&bound, Some(&psk)).unwrap();
+        let mut client_session = client
+            .process_server_response(&hello, &bound, &response, Some(&psk))
+            .unwrap();
+        let frame = client_session.encrypt(b"with psk").unwrap();
+        assert_eq!(server_session.decrypt(&frame).unwrap(), b"with psk");
+
+        // A client that drops or swaps the PSK is refused or derives different keys.
+        let (response, mut server_session) = server.process_client_key(&bound, Some(&psk)).unwrap();
+        assert!(client
+            .process_server_response(&hello, &bound, &response, None)
+            .is_err());
+        let mut wrong_session = client
+            .process_server_response(&hello, &bound, &response, Some(&other_psk))
+            .unwrap();
+        assert!(server_session
+            .decrypt(&wrong_session.encrypt(b"wrong psk").unwrap())
+            .is_err());
+
+        assert!(matches!(
+            server.process_client_key(&request(), Some(&psk)),
+            Err(CryptoError::PskError(_))
+        ));
+        assert!(matches!(
+            server.process_client_key(&bound, None),
+            Err(CryptoError::PskError(_))
+        ));
+        let mut wrong = request();
+        client.bind_psk(&hello, &mut wrong, &other_psk);
+        assert!(matches!(
+            server.process_client_key(&wrong, Some(&psk)),
+            Err(CryptoError::PskError("PSK mismatch"))
+        ));
+    }
+
+    #[test]
+    fn request_replayed_to_another_connection_is_rejected() {
+        let identity = Arc::new(SigningIdentity::generate());
+        let first_connection = KeyExchange::with_identity(identity.clone());
+        let client = KeyExchange::new();
+
+        let request = client
+            .handshake_request(
+                &first_connection.server_hello(),
+                AlgorithmSuite::FIPS,
+                KeyExchangeMode::PqOnly,
+                AeadAlgorithm::Aes256Gcm,
+            )
+            .unwrap();
+        first_connection.verify_client_request(&request).unwrap();
+
+        // Same server identity, fresh ephemeral keys: the signature was
+        // over the first connection's hello and must not carry over.
+        let second_connection = KeyExchange::with_identity(identity);
+        assert!(matches!(
+            second_connection.verify_client_request(&request),
+            Err(CryptoError::SignatureError)
+        ));
+    }
+
+    #[test]
+    fn dual_signatures_must_both_verify() {
+        let server = KeyExchange::new();
+        let client = KeyExchange::new();
+        let hello = server.server_hello();
+        let request = || {
+            let mut request = client
+                .handshake_request(
+                    &hello,
+                    AlgorithmSuite::FIPS,
+                    KeyExchangeMode::Hybrid,
+                    AeadAlgorithm::Aes256Gcm,
+                )
+                .unwrap();
+            client.sign_dual(&hello, &mut request).unwrap();
+            request
+        };
+
+        let dual = request();
+        assert_eq!(dual.auth, AuthMode::Dual);
+        server.verify_client_request(&dual).unwrap();
+        let (response, mut server_session) = server.process_client_key(&dual, None).unwrap();
+        assert!(response.ed25519_signature.is_some());
+        let mut client_session = client
+            .process_server_response(&hello, &dual, &response, None)
+            .unwrap();
+        let frame = client_session.encrypt(b"dual").unwrap();
+        assert_eq!(server_session.decrypt(&frame).unwrap(), b"dual");
+
+        // Breaking either client signature fails verification.
+        let mut tampered = request();
+        tampered.client_ed25519_signature.as_mut().unwrap()[0] ^= 1;
+        assert!(server.verify_client_request(&tampered).is_err());
+        let mut tampered = request();
+        tampered.signature[0] ^= 1;
+        assert!(server.verify_client_request(&tampered).is_err());
+
+        // So does breaking or dropping the server's Ed25519 signature.
+        let (mut response, _) = server.process_client_key(&dual, None).unwrap();
+        response.ed25519_signature.as_mut().unwrap()[0] ^= 1;
+        assert!(client
+            .process_server_response(&hello, &dual, &response, None)
+            .is_err());
+        response.ed25519_signature = None;
+        assert!(client
+            .process_server_response(&hello, &dual, &response, None)
+            .is_err());
     }
 }
@@ -0,0 +1,66 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Tells one version of a file from the next, for stores that poll their
+/// files for changes. Two writes in quick succession can share a
+/// modification time, and a rename keeps the renamed file's, but a file
+/// renamed into place still brings its own inode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    modified: SystemTime,
+    #[cfg(unix)]
+    inode: u64,
+}
+
+impl FileStamp {
+    pub(crate) fn of(metadata: &fs::Metadata) -> io::Result<Self> {
+        Ok(Self {
+            modified: metadata.modified()?,
+            #[cfg(unix)]
+            inode: std::os::unix::fs::MetadataExt::ino(metadata),
+        })
+    }
+
+    /// Stamp of the file at `path`, `None` if it does not exist.
+    pub(crate) fn read(path: &Path) -> io::Result<Option<Self>> {
+        match fs::metadata(path) {
+            Ok(metadata) => Self::of(&metadata).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn a_file_renamed_into_place_in_the_same_tick_is_a_new_version() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, "one").unwrap();
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        let before = FileStamp::read(&path).unwrap();
+
+        let replacement = dir.join("file.tmp");
+        fs::write(&replacement, "two").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&replacement)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        fs::rename(&replacement, &path).unwrap();
+
+        let after = FileStamp::read(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+        assert_ne!(before, after);
+        assert_eq!(FileStamp::read(&replacement).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,338 @@
+use crate::crypto::{ml_dsa_public_key, signer_for, CryptoError, SignatureAlgorithm};
+use crate::keyfile::{KeyAlgorithm, KeyPair};
+use crate::keystore::{self, KeyEncryptionKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Algorithms every identity holds, because the server hello always carries
+/// their public keys. Ed25519 backs the classical half of dual-signature
+/// authentication.
+const REQUIRED_ALGORITHMS: [SignatureAlgorithm; 3] = [
+    SignatureAlgorithm::Dilithium2,
+    SignatureAlgorithm::MlDsa65,
+    SignatureAlgorithm::Ed25519,
+];
+
+/// Algorithms stored in the legacy top-level fields of [`IdentityFile`].
+const LEGACY_ALGORITHMS: [SignatureAlgorithm; 2] =
+    [SignatureAlgorithm::Dilithium2, SignatureAlgorithm::MlDsa65];
+
+/// Long-term signing keys, one per signature algorithm the server offers.
+///
+/// The server loads these once at startup and signs every handshake
+/// transcript with them, so clients can pin the fingerprint of the key they
+/// negotiate. Clients and tests use [`SigningIdentity::generate`] for
+/// throwaway identities. Secret halves are wiped on drop.
+pub struct SigningIdentity {
+    keys: BTreeMap<SignatureAlgorithm, IdentityKey>,
+}
+
+/// One keypair in the format its [`Signer`](crate::crypto::Signer) takes.
+/// For ML-DSA-65 the secret key is the 32-byte seed.
+struct IdentityKey {
+    public_key: Vec<u8>,
+    secret_key: Zeroizing<Vec<u8>>,
+}
+
+/// On-disk form of a [`SigningIdentity`]. ML-DSA keys are stored as their
+/// 32-byte seed and expanded on load. Keys beyond the original two live in
+/// `keys`, so files written before it existed still load.
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct IdentityFile {
+    dilithium2_public_key: Vec<u8>,
+    dilithium2_secret_key: Vec<u8>,
+    ml_dsa_65_seed: Vec<u8>,
+    #[serde(default)]
+    keys: Vec<StoredKey>,
+}
+
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct StoredKey {
+    #[zeroize(skip)]
+    algorithm: SignatureAlgorithm,
+    public_key: Vec<u8>,
+    secret_key: Vec<u8>,
+}
+
+impl fmt::Debug for SigningIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("SigningIdentity");
+        for (algorithm, key) in &self.keys {
+            debug.field(algorithm.as_str(), &fingerprint(&key.public_key));
+        }
+        debug.finish_non_exhaustive()
+    }
+}
+
+impl SigningIdentity {
+    /// A throwaway identity with a key for every signature algorithm.
+    pub fn generate() -> Self {
+        Self::generate_for(&SignatureAlgorithm::ALL)
+    }
+
+    /// A fresh identity with keys for `algorithms`, plus Dilithium2,
+    /// ML-DSA-65 and Ed25519.
+    pub fn generate_for(algorithms: &[SignatureAlgorithm]) -> Self {
+        let mut identity = Self {
+            keys: BTreeMap::new(),
+        };
+        identity.add_missing(algorithms);
+        identity
+    }
+
+    /// Generates keys for whichever of `algorithms` (and the required ones)
+    /// this identity lacks. Returns whether any were added.
+    fn add_missing(&mut self, algorithms: &[SignatureAlgorithm]) -> bool {
+        let mut added = false;
+        for &algorithm in REQUIRED_ALGORITHMS.iter().chain(algorithms) {
+            self.keys.entry(algorithm).or_insert_with(|| {
+                added = true;
+                let (public_key, secret_key) = signer_for(algorithm).keypair();
+                IdentityKey {
+                    public_key,
+                    secret_key,
+                }
+            });
+        }
+        added
+    }
+
+    /// Reads the identity at `path`, or generates one and writes it there if
+    /// the file does not exist yet. Keys for any of `algorithms` the file
+    /// lacks are generated and written back, leaving existing keys as they
+    /// were. With a `kek`, the file is kept encrypted. A plaintext one is
+    /// refused unless the KEK allows it (`VPN_ALLOW_PLAINTEXT_KEYS=1`), and
+    /// is then encrypted in place.
+    pub fn load_or_generate(
+        path: &Path,
+        algorithms: &[SignatureAlgorithm],
+        kek: Option<&KeyEncryptionKey>,
+    ) -> io::Result<Self> {
+        match fs::read(path) {
+            Ok(contents) => {
+                let contents = Zeroizing::new(contents);
+                let mut identity = Self::decode(&keystore::open(&contents, kek)?)?;
+                if identity.add_missing(algorithms) {
+                    identity.replace(path, kek)?;
+                    log::info!(
+                        "Added signing keys to server identity at {}",
+                        path.display()
+                    );
+                } else if kek.is_some() && !keystore::is_sealed(&contents) {
+                    identity.replace(path, kek)?;
+                    log::info!("Encrypted server identity at {}", path.display());
+                }
+                Ok(identity)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate_for(algorithms);
+                identity.save(path, kek)?;
+                log::info!("Generated new server identity at {}", path.display());
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// An identity made of the signature keys in `key_pairs`, e.g. PEM files
+    /// from `keygen`. Unlike the other constructors this adds nothing, so a
+    /// client can hold just the key it signs with.
+    pub fn from_key_pairs(key_pairs: Vec<KeyPair>) -> io::Result<Self> {
+        let mut identity = Self {
+            keys: BTreeMap::new(),
+        };
+        for key_pair in key_pairs {
+            let KeyAlgorithm::Signature(algorithm) = key_pair.algorithm else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a signing key", key_pair.algorithm.as_str()),
+                ));
+            };
+            key_pair.check()?;
+            let key = IdentityKey {
+                public_key: key_pair.public_key,
+                secret_key: key_pair.secret_key,
+            };
+            if identity.keys.insert(algorithm, key).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("more than one {} key", algorithm.as_str()),
+                ));
+            }
+        }
+        Ok(identity)
+    }
+
+    /// Loads the server identity from key files, which must between them
+    /// hold a key for Dilithium2, ML-DSA-65, Ed25519 and each of
+    /// `algorithms`.
+    pub fn load_key_files(
+        paths: &[PathBuf],
+        algorithms: &[SignatureAlgorithm],
+        kek: Option<&KeyEncryptionKey>,
+    ) -> io::Result<Self> {
+        let key_pairs = paths
+            .iter()
+            .map(|path| KeyPair::load(path, kek))
+            .collect::<io::Result<Vec<_>>>()?;
+        let identity = Self::from_key_pairs(key_pairs)?;
+        if let Some(missing) = REQUIRED_ALGORITHMS
+            .iter()
+            .chain(algorithms)
+            .find(|algorithm| !identity.keys.contains_key(algorithm))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no {} key among the identity key files", missing.as_str()),
+            ));
+        }
+        Ok(identity)
+    }
+
+    /// Reads the identity at `path` without generating anything.
+    pub fn load(path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<Self> {
+        Self::decode(&keystore::read_private(path, kek)?)
+    }
+
+    fn decode(contents: &[u8]) -> io::Result<Self> {
+        let file: IdentityFile = serde_json::from_slice(contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let ml_dsa_public_key = ml_dsa_public_key(&file.ml_dsa_65_seed)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut identity = Self {
+            keys: BTreeMap::new(),
+        };
+        let stored = [
+            (
+                SignatureAlgorithm::Dilithium2,
+                &file.dilithium2_public_key,
+                &file.dilithium2_secret_key,
+            ),
+            (
+                SignatureAlgorithm::MlDsa65,
+                &ml_dsa_public_key,
+                &file.ml_dsa_65_seed,
+            ),
+        ]
+        .into_iter()
+        .chain(
+            file.keys
+                .iter()
+                .map(|key| (key.algorithm, &key.public_key, &key.secret_key)),
+        );
+        for (algorithm, public_key, secret_key) in stored {
+            // A signature that verifies proves the two halves belong together.
+            let signer = signer_for(algorithm);
+            let malformed = |_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed {} key", algorithm.as_str()),
+                )
+            };
+            let signature = signer
+                .sign(secret_key, b"pqc-vpn identity check")
+                .map_err(malformed)?;
+            signer
+                .verify(b"pqc-vpn identity check", &signature, public_key)
+                .map_err(malformed)?;
+            identity.keys.insert(
+                algorithm,
+                IdentityKey {
+                    public_key: public_key.clone(),
+                    secret_key: Zeroizing::new(secret_key.clone()),
+                },
+            );
+        }
+        Ok(identity)
+    }
+
+    fn encode(&self) -> io::Result<Zeroizing<Vec<u8>>> {
+        let secret_key = |algorithm| {
+            self.keys
+                .get(&algorithm)
+                .map(|key| key.secret_key.to_vec())
+                .unwrap_or_default()
+        };
+        let file = IdentityFile {
+            dilithium2_public_key: self
+                .public_key(SignatureAlgorithm::Dilithium2)
+                .unwrap_or_default()
+                .to_vec(),
+            dilithium2_secret_key: secret_key(SignatureAlgorithm::Dilithium2),
+            ml_dsa_65_seed: secret_key(SignatureAlgorithm::MlDsa65),
+            keys: self
+                .keys
+                .iter()
+                .filter(|(algorithm, _)| !LEGACY_ALGORITHMS.contains(algorithm))
+                .map(|(&algorithm, key)| StoredKey {
+                    algorithm,
+                    public_key: key.public_key.clone(),
+                    secret_key: key.secret_key.to_vec(),
+                })
+                .collect(),
+        };
+        Ok(Zeroizing::new(serde_json::to_vec(&file)?))
+    }
+
+    /// Writes the identity to `path`, readable by the owner only and
+    /// encrypted under `kek` if one is given.
+    pub fn save(&self, path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        keystore::write_private(path, &self.encode()?, kek)
+    }
+
+    /// Overwrites the identity at `path` without ever leaving a truncated
+    /// file behind.
+    fn replace(&self, path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<()> {
+        keystore::replace_private(path, &self.encode()?, kek)
+    }
+
+    /// A copy of the keypair for `algorithm`, for exporting to a key file.
+    pub(crate) fn key_pair(&self, algorithm: SignatureAlgorithm) -> Option<KeyPair> {
+        self.keys.get(&algorithm).map(|key| KeyPair {
+            algorithm: KeyAlgorithm::Signature(algorithm),
+            public_key: key.public_key.clone(),
+            secret_key: key.secret_key.clone(),
+        })
+    }
+
+    /// Algorithms this identity holds keys for.
+    pub fn algorithms(&self) -> impl Iterator<Item = SignatureAlgorithm> + '_ {
+        self.keys.keys().copied()
+    }
+
+    /// The public key for `algorithm`, if this identity holds one.
+    pub fn public_key(&self, algorithm: SignatureAlgorithm) -> Option<&[u8]> {
+        self.keys
+            .get(&algorithm)
+            .map(|key| key.public_key.as_slice())
+    }
+
+    /// Fingerprint of the public key clients will see for `algorithm`.
+    pub fn fingerprint(&self, algorithm: SignatureAlgorithm) -> Option<String> {
+        self.public_key(algorithm).map(fingerprint)
+    }
+
+    pub fn sign(&self, algorithm: SignatureAlgorithm, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let key = self
+            .keys
+            .get(&algorithm)
+            .ok_or_else(|| CryptoError::UnsupportedAlgorithm(algorithm.as_str().to_string()))?;
+        signer_for(algorithm).sign(&key.secret_key, data)
+    }
+}
+
+/// `sha256:` followed by the lowercase hex SHA-256 of a public key. This is
+/// the string operators hand to clients for pinning.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(public_key)))
+}
@@ -0,0 +1,516 @@
+use crate::crypto::{kem_for, ml_dsa_public_key, signer_for, KemAlgorithm, SignatureAlgorithm};
+use crate::identity::fingerprint;
+use crate::keystore::{self, KeyEncryptionKey};
+use pkcs8::der::asn1::{BitStringRef, OctetStringRef};
+use pkcs8::der::pem::{self, LineEnding};
+use pkcs8::der::{Decode, Encode};
+use pkcs8::spki::{AlgorithmIdentifierRef, SubjectPublicKeyInfoRef};
+use pkcs8::{ObjectIdentifier, PrivateKeyInfo};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+const PRIVATE_KEY_LABEL: &str = "PRIVATE KEY";
+const PUBLIC_KEY_LABEL: &str = "PUBLIC KEY";
+
+/// Tag and length of the `seed [0]` choice of an RFC 9881 ML-DSA private
+/// key holding a 32-byte seed.
+const ML_DSA_SEED_PREFIX: [u8; 2] = [0x80, 0x20];
+
+/// Any algorithm a key file can hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyAlgorithm {
+    Kem(KemAlgorithm),
+    Signature(SignatureAlgorithm),
+}
+
+/// On-disk encoding of a key file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyFormat {
+    Pem,
+    Der,
+}
+
+impl KeyAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyAlgorithm::Kem(algorithm) => algorithm.as_str(),
+            KeyAlgorithm::Signature(algorithm) => algorithm.as_str(),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        KemAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.as_str() == name)
+            .map(KeyAlgorithm::Kem)
+            .or_else(|| SignatureAlgorithm::from_name(name).map(KeyAlgorithm::Signature))
+    }
+
+    /// Object identifier written into PKCS#8 and SPKI structures. NIST and
+    /// RFC 8410 arcs where they exist; the Open Quantum Safe arcs for the
+    /// round-3 Kyber, Dilithium, Falcon and SPHINCS+ schemes.
+    pub fn oid(&self) -> ObjectIdentifier {
+        let oid = match self {
+            KeyAlgorithm::Kem(KemAlgorithm::Kyber512) => "1.3.6.1.4.1.22554.5.6.1",
+            KeyAlgorithm::Kem(KemAlgorithm::Kyber768) => "1.3.6.1.4.1.22554.5.6.2",
+            KeyAlgorithm::Kem(KemAlgorithm::Kyber1024) => "1.3.6.1.4.1.22554.5.6.3",
+            KeyAlgorithm::Kem(KemAlgorithm::MlKem768) => "2.16.840.1.101.3.4.4.2",
+            KeyAlgorithm::Kem(KemAlgorithm::X25519) => "1.3.101.110",
+            KeyAlgorithm::Signature(SignatureAlgorithm::Dilithium2) => "1.3.6.1.4.1.2.267.7.4.4",
+            KeyAlgorithm::Signature(SignatureAlgorithm::Dilithium3) => "1.3.6.1.4.1.2.267.7.6.5",
+            KeyAlgorithm::Signature(SignatureAlgorithm::Dilithium5) => "1.3.6.1.4.1.2.267.7.8.7",
+            KeyAlgorithm::Signature(SignatureAlgorithm::MlDsa65) => "2.16.840.1.101.3.4.3.18",
+            KeyAlgorithm::Signature(SignatureAlgorithm::Falcon512) => "1.3.9999.3.11",
+            KeyAlgorithm::Signature(SignatureAlgorithm::Falcon1024) => "1.3.9999.3.14",
+            KeyAlgorithm::Signature(SignatureAlgorithm::SphincsSha2128s) => "1.3.9999.6.4.16",
+            KeyAlgorithm::Signature(SignatureAlgorithm::Ed25519) => "1.3.101.112",
+        };
+        ObjectIdentifier::new_unwrap(oid)
+    }
+
+    pub fn from_oid(oid: ObjectIdentifier) -> Option<Self> {
+        KemAlgorithm::ALL
+            .into_iter()
+            .map(KeyAlgorithm::Kem)
+            .chain(
+                SignatureAlgorithm::ALL
+                    .into_iter()
+                    .map(KeyAlgorithm::Signature),
+            )
+            .find(|algorithm| algorithm.oid() == oid)
+    }
+
+    fn keypair(&self) -> (Vec<u8>, Zeroizing<Vec<u8>>) {
+        match self {
+            KeyAlgorithm::Kem(algorithm) => kem_for(*algorithm).keypair(),
+            KeyAlgorithm::Signature(algorithm) => signer_for(*algorithm).keypair(),
+        }
+    }
+}
+
+/// A private key together with its public half, stored as PKCS#8 v2
+/// (`OneAsymmetricKey`). The secret key is in the format the algorithm's
+/// [`Kem`](crate::crypto::Kem) or [`Signer`](crate::crypto::Signer) takes.
+pub struct KeyPair {
+    pub algorithm: KeyAlgorithm,
+    pub public_key: Vec<u8>,
+    pub secret_key: Zeroizing<Vec<u8>>,
+}
+
+/// A public key stored as an X.509 `SubjectPublicKeyInfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    pub algorithm: KeyAlgorithm,
+    pub public_key: Vec<u8>,
+}
+
+/// Whatever a key file turned out to hold.
+pub enum KeyFile {
+    Private(KeyPair),
+    Public(PublicKey),
+}
+
+impl fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyPair")
+            .field("algorithm", &self.algorithm)
+            .field("fingerprint", &fingerprint(&self.public_key))
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyPair {
+    pub fn generate(algorithm: KeyAlgorithm) -> Self {
+        let (public_key, secret_key) = algorithm.keypair();
+        Self {
+            algorithm,
+            public_key,
+            secret_key,
+        }
+    }
+
+    pub fn public(&self) -> PublicKey {
+        PublicKey {
+            algorithm: self.algorithm,
+            public_key: self.public_key.clone(),
+        }
+    }
+
+    /// Checks that the two halves belong together by signing and verifying,
+    /// or by encapsulating to the public key and decapsulating.
+    pub fn check(&self) -> io::Result<()> {
+        let matches = match self.algorithm {
+            KeyAlgorithm::Signature(algorithm) => {
+                let signer = signer_for(algorithm);
+                signer
+                    .sign(&self.secret_key, b"pqc-vpn key check")
+                    .and_then(|signature| {
+                        signer.verify(b"pqc-vpn key check", &signature, &self.public_key)
+                    })
+                    .is_ok()
+            }
+            KeyAlgorithm::Kem(algorithm) => {
+                let kem = kem_for(algorithm);
+                kem.encapsulate(&self.public_key)
+                    .and_then(|(shared_secret, ciphertext)| {
+                        let decapsulated = kem.decapsulate(&self.secret_key, &ciphertext)?;
+                        Ok(bool::from(shared_secret.ct_eq(&decapsulated)))
+                    })
+                    .unwrap_or(false)
+            }
+        };
+        if !matches {
+            return Err(invalid(format!(
+                "{} private key does not match its public key",
+                self.algorithm.as_str()
+            )));
+        }
+        Ok(())
+    }
+
+    /// PKCS#8 DER.
+    pub fn to_der(&self) -> io::Result<Zeroizing<Vec<u8>>> {
+        let private_key = encode_secret(self.algorithm, &self.secret_key)?;
+        let info = PrivateKeyInfo {
+            algorithm: algorithm_identifier(self.algorithm),
+            private_key: &private_key,
+            public_key: Some(&self.public_key),
+        };
+        Ok(Zeroizing::new(info.to_der().map_err(invalid)?))
+    }
+
+    pub fn encode(&self, format: KeyFormat) -> io::Result<Zeroizing<Vec<u8>>> {
+        let der = self.to_der()?;
+        match format {
+            KeyFormat::Der => Ok(der),
+            KeyFormat::Pem => Ok(Zeroizing::new(
+                pem::encode_string(PRIVATE_KEY_LABEL, LineEnding::LF, &der)
+                    .map_err(invalid)?
+                    .into_bytes(),
+            )),
+        }
+    }
+
+    fn from_der(der: &[u8]) -> io::Result<Self> {
+        let info = PrivateKeyInfo::try_from(der).map_err(invalid)?;
+        let algorithm = algorithm_from_identifier(info.algorithm)?;
+        let secret_key = decode_secret(algorithm, info.private_key)?;
+        // PKCS#8 v1 files, such as OpenSSL writes, leave the public key out.
+        let public_key = match info.public_key {
+            Some(public_key) => public_key.to_vec(),
+            None => derive_public_key(algorithm, &secret_key)?,
+        };
+        let key_pair = Self {
+            algorithm,
+            public_key,
+            secret_key,
+        };
+        key_pair.check()?;
+        Ok(key_pair)
+    }
+
+    /// Reads a PEM or DER private key file, unsealing it with `kek` if it
+    /// is encrypted, and checks that its halves match.
+    pub fn load(path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<Self> {
+        match KeyFile::load(path, kek)? {
+            KeyFile::Private(key_pair) => Ok(key_pair),
+            KeyFile::Public(_) => Err(invalid(format!(
+                "{} holds a public key, not a private one",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Writes the key to `path`, readable by the owner only and encrypted
+    /// under `kek` if one is given.
+    pub fn save(
+        &self,
+        path: &Path,
+        format: KeyFormat,
+        kek: Option<&KeyEncryptionKey>,
+    ) -> io::Result<()> {
+        keystore::write_private(path, &self.encode(format)?, kek)
+    }
+}
+
+impl PublicKey {
+    /// SPKI DER.
+    pub fn to_der(&self) -> io::Result<Vec<u8>> {
+        let info = SubjectPublicKeyInfoRef {
+            algorithm: algorithm_identifier(self.algorithm),
+            subject_public_key: BitStringRef::from_bytes(&self.public_key).map_err(invalid)?,
+        };
+        info.to_der().map_err(invalid)
+    }
+
+    pub fn encode(&self, format: KeyFormat) -> io::Result<Vec<u8>> {
+        let der = self.to_der()?;
+        match format {
+            KeyFormat::Der => Ok(der),
+            KeyFormat::Pem => Ok(pem::encode_string(PUBLIC_KEY_LABEL, LineEnding::LF, &der)
+                .map_err(invalid)?
+                .into_bytes()),
+        }
+    }
+
+    fn from_der(der: &[u8]) -> io::Result<Self> {
+        let info = SubjectPublicKeyInfoRef::try_from(der).map_err(invalid)?;
+        Ok(Self {
+            algorithm: algorithm_from_identifier(info.algorithm)?,
+            public_key: info
+                .subject_public_key
+                .as_bytes()
+                .ok_or_else(|| invalid("public key has unused bits"))?
+                .to_vec(),
+        })
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match KeyFile::load(path, None)? {
+            KeyFile::Public(public_key) => Ok(public_key),
+            KeyFile::Private(key_pair) => Ok(key_pair.public()),
+        }
+    }
+
+    pub fn save(&self, path: &Path, format: KeyFormat) -> io::Result<()> {
+        fs::write(path, self.encode(format)?)
+    }
+
+    /// The same fingerprint clients pin and `/identity` reports.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+}
+
+impl KeyFile {
+    /// Parses PEM or DER, private or public, whichever `contents` holds.
+    pub fn decode(contents: &[u8]) -> io::Result<Self> {
+        if contents.starts_with(b"-----BEGIN") {
+            let (label, der) = pem::decode_vec(contents).map_err(invalid)?;
+            let der = Zeroizing::new(der);
+            return match label {
+                PRIVATE_KEY_LABEL => KeyPair::from_der(&der).map(KeyFile::Private),
+                PUBLIC_KEY_LABEL => PublicKey::from_der(&der).map(KeyFile::Public),
+                _ => Err(invalid(format!("unexpected PEM label {}", label))),
+            };
+        }
+        match KeyPair::from_der(contents) {
+            Ok(key_pair) => Ok(KeyFile::Private(key_pair)),
+            Err(private_error) => PublicKey::from_der(contents)
+                .map(KeyFile::Public)
+                .map_err(|_| private_error),
+        }
+    }
+
+    /// Reads a key file, unsealing it with `kek` if it is encrypted.
+    pub fn load(path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<Self> {
+        let contents = keystore::read_private(path, kek)?;
+        Self::decode(&contents)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+}
+
+fn algorithm_identifier(algorithm: KeyAlgorithm) -> AlgorithmIdentifierRef<'static> {
+    AlgorithmIdentifierRef {
+        oid: algorithm.oid(),
+        parameters: None,
+    }
+}
+
+fn algorithm_from_identifier(identifier: AlgorithmIdentifierRef<'_>) -> io::Result<KeyAlgorithm> {
+    KeyAlgorithm::from_oid(identifier.oid)
+        .ok_or_else(|| invalid(format!("unsupported key algorithm {}", identifier.oid)))
+}
+
+/// The `privateKey` contents for `algorithm`: RFC 8410 wraps X25519 and
+/// Ed25519 keys in an OCTET STRING and RFC 9881 stores ML-DSA as its seed.
+/// ML-KEM uses the expanded decapsulation key as an OCTET STRING, and the
+/// round-3 schemes store their secret key as is.
+fn encode_secret(algorithm: KeyAlgorithm, secret_key: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+    let encoded = match algorithm {
+        KeyAlgorithm::Signature(SignatureAlgorithm::Ed25519)
+        | KeyAlgorithm::Kem(KemAlgorithm::X25519 | KemAlgorithm::MlKem768) => {
+            OctetStringRef::new(secret_key)
+                .and_then(|octets| octets.to_der())
+                .map_err(invalid)?
+        }
+        KeyAlgorithm::Signature(SignatureAlgorithm::MlDsa65) => {
+            [ML_DSA_SEED_PREFIX.as_slice(), secret_key].concat()
+        }
+        _ => secret_key.to_vec(),
+    };
+    Ok(Zeroizing::new(encoded))
+}
+
+/// Reverses [`encode_secret`]. Also takes the `both` form of RFC 9881 and
+/// FIPS 203 keys, a SEQUENCE of the seed and the expanded key, which is
+/// what OpenSSL writes by default.
+fn decode_secret(algorithm: KeyAlgorithm, private_key: &[u8]) -> io::Result<Zeroizing<Vec<u8>>> {
+    let both = || Vec::<OctetStringRef<'_>>::from_der(private_key).map_err(invalid);
+    let secret_key = match algorithm {
+        KeyAlgorithm::Signature(SignatureAlgorithm::MlDsa65) => {
+            match private_key.strip_prefix(&ML_DSA_SEED_PREFIX) {
+                Some(seed) => seed.to_vec(),
+                None => match both()?.as_slice() {
+                    [seed, _expanded] => seed.as_bytes().to_vec(),
+                    _ => return Err(invalid("ML-DSA-65 private key has no seed")),
+                },
+            }
+        }
+        KeyAlgorithm::Kem(KemAlgorithm::MlKem768) if private_key.first() == Some(&0x30) => {
+            match both()?.as_slice() {
+                [_seed, expanded] => expanded.as_bytes().to_vec(),
+                _ => return Err(invalid("malformed ML-KEM-768 private key")),
+            }
+        }
+        KeyAlgorithm::Signature(SignatureAlgorithm::Ed25519)
+        | KeyAlgorithm::Kem(KemAlgorithm::X25519 | KemAlgorithm::MlKem768) => {
+            OctetStringRef::from_der(private_key)
+                .map_err(invalid)?
+                .as_bytes()
+                .to_vec()
+        }
+        _ => private_key.to_vec(),
+    };
+    Ok(Zeroizing::new(secret_key))
+}
+
+/// Recomputes the public key for files that carry only the private one.
+/// Kyber and ML-KEM decapsulation keys embed the encapsulation key right
+/// after the 384·k-byte secret vector.
+fn derive_public_key(algorithm: KeyAlgorithm, secret_key: &[u8]) -> io::Result<Vec<u8>> {
+    let embedded = |k: usize| {
+        secret_key
+            .get(384 * k..768 * k + 32)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| invalid(format!("malformed {} private key", algorithm.as_str())))
+    };
+    let fixed = || -> io::Result<[u8; 32]> {
+        secret_key
+            .try_into()
+            .map_err(|_| invalid(format!("malformed {} private key", algorithm.as_str())))
+    };
+    match algorithm {
+        KeyAlgorithm::Kem(KemAlgorithm::Kyber512) => embedded(2),
+        KeyAlgorithm::Kem(KemAlgorithm::Kyber768 | KemAlgorithm::MlKem768) => embedded(3),
+        KeyAlgorithm::Kem(KemAlgorithm::Kyber1024) => embedded(4),
+        KeyAlgorithm::Kem(KemAlgorithm::X25519) => {
+            let secret = x25519_dalek::StaticSecret::from(fixed()?);
+            Ok(x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec())
+        }
+        KeyAlgorithm::Signature(SignatureAlgorithm::Ed25519) => {
+            let signing_key = ed25519_dalek::SigningKey::from_bytes(&fixed()?);
+            Ok(signing_key.verifying_key().as_bytes().to_vec())
+        }
+        KeyAlgorithm::Signature(SignatureAlgorithm::MlDsa65) => {
+            ml_dsa_public_key(secret_key).map_err(invalid)
+        }
+        KeyAlgorithm::Signature(_) => Err(invalid(format!(
+            "{} private key file has no public key",
+            algorithm.as_str()
+        ))),
+    }
+}
+
+fn invalid(e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_algorithm_round_trips_through_pem_and_der() {
+        let algorithms = KemAlgorithm::ALL.into_iter().map(KeyAlgorithm::Kem).chain(
+            SignatureAlgorithm::ALL
+                .into_iter()
+                .map(KeyAlgorithm::Signature),
+        );
+        for algorithm in algorithms {
+            let key_pair = KeyPair::generate(algorithm);
+            for format in [KeyFormat::Pem, KeyFormat::Der] {
+                let Ok(KeyFile::Private(decoded)) =
+                    KeyFile::decode(&key_pair.encode(format).unwrap())
+                else {
+                    panic!("{} private key did not round-trip", algorithm.as_str());
+                };
+                assert_eq!(decoded.algorithm, algorithm);
+                assert_eq!(decoded.secret_key, key_pair.secret_key);
+                assert_eq!(decoded.public(), key_pair.public());
+
+                let Ok(KeyFile::Public(public)) =
+                    KeyFile::decode(&key_pair.public().encode(format).unwrap())
+                else {
+                    panic!("{} public key did not round-trip", algorithm.as_str());
+                };
+                assert_eq!(public, key_pair.public());
+            }
+        }
+    }
+
+    #[test]
+    fn mismatched_halves_are_rejected() {
+        let algorithm = KeyAlgorithm::Signature(SignatureAlgorithm::MlDsa65);
+        let mut key_pair = KeyPair::generate(algorithm);
+        key_pair.public_key = KeyPair::generate(algorithm).public_key;
+        assert!(key_pair.check().is_err());
+        assert!(KeyFile::decode(&key_pair.encode(KeyFormat::Pem).unwrap()).is_err());
+
+        let algorithm = KeyAlgorithm::Kem(KemAlgorithm::Kyber768);
+        let mut key_pair = KeyPair::generate(algorithm);
+        key_pair.public_key = KeyPair::generate(algorithm).public_key;
+        assert!(key_pair.check().is_err());
+    }
+
+    #[test]
+    fn files_without_a_public_key_recover_it() {
+        for algorithm in [
+            KeyAlgorithm::Kem(KemAlgorithm::Kyber1024),
+            KeyAlgorithm::Kem(KemAlgorithm::X25519),
+            KeyAlgorithm::Signature(SignatureAlgorithm::MlDsa65),
+            KeyAlgorithm::Signature(SignatureAlgorithm::Ed25519),
+        ] {
+            let key_pair = KeyPair::generate(algorithm);
+            let private_key = encode_secret(algorithm, &key_pair.secret_key).unwrap();
+            let v1 = PrivateKeyInfo::new(algorithm_identifier(algorithm), &private_key)
+                .to_der()
+                .unwrap();
+            let decoded = KeyPair::from_der(&v1).unwrap();
+            assert_eq!(decoded.public(), key_pair.public());
+        }
+    }
+
+    #[test]
+    fn identity_keys_export_and_load_back_as_an_identity() {
+        let identity = crate::identity::SigningIdentity::generate();
+        let key_pairs = identity
+            .algorithms()
+            .map(|algorithm| {
+                let pem = identity
+                    .key_pair(algorithm)
+                    .unwrap()
+                    .encode(KeyFormat::Pem)
+                    .unwrap();
+                match KeyFile::decode(&pem).unwrap() {
+                    KeyFile::Private(key_pair) => key_pair,
+                    KeyFile::Public(_) => panic!("exported a public key"),
+                }
+            })
+            .collect();
+        let loaded = crate::identity::SigningIdentity::from_key_pairs(key_pairs).unwrap();
+        for algorithm in SignatureAlgorithm::ALL {
+            assert_eq!(
+                loaded.fingerprint(algorithm),
+                identity.fingerprint(algorithm)
+            );
+        }
+
+        let kem = KeyPair::generate(KeyAlgorithm::Kem(KemAlgorithm::Kyber768));
+        assert!(crate::identity::SigningIdentity::from_key_pairs(vec![kem]).is_err());
+    }
+}
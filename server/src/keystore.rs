@@ -0,0 +1,479 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+/// Environment variables the key-encryption key is read from. The `_FD`
+/// forms name an inherited file descriptor to read it from instead, which
+/// keeps it out of `/proc/<pid>/environ`.
+pub const PASSPHRASE_ENV: &str = "VPN_KEY_PASSPHRASE";
+pub const KEK_ENV: &str = "VPN_KEK";
+/// Set to `1` to let a configured key-encryption key also read plaintext
+/// key files, e.g. while migrating them. Otherwise they are refused.
+pub const ALLOW_PLAINTEXT_ENV: &str = "VPN_ALLOW_PLAINTEXT_KEYS";
+/// Prefix for the replacement key `rotate-passphrase` switches to, e.g.
+/// `VPN_NEW_KEY_PASSPHRASE`.
+pub const NEW_PREFIX: &str = "VPN_NEW_";
+
+const SEALED_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Argon2id cost for new files: 64 MiB, three passes. Stored in each file so
+/// it can be raised later without breaking existing ones. Tests use the
+/// minimum so they stay fast in debug builds.
+#[cfg(not(test))]
+const ARGON2_M_COST: u32 = 64 * 1024;
+#[cfg(test)]
+const ARGON2_M_COST: u32 = 8;
+#[cfg(not(test))]
+const ARGON2_T_COST: u32 = 3;
+#[cfg(test)]
+const ARGON2_T_COST: u32 = 1;
+const ARGON2_P_COST: u32 = 1;
+/// Cost a sealed file may ask for. Files carry their own parameters, so a
+/// tampered one could otherwise demand gigabytes of memory before the
+/// authentication tag is ever checked.
+const ARGON2_M_COST_RANGE: RangeInclusive<u32> = ARGON2_M_COST / 4..=ARGON2_M_COST * 16;
+const ARGON2_T_COST_RANGE: RangeInclusive<u32> = 1..=ARGON2_T_COST * 10;
+const ARGON2_P_COST_RANGE: RangeInclusive<u32> = 1..=16;
+
+/// What private key files are encrypted under: a passphrase stretched with
+/// Argon2id, or a 32-byte key-encryption key from a secrets manager.
+pub struct KeyEncryptionKey {
+    secret: Secret,
+    allow_plaintext: bool,
+}
+
+enum Secret {
+    Passphrase(Zeroizing<String>),
+    Key(Zeroizing<[u8; 32]>),
+}
+
+/// A private key file encrypted at rest. The plaintext is whatever the file
+/// held before sealing: an identity, a PKCS#8 key or a CA key.
+#[derive(Serialize, Deserialize)]
+struct SealedFile {
+    sealed: u8,
+    kdf: Kdf,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+enum Kdf {
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+        salt: Vec<u8>,
+    },
+    /// HKDF-SHA256 of the key-encryption key under a per-file salt.
+    Kek { salt: Vec<u8> },
+}
+
+impl fmt::Debug for KeyEncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.secret {
+            Secret::Passphrase(_) => f.write_str("KeyEncryptionKey::Passphrase(..)"),
+            Secret::Key(_) => f.write_str("KeyEncryptionKey::Key(..)"),
+        }
+    }
+}
+
+impl KeyEncryptionKey {
+    pub fn passphrase(passphrase: &str) -> Self {
+        Self::new(Secret::Passphrase(Zeroizing::new(passphrase.to_string())))
+    }
+
+    fn new(secret: Secret) -> Self {
+        Self {
+            secret,
+            allow_plaintext: false,
+        }
+    }
+
+    /// Whether files that are not sealed may still be read under this key.
+    /// Off by default, so a swapped-in plaintext file is not trusted.
+    pub fn with_plaintext_allowed(mut self, allowed: bool) -> Self {
+        self.allow_plaintext = allowed;
+        self
+    }
+
+    /// Parses 64 hex digits.
+    pub fn from_hex(hex_key: &str) -> io::Result<Self> {
+        let bytes = Zeroizing::new(hex::decode(hex_key.trim()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "KEK must be 64 hex digits")
+        })?);
+        let key: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "KEK must be 64 hex digits")
+        })?;
+        Ok(Self::new(Secret::Key(Zeroizing::new(key))))
+    }
+
+    /// Reads the key from `VPN_KEY_PASSPHRASE`, `VPN_KEK` or their `_FD`
+    /// forms, then clears the variable so child processes do not inherit
+    /// it. Returns `None` when none is set. `VPN_ALLOW_PLAINTEXT_KEYS=1`
+    /// lets the key read plaintext files too.
+    ///
+    /// Clearing the environment is not thread-safe, so call this before
+    /// starting any threads.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let allow_plaintext = env::var(ALLOW_PLAINTEXT_ENV).is_ok_and(|value| value == "1");
+        Ok(Self::from_env_with_prefix("VPN_")?
+            .map(|kek| kek.with_plaintext_allowed(allow_plaintext)))
+    }
+
+    /// Like [`KeyEncryptionKey::from_env`], for the `VPN_NEW_` variables.
+    pub fn new_from_env() -> io::Result<Option<Self>> {
+        Self::from_env_with_prefix(NEW_PREFIX)
+    }
+
+    fn from_env_with_prefix(prefix: &str) -> io::Result<Option<Self>> {
+        let rename = |name: &str| format!("{}{}", prefix, &name["VPN_".len()..]);
+        let passphrase = read_secret_var(&rename(PASSPHRASE_ENV))?;
+        let kek = read_secret_var(&rename(KEK_ENV))?;
+        match (passphrase, kek) {
+            (Some(_), Some(_)) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "set only one of {} and {}",
+                    rename(PASSPHRASE_ENV),
+                    rename(KEK_ENV)
+                ),
+            )),
+            (Some(passphrase), None) => {
+                let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+                if passphrase.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "empty key passphrase",
+                    ));
+                }
+                Ok(Some(Self::passphrase(passphrase)))
+            }
+            (None, Some(kek)) => Self::from_hex(&kek).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn file_key(&self, kdf: &Kdf) -> io::Result<Zeroizing<[u8; 32]>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        match (&self.secret, kdf) {
+            (
+                Secret::Passphrase(passphrase),
+                Kdf::Argon2id {
+                    m_cost,
+                    t_cost,
+                    p_cost,
+                    salt,
+                },
+            ) => {
+                if !ARGON2_M_COST_RANGE.contains(m_cost)
+                    || !ARGON2_T_COST_RANGE.contains(t_cost)
+                    || !ARGON2_P_COST_RANGE.contains(p_cost)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Argon2id cost m={} t={} p={} is outside the accepted range",
+                            m_cost, t_cost, p_cost
+                        ),
+                    ));
+                }
+                let params = Params::new(*m_cost, *t_cost, *p_cost, Some(key.len()))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            }
+            (Secret::Key(kek), Kdf::Kek { salt }) => {
+                Hkdf::<Sha256>::new(Some(salt), kek.as_ref())
+                    .expand(b"pqc-vpn sealed key v1", key.as_mut())
+                    .expect("32 bytes is a valid HKDF-SHA256 output length");
+            }
+            (_, Kdf::Argon2id { .. }) => {
+                return Err(locked(format!(
+                    "file is sealed with a passphrase; set {}",
+                    PASSPHRASE_ENV
+                )))
+            }
+            (_, Kdf::Kek { .. }) => {
+                return Err(locked(format!(
+                    "file is sealed with a key-encryption key; set {}",
+                    KEK_ENV
+                )))
+            }
+        }
+        Ok(key)
+    }
+
+    fn new_kdf(&self) -> Kdf {
+        let mut salt = vec![0u8; SALT_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut salt);
+        match self.secret {
+            Secret::Passphrase(_) => Kdf::Argon2id {
+                m_cost: ARGON2_M_COST,
+                t_cost: ARGON2_T_COST,
+                p_cost: ARGON2_P_COST,
+                salt,
+            },
+            Secret::Key(_) => Kdf::Kek { salt },
+        }
+    }
+}
+
+/// Reads `name`, or the file descriptor named by `name_FD`, and removes
+/// whichever was set from the environment.
+fn read_secret_var(name: &str) -> io::Result<Option<Zeroizing<String>>> {
+    let fd_name = format!("{}_FD", name);
+    let value = match (env::var(name), env::var(&fd_name)) {
+        (Ok(value), Err(_)) => Zeroizing::new(value),
+        (Err(_), Ok(fd)) => {
+            let fd: u32 = fd.trim().parse().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} must be a file descriptor number", fd_name),
+                )
+            })?;
+            let contents = Zeroizing::new(fs::read(format!("/dev/fd/{}", fd))?);
+            Zeroizing::new(String::from_utf8(contents.to_vec()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} did not hold UTF-8 text", fd_name),
+                )
+            })?)
+        }
+        (Ok(_), Ok(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("set only one of {} and {}", name, fd_name),
+            ))
+        }
+        (Err(_), Err(_)) => return Ok(None),
+    };
+    env::remove_var(name);
+    env::remove_var(&fd_name);
+    Ok(Some(value))
+}
+
+fn locked(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message)
+}
+
+/// Whether `contents` is a sealed file rather than plaintext.
+pub fn is_sealed(contents: &[u8]) -> bool {
+    serde_json::from_slice::<SealedFile>(contents).is_ok()
+}
+
+fn additional_data(kdf: &Kdf) -> io::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&(SEALED_VERSION, kdf))?)
+}
+
+/// Encrypts `plaintext` under a fresh salt and nonce.
+pub fn seal(plaintext: &[u8], kek: &KeyEncryptionKey) -> io::Result<Vec<u8>> {
+    let kdf = kek.new_kdf();
+    let key = kek.file_key(&kdf)?;
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let ciphertext = ChaCha20Poly1305::new(key.as_ref().into())
+        .encrypt(
+            nonce.as_slice().into(),
+            Payload {
+                msg: plaintext,
+                aad: &additional_data(&kdf)?,
+            },
+        )
+        .map_err(|_| io::Error::other("sealing failed"))?;
+    Ok(serde_json::to_vec(&SealedFile {
+        sealed: SEALED_VERSION,
+        kdf,
+        nonce,
+        ciphertext,
+    })?)
+}
+
+/// Decrypts a sealed file, or passes plaintext through unchanged. Sealed
+/// contents need `kek`; a wrong one fails with `PermissionDenied`. With a
+/// `kek`, plaintext is refused unless the key allows it.
+pub fn open(contents: &[u8], kek: Option<&KeyEncryptionKey>) -> io::Result<Zeroizing<Vec<u8>>> {
+    let Ok(sealed) = serde_json::from_slice::<SealedFile>(contents) else {
+        if kek.is_some_and(|kek| !kek.allow_plaintext) {
+            return Err(locked(format!(
+                "key file is not encrypted; seal it with rotate-passphrase or set {}=1",
+                ALLOW_PLAINTEXT_ENV
+            )));
+        }
+        return Ok(Zeroizing::new(contents.to_vec()));
+    };
+    if sealed.sealed != SEALED_VERSION || sealed.nonce.len() != NONCE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unsupported sealed key file",
+        ));
+    }
+    let kek = kek.ok_or_else(|| {
+        locked(format!(
+            "key file is encrypted; set {} or {}",
+            PASSPHRASE_ENV, KEK_ENV
+        ))
+    })?;
+    let key = kek.file_key(&sealed.kdf)?;
+    let plaintext = ChaCha20Poly1305::new(key.as_ref().into())
+        .decrypt(
+            sealed.nonce.as_slice().into(),
+            Payload {
+                msg: &sealed.ciphertext,
+                aad: &additional_data(&sealed.kdf)?,
+            },
+        )
+        .map_err(|_| locked("wrong passphrase or key-encryption key".to_string()))?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+/// Reads a private key file, unsealing it with `kek` if it is sealed.
+pub fn read_private(path: &Path, kek: Option<&KeyEncryptionKey>) -> io::Result<Zeroizing<Vec<u8>>> {
+    let contents = Zeroizing::new(fs::read(path)?);
+    open(&contents, kek).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// Creates `path` with owner-only permissions holding `contents`, sealed
+/// under `kek` if one is given.
+pub fn write_private(
+    path: &Path,
+    contents: &[u8],
+    kek: Option<&KeyEncryptionKey>,
+) -> io::Result<()> {
+    match kek {
+        Some(kek) => create_private(path, &seal(contents, kek)?),
+        None => create_private(path, contents),
+    }
+}
+
+/// Overwrites `path` by writing a sibling file and renaming it into place,
+/// so a crash never leaves a truncated key behind.
+pub fn replace_private(
+    path: &Path,
+    contents: &[u8],
+    kek: Option<&KeyEncryptionKey>,
+) -> io::Result<()> {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".tmp");
+    let staging = PathBuf::from(staging);
+    match fs::remove_file(&staging) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    write_private(&staging, contents, kek)?;
+    fs::rename(&staging, path)
+}
+
+/// Re-encrypts the file at `path` from `old` (or plaintext) to `new`
+/// without parsing what it holds.
+pub fn rotate(
+    path: &Path,
+    old: Option<&KeyEncryptionKey>,
+    new: &KeyEncryptionKey,
+) -> io::Result<()> {
+    let plaintext = read_private(path, old)?;
+    replace_private(path, &plaintext, Some(new))
+}
+
+fn create_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    io::Write::write_all(&mut options.open(path)?, contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_files_open_only_under_their_key() {
+        let passphrase = KeyEncryptionKey::passphrase("correct horse");
+        let kek = KeyEncryptionKey::from_hex(&"42".repeat(32)).unwrap();
+        let other_kek = KeyEncryptionKey::from_hex(&"43".repeat(32)).unwrap();
+
+        for key in [&passphrase, &kek] {
+            let sealed = seal(b"identity bytes", key).unwrap();
+            assert!(is_sealed(&sealed));
+            assert!(!sealed
+                .windows(b"identity bytes".len())
+                .any(|w| w == b"identity bytes"));
+            assert_eq!(*open(&sealed, Some(key)).unwrap(), b"identity bytes");
+            assert_eq!(
+                open(&sealed, None).unwrap_err().kind(),
+                io::ErrorKind::PermissionDenied
+            );
+        }
+
+        let sealed = seal(b"identity bytes", &kek).unwrap();
+        assert!(open(&sealed, Some(&other_kek)).is_err());
+        assert!(open(&sealed, Some(&passphrase)).is_err());
+        let wrong_passphrase = KeyEncryptionKey::passphrase("wrong");
+        let sealed = seal(b"identity bytes", &passphrase).unwrap();
+        assert!(open(&sealed, Some(&wrong_passphrase)).is_err());
+
+        // Plaintext files pass through without a key, but a configured key
+        // only reads them once the operator opts in.
+        assert!(!is_sealed(b"{\"dilithium2_public_key\":[]}"));
+        assert_eq!(*open(b"plain", None).unwrap(), b"plain");
+        assert_eq!(
+            open(b"plain", Some(&kek)).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        let kek = kek.with_plaintext_allowed(true);
+        assert_eq!(*open(b"plain", Some(&kek)).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn argon2_cost_outside_the_accepted_range_is_refused() {
+        let passphrase = KeyEncryptionKey::passphrase("correct horse");
+        let sealed = seal(b"identity bytes", &passphrase).unwrap();
+        let mut file: SealedFile = serde_json::from_slice(&sealed).unwrap();
+        let Kdf::Argon2id { m_cost, .. } = &mut file.kdf else {
+            panic!("passphrases derive with Argon2id");
+        };
+        *m_cost = 4 * 1024 * 1024;
+        let tampered = serde_json::to_vec(&file).unwrap();
+        assert_eq!(
+            open(&tampered, Some(&passphrase)).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn rotation_rewrites_the_file_under_the_new_key() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key.pem");
+        let old = KeyEncryptionKey::from_hex(&"01".repeat(32)).unwrap();
+        let new = KeyEncryptionKey::passphrase("rotated");
+
+        write_private(&path, b"secret", None).unwrap();
+        rotate(&path, None, &old).unwrap();
+        assert_eq!(*read_private(&path, Some(&old)).unwrap(), b"secret");
+        rotate(&path, Some(&old), &new).unwrap();
+        assert!(read_private(&path, Some(&old)).is_err());
+        assert_eq!(*read_private(&path, Some(&new)).unwrap(), b"secret");
+        assert!(!dir.join("key.pem.tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
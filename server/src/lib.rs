@@ -0,0 +1,17 @@
+pub mod authorized_keys;
+pub mod certs;
+pub mod cipher;
+pub mod cli;
+pub mod config;
+pub mod connection;
+pub mod crypto;
+mod file_stamp;
+pub mod identity;
+pub mod keyfile;
+pub mod keystore;
+pub mod replay;
+pub mod resumption;
+pub mod revocation;
+pub mod rotation;
+pub mod session;
+pub mod transparency;
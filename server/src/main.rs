@@ -1,17 +1,28 @@
 use actix_web::{middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
-use actix_ws::Message;
+use actix_ws::{CloseCode, CloseReason, Message};
 use futures::StreamExt;
-use reqwest;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 use url::Url;
 
-mod crypto;
-mod session;
-
-use crypto::KeyExchange;
-use session::SessionManager;
+use quantum_vpn_server::authorized_keys::AuthorizedKeyStore;
+use quantum_vpn_server::certs::CaPublicKey;
+use quantum_vpn_server::cli;
+use quantum_vpn_server::config::ServerConfig;
+use quantum_vpn_server::connection::{Connection, ConnectionState, MessageKind, ProtocolViolation};
+use quantum_vpn_server::crypto::{
+    AlgorithmSuite, AuthMode, CryptoError, HandshakeRequest, KeyExchange, SignatureAlgorithm,
+};
+use quantum_vpn_server::identity::SigningIdentity;
+use quantum_vpn_server::keystore::KeyEncryptionKey;
+use quantum_vpn_server::resumption::{ResumeRequest, TicketClaims, TicketIssuer};
+use quantum_vpn_server::revocation::{RevocationStore, Revocations};
+use quantum_vpn_server::rotation::IdentityStore;
+use quantum_vpn_server::session::{SessionManager, Termination};
+use quantum_vpn_server::transparency::TransparencyLog;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct AuthRequest {
@@ -20,12 +31,6 @@ struct AuthRequest {
     username: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct HandshakeRequest {
-    client_public_key: Vec<u8>,
-    signature: Vec<u8>,
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 struct VpnPacket {
     #[serde(rename = "type")]
@@ -56,23 +61,124 @@ struct HttpProxyResponse {
     body: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct StatsRequest {
-    #[serde(rename = "type")]
-    message_type: String,
+/// A text frame from the client, told apart by its `type` field. Handshake
+/// requests predate the field and are recognised by its absence.
+enum ClientMessage {
+    Handshake(HandshakeRequest),
+    Resume(ResumeRequest),
+    Auth(AuthRequest),
+    Stats,
+    HttpProxy(HttpProxyRequest),
+    TunnelData(VpnPacket),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ServerListRequest {
-    #[serde(rename = "type")]
-    message_type: String,
+impl ClientMessage {
+    fn parse(text: &str) -> Option<Self> {
+        let value: serde_json::Value = serde_json::from_str(text).ok()?;
+        let message = match value.get("type").map(|t| t.as_str()) {
+            None => ClientMessage::Handshake(serde_json::from_value(value).ok()?),
+            Some(Some("resume")) => ClientMessage::Resume(serde_json::from_value(value).ok()?),
+            Some(Some("auth")) => ClientMessage::Auth(serde_json::from_value(value).ok()?),
+            Some(Some("get_stats")) => ClientMessage::Stats,
+            Some(Some("http_proxy_request")) => {
+                ClientMessage::HttpProxy(serde_json::from_value(value).ok()?)
+            }
+            Some(Some("tunnel_data")) => {
+                ClientMessage::TunnelData(serde_json::from_value(value).ok()?)
+            }
+            Some(_) => return None,
+        };
+        Some(message)
+    }
+
+    fn kind(&self) -> MessageKind {
+        match self {
+            ClientMessage::Handshake(_) => MessageKind::Handshake,
+            ClientMessage::Resume(_) => MessageKind::Resume,
+            ClientMessage::Auth(_) => MessageKind::Auth,
+            ClientMessage::Stats => MessageKind::Stats,
+            ClientMessage::HttpProxy(_) => MessageKind::HttpProxy,
+            ClientMessage::TunnelData(_) => MessageKind::TunnelData,
+        }
+    }
+}
+
+/// Tells the client a message was refused and why.
+async fn reject_message(
+    session: &mut actix_ws::Session,
+    connection: &Connection,
+    peer_addr: &str,
+    violation: ProtocolViolation,
+) -> Result<(), actix_ws::Closed> {
+    log::debug!(
+        "Rejected message from {}: {} ({} so far)",
+        peer_addr,
+        violation,
+        connection.rejected_messages()
+    );
+    send_error(
+        session,
+        connection,
+        violation.code(),
+        &violation.to_string(),
+    )
+    .await
+}
+
+/// Resolves with the reason once the server ends the connection's session,
+/// e.g. because its key was revoked. Pending until a session exists.
+async fn terminated(termination: &mut Option<Termination>) -> String {
+    let Some(termination) = termination else {
+        return std::future::pending().await;
+    };
+    match termination.changed().await {
+        Ok(()) => termination
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| "session terminated".to_string()),
+        Err(_) => "session ended".to_string(),
+    }
+}
+
+/// Refuses a handshake or resumption: a failed rekey keeps the existing
+/// session, and the client always hears why rather than waiting.
+async fn fail_handshake(
+    session: &mut actix_ws::Session,
+    connection: &mut Connection,
+    reason: &str,
+) -> Result<(), actix_ws::Closed> {
+    connection.handshake_failed();
+    send_error(session, connection, "handshake_failed", reason).await
+}
+
+async fn send_error(
+    session: &mut actix_ws::Session,
+    connection: &Connection,
+    code: &str,
+    message: &str,
+) -> Result<(), actix_ws::Closed> {
+    let frame = serde_json::json!({
+        "type": "error",
+        "code": code,
+        "message": message,
+        "state": connection.state()
+    });
+    session.text(serde_json::to_string(&frame).unwrap()).await
 }
 
 async fn handle_ws_connection(
     req: HttpRequest,
     stream: web::Payload,
     session_manager: web::Data<SessionManager>,
+    identity: web::Data<IdentityStore>,
+    authorized_keys: web::Data<AuthorizedKeyStore>,
+    ticket_issuer: web::Data<TicketIssuer>,
+    config: web::Data<ServerConfig>,
 ) -> Result<HttpResponse, Error> {
+    let listener = config
+        .listener(req.app_config().local_addr())
+        .cloned()
+        .ok_or_else(|| actix_web::error::ErrorInternalServerError("unknown listener"))?;
     let (response, mut session, mut msg_stream) = actix_ws::handle(&req, stream)?;
     let peer_addr = req
         .peer_addr()
@@ -82,40 +188,136 @@ async fn handle_ws_connection(
     // Get server's local IP address
     let server_ip = get_local_ip().unwrap_or_else(|| "127.0.0.1".to_string());
 
-    let key_exchange = KeyExchange::new();
-    let (kyber_public_key, dilithium_public_key) = key_exchange.get_public_keys();
+    let identity = identity.get();
+    let revocations = req
+        .app_data::<web::Data<RevocationStore>>()
+        .map(|revocations| revocations.clone().into_inner());
+    let key_exchange = KeyExchange::with_identity(identity.current.clone())
+        .with_next_identity(identity.next.clone())
+        .with_suites(config.suites(&listener))
+        .with_certificate(identity.certificate.clone())
+        .with_revocations(
+            revocations
+                .clone()
+                .map(|revocations| revocations as Arc<dyn Revocations>),
+        );
 
-    // Send server's public keys
-    let initial_message = serde_json::json!({
-        "kyber_public_key": kyber_public_key,
-        "dilithium_public_key": dilithium_public_key,
-    });
-
-    let _ = session
-        .text(serde_json::to_string(&initial_message).unwrap())
-        .await;
+    // Send server's public keys; a client already gone needs no connection loop
+    if session
+        .text(serde_json::to_string(&key_exchange.server_hello()).unwrap())
+        .await
+        .is_err()
+    {
+        return Ok(response);
+    }
 
     // Use actix_rt::spawn for non-Send futures
     actix_rt::spawn(async move {
+        let mut connection = Connection::new();
         let mut session_id: Option<String> = None;
+        let mut session_user: Option<String> = None;
+        let mut negotiated_suite: Option<AlgorithmSuite> = None;
         let mut bytes_rx = 0u64;
         let mut bytes_tx = 0u64;
         let last_ping = std::time::Instant::now();
+        let mut termination: Option<Termination> = None;
 
-        while let Some(msg) = msg_stream.next().await {
+        loop {
+            let msg = tokio::select! {
+                biased;
+                reason = terminated(&mut termination) => {
+                    log::info!("Closing connection from {}: {}", peer_addr, reason);
+                    connection.close();
+                    let _ = session
+                        .close(Some(CloseReason {
+                            code: CloseCode::Policy,
+                            description: Some(reason),
+                        }))
+                        .await;
+                    break;
+                }
+                msg = msg_stream.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+            };
             match msg {
                 Ok(Message::Text(text)) => {
-                    // Try to parse as auth request first
-                    if let Ok(auth_req) = serde_json::from_str::<AuthRequest>(&text) {
-                        if auth_req.message_type == "auth" {
-                            // Simple auth success response
+                    let Some(message) = ClientMessage::parse(&text) else {
+                        let violation = connection.malformed();
+                        if reject_message(&mut session, &connection, &peer_addr, violation)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    };
+                    if let Err(violation) = connection.check(message.kind()) {
+                        if reject_message(&mut session, &connection, &peer_addr, violation)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    match message {
+                        ClientMessage::Auth(auth_req) => {
+                            // The username must be the one the handshake's key is enrolled for
+                            if session_user.as_ref() != Some(&auth_req.username) {
+                                log::warn!(
+                                    "Rejected auth from {}: {} does not match the handshake key",
+                                    peer_addr,
+                                    auth_req.username
+                                );
+                                if send_error(
+                                    &mut session,
+                                    &connection,
+                                    "auth_failed",
+                                    "username does not match the handshake key",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+                            // ...and the client must have proven it holds the traffic key
+                            if !connection.authenticated() {
+                                log::warn!(
+                                    "Rejected auth from {}: no encrypted frame yet",
+                                    peer_addr
+                                );
+                                if send_error(
+                                    &mut session,
+                                    &connection,
+                                    "auth_failed",
+                                    "send an encrypted frame before auth",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+
                             let response = serde_json::json!({
                                 "type": "auth_success",
                                 "message": "Authentication successful",
                                 "server_info": {
                                     "name": "Quantum VPN Server",
                                     "location": "Global",
-                                    "encryption": "Post-Quantum (Kyber768 + Dilithium2)",
+                                    "encryption": format!(
+                                        "Post-Quantum ({})",
+                                        negotiated_suite.unwrap_or_default().name()
+                                    ),
+                                    "suite": negotiated_suite,
+                                    "security_level": negotiated_suite
+                                        .map(|suite| suite.security_level()),
                                     "ip_address": server_ip,
                                     "port": "8000"
                                 }
@@ -128,6 +330,7 @@ async fn handle_ws_connection(
                                 log::error!("Failed to send auth response: {}", e);
                                 break;
                             }
+                            log::info!("{} authenticated as {}", peer_addr, auth_req.username);
 
                             // Start sending periodic stats
                             let mut session_clone = session.clone();
@@ -155,13 +358,16 @@ async fn handle_ws_connection(
                                     }
                                 }
                             });
-                            continue;
                         }
-                    }
-
-                    // Handle stats request
-                    if let Ok(stats_req) = serde_json::from_str::<StatsRequest>(&text) {
-                        if stats_req.message_type == "get_stats" {
+                        ClientMessage::Stats => {
+                            let (aead, replay) = session_id
+                                .as_deref()
+                                .and_then(|id| {
+                                    session_manager.with_session(id, |vpn_session| {
+                                        (vpn_session.aead, vpn_session.crypto.replay_stats())
+                                    })
+                                })
+                                .unzip();
                             let response = serde_json::json!({
                                 "type": "stats_response",
                                 "latency": (std::time::Instant::now() - last_ping).as_millis() as u32,
@@ -170,7 +376,11 @@ async fn handle_ws_connection(
                                     "tx": bytes_tx
                                 },
                                 "uptime": "Connected",
-                                "server_load": rand::random::<u32>() % 30 + 20
+                                "server_load": rand::random::<u32>() % 30 + 20,
+                                "aead": aead,
+                                "replay": replay,
+                                "state": connection.state(),
+                                "rejected_messages": connection.rejected_messages()
                             });
 
                             if let Err(e) = session
@@ -179,13 +389,8 @@ async fn handle_ws_connection(
                             {
                                 log::error!("Failed to send stats response: {}", e);
                             }
-                            continue;
                         }
-                    }
-
-                    // Handle HTTP proxy requests
-                    if let Ok(proxy_req) = serde_json::from_str::<HttpProxyRequest>(&text) {
-                        if proxy_req.message_type == "http_proxy_request" {
+                        ClientMessage::HttpProxy(proxy_req) => {
                             bytes_rx +=
                                 proxy_req.body.as_ref().map(|b| b.len()).unwrap_or(0) as u64;
 
@@ -207,13 +412,8 @@ async fn handle_ws_connection(
                                     log::error!("Failed to send proxy response: {}", e);
                                 }
                             });
-                            continue;
                         }
-                    }
-
-                    // Handle VPN packet tunneling (legacy)
-                    if let Ok(vpn_packet) = serde_json::from_str::<VpnPacket>(&text) {
-                        if vpn_packet.packet_type == "tunnel_data" {
+                        ClientMessage::TunnelData(vpn_packet) => {
                             bytes_rx += vpn_packet.data.len() as u64;
 
                             // Simulate packet processing and forwarding
@@ -238,61 +438,400 @@ async fn handle_ws_connection(
                             {
                                 log::error!("Failed to send tunnel response: {}", e);
                             }
-                            continue;
                         }
-                    }
+                        ClientMessage::Resume(resume) => {
+                            // Resume from a ticket instead of running the full handshake
+                            connection.handshake_started();
+                            let (resume_response, crypto, claims) = match ticket_issuer
+                                .redeem(&resume)
+                            {
+                                Ok(resumed) => resumed,
+                                Err(e) => {
+                                    log::warn!("Rejected resumption from {}: {}", peer_addr, e);
+                                    if fail_handshake(&mut session, &mut connection, &e.to_string())
+                                        .await
+                                        .is_err()
+                                    {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            };
 
-                    // Try to parse as handshake request
-                    if let Ok(handshake) = serde_json::from_str::<HandshakeRequest>(&text) {
-                        // Verify client's signature
-                        if key_exchange
-                            .verify_client_signature(
-                                &handshake.client_public_key,
-                                &handshake.signature,
-                                &handshake.client_public_key,
-                            )
-                            .is_ok()
-                        {
-                            // Process client's public key and generate shared secret
-                            if let Ok(shared_secret) =
-                                key_exchange.process_client_key(&handshake.client_public_key)
+                            // The keys behind the ticket must still be enrolled to the
+                            // ticket's user and not revoked
+                            let enrolled_to_user = |algorithm, key: &[u8]| {
+                                authorized_keys.username_for(algorithm, key).as_ref()
+                                    == Some(&claims.username)
+                            };
+                            let ed25519_unenrolled =
+                                claims.client_ed25519_key.as_ref().is_some_and(|key| {
+                                    !enrolled_to_user(SignatureAlgorithm::Ed25519, key)
+                                });
+                            if !enrolled_to_user(claims.signature, &claims.client_signing_key)
+                                || ed25519_unenrolled
+                                || revocations.as_ref().is_some_and(|revocations| {
+                                    claims
+                                        .client_keys()
+                                        .iter()
+                                        .any(|key| revocations.is_revoked(key))
+                                })
+                            {
+                                log::warn!(
+                                    "Rejected resumption from {}: {} is no longer authorized",
+                                    peer_addr,
+                                    claims.username
+                                );
+                                if fail_handshake(
+                                    &mut session,
+                                    &mut connection,
+                                    "key is no longer authorized",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+                            if config.require_dual_signatures && claims.client_ed25519_key.is_none()
+                            {
+                                log::warn!(
+                                    "Rejected resumption from {}: dual signatures are required",
+                                    peer_addr
+                                );
+                                if fail_handshake(
+                                    &mut session,
+                                    &mut connection,
+                                    "dual signatures are required",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+                            let min_level =
+                                config.min_security_level(&listener, Some(&claims.username));
+                            if claims.suite.security_level() < min_level {
+                                let e =
+                                    CryptoError::SecurityLevelError(claims.suite.name(), min_level);
+                                log::warn!("Rejected resumption from {}: {}", peer_addr, e);
+                                if fail_handshake(&mut session, &mut connection, &e.to_string())
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+                            if connection.state() == ConnectionState::Rekeying
+                                && session_user.as_ref() != Some(&claims.username)
+                            {
+                                log::warn!(
+                                    "Rejected rekey from {}: ticket belongs to {}",
+                                    peer_addr,
+                                    claims.username
+                                );
+                                if fail_handshake(
+                                    &mut session,
+                                    &mut connection,
+                                    "ticket belongs to another user",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            if let Err(e) = session
+                                .text(serde_json::to_string(&resume_response).unwrap())
+                                .await
                             {
-                                // Create session with the shared secret
-                                if let Ok(id) = session_manager.create_session(
-                                    peer_addr.clone(),
-                                    shared_secret,
-                                    session.clone(),
-                                ) {
-                                    session_id = Some(id);
-                                    log::info!("Session established for {}", peer_addr);
+                                log::error!("Failed to send resume response: {}", e);
+                                break;
+                            }
+                            match ticket_issuer.issue(&crypto, claims.clone()) {
+                                Ok(ticket) => {
+                                    let _ =
+                                        session.text(serde_json::to_string(&ticket).unwrap()).await;
+                                }
+                                Err(e) => log::error!("Failed to issue resumption ticket: {}", e),
+                            }
+
+                            let (new_session_id, new_termination) = session_manager.create_session(
+                                peer_addr.clone(),
+                                claims.username.clone(),
+                                crypto,
+                                claims.client_keys(),
+                            );
+                            termination = Some(new_termination);
+                            if let Some(old_session_id) = session_id.replace(new_session_id) {
+                                session_manager.remove_session(&old_session_id);
+                            }
+                            session_user = Some(claims.username.clone());
+                            negotiated_suite = Some(claims.suite);
+                            connection.handshake_completed();
+                            log::info!(
+                                "Session resumed for {} as {} ({}, {})",
+                                peer_addr,
+                                claims.username,
+                                claims.suite.name(),
+                                claims.aead.as_str()
+                            );
+                        }
+                        ClientMessage::Handshake(handshake) => {
+                            connection.handshake_started();
+
+                            if config.require_dual_signatures && handshake.auth != AuthMode::Dual {
+                                log::warn!(
+                                    "Rejected handshake from {}: dual signatures are required",
+                                    peer_addr
+                                );
+                                if fail_handshake(
+                                    &mut session,
+                                    &mut connection,
+                                    "dual signatures are required",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            // Verify client's signature (both of them in dual mode)
+                            if let Err(e) = key_exchange.verify_client_request(&handshake) {
+                                log::warn!("Rejected handshake from {}: {}", peer_addr, e);
+                                if fail_handshake(&mut session, &mut connection, &e.to_string())
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            // Only enrolled client keys may complete the handshake
+                            let Some(username) = authorized_keys.username_for(
+                                handshake.suite.signature,
+                                &handshake.client_signing_key,
+                            ) else {
+                                log::warn!(
+                                    "Rejected handshake from {}: {} key is not authorized",
+                                    peer_addr,
+                                    handshake.suite.signature.as_str()
+                                );
+                                if fail_handshake(
+                                    &mut session,
+                                    &mut connection,
+                                    "client key is not authorized",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            };
+                            // The Ed25519 key must be enrolled for the same user
+                            if let Some(ed25519_key) = handshake
+                                .client_ed25519_key
+                                .as_ref()
+                                .filter(|_| handshake.auth == AuthMode::Dual)
+                            {
+                                if authorized_keys
+                                    .username_for(SignatureAlgorithm::Ed25519, ed25519_key)
+                                    .as_ref()
+                                    != Some(&username)
+                                {
+                                    log::warn!(
+                                        "Rejected handshake from {}: ed25519 key is not authorized for {}",
+                                        peer_addr,
+                                        username
+                                    );
+                                    if fail_handshake(
+                                        &mut session,
+                                        &mut connection,
+                                        "ed25519 key is not authorized",
+                                    )
+                                    .await
+                                    .is_err()
+                                    {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                            }
+                            // Some users must negotiate more than the listener's floor
+                            let min_level = config.min_security_level(&listener, Some(&username));
+                            if handshake.suite.security_level() < min_level {
+                                let e = CryptoError::SecurityLevelError(
+                                    handshake.suite.name(),
+                                    min_level,
+                                );
+                                log::warn!("Rejected handshake from {}: {}", peer_addr, e);
+                                if fail_handshake(&mut session, &mut connection, &e.to_string())
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+                            if connection.state() == ConnectionState::Rekeying
+                                && session_user.as_ref() != Some(&username)
+                            {
+                                log::warn!(
+                                    "Rejected rekey from {}: key belongs to {}",
+                                    peer_addr,
+                                    username
+                                );
+                                if fail_handshake(
+                                    &mut session,
+                                    &mut connection,
+                                    "key belongs to another user",
+                                )
+                                .await
+                                .is_err()
+                                {
+                                    break;
+                                }
+                                continue;
+                            }
+
+                            // Encapsulate against the client's key and send back the signed ciphertext
+                            match key_exchange
+                                .process_client_key(&handshake, config.psk_for(&username))
+                            {
+                                Ok((handshake_response, crypto)) => {
+                                    if let Err(e) = session
+                                        .text(serde_json::to_string(&handshake_response).unwrap())
+                                        .await
+                                    {
+                                        log::error!("Failed to send handshake response: {}", e);
+                                        break;
+                                    }
+
+                                    // Let the client skip the full handshake next time
+                                    let claims = TicketClaims {
+                                        username: username.clone(),
+                                        signature: handshake.suite.signature,
+                                        client_signing_key: handshake.client_signing_key.clone(),
+                                        client_ed25519_key: handshake
+                                            .client_ed25519_key
+                                            .clone()
+                                            .filter(|_| handshake.auth == AuthMode::Dual),
+                                        suite: handshake_response.suite,
+                                        aead: handshake_response.aead,
+                                    };
+                                    let client_keys = claims.client_keys();
+                                    match ticket_issuer.issue(&crypto, claims) {
+                                        Ok(ticket) => {
+                                            let _ = session
+                                                .text(serde_json::to_string(&ticket).unwrap())
+                                                .await;
+                                        }
+                                        Err(e) => {
+                                            log::error!("Failed to issue resumption ticket: {}", e)
+                                        }
+                                    }
+
+                                    let (new_session_id, new_termination) = session_manager
+                                        .create_session(
+                                            peer_addr.clone(),
+                                            username.clone(),
+                                            crypto,
+                                            client_keys,
+                                        );
+                                    termination = Some(new_termination);
+                                    if let Some(old_session_id) = session_id.replace(new_session_id)
+                                    {
+                                        session_manager.remove_session(&old_session_id);
+                                    }
+                                    session_user = Some(username.clone());
+                                    negotiated_suite = Some(handshake_response.suite);
+                                    connection.handshake_completed();
+                                    log::info!(
+                                        "Session established for {} as {} ({}, {}, {})",
+                                        peer_addr,
+                                        username,
+                                        handshake_response.suite.name(),
+                                        handshake_response.mode.as_str(),
+                                        handshake_response.aead.as_str()
+                                    );
+                                }
+                                Err(e) => {
+                                    log::warn!("Key exchange with {} failed: {}", peer_addr, e);
+                                    if fail_handshake(&mut session, &mut connection, &e.to_string())
+                                        .await
+                                        .is_err()
+                                    {
+                                        break;
+                                    }
                                 }
                             }
                         }
                     }
                 }
                 Ok(Message::Binary(data)) => {
+                    if let Err(violation) = connection.check(MessageKind::EncryptedFrame) {
+                        if reject_message(&mut session, &connection, &peer_addr, violation)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
                     if let Some(id) = &session_id {
-                        if let Some(mut vpn_session) = session_manager.get_session(id) {
-                            if let Ok(_decrypted) = vpn_session.crypto.decrypt(&data) {
+                        match session_manager
+                            .with_session(id, |vpn_session| vpn_session.decrypt_frame(&data))
+                        {
+                            Some(Ok(frame)) => {
+                                connection.key_confirmed();
                                 // Handle decrypted VPN traffic here
-                                log::debug!("Received {} bytes of encrypted data", data.len());
+                                log::debug!(
+                                    "Received {} bytes of {:?} on stream {}",
+                                    frame.payload.len(),
+                                    frame.frame_type,
+                                    frame.stream
+                                );
+                            }
+                            Some(Err(e)) => {
+                                log::debug!("Dropped encrypted frame from {}: {}", peer_addr, e);
                             }
+                            None => {}
                         }
                     }
                 }
                 Ok(Message::Close(_)) => {
-                    if let Some(id) = &session_id {
-                        session_manager.remove_session(id);
-                    }
+                    connection.close();
                     break;
                 }
                 Err(e) => {
                     log::error!("WebSocket error: {}", e);
+                    connection.close();
                     break;
                 }
                 _ => {}
             }
         }
+
+        if let Some(id) = &session_id {
+            session_manager.remove_session(id);
+        }
+        if connection.rejected_messages() > 0 {
+            log::info!(
+                "Connection from {} closed after {} rejected message(s)",
+                peer_addr,
+                connection.rejected_messages()
+            );
+        }
     });
 
     Ok(response)
@@ -388,6 +927,91 @@ async fn handle_http_proxy_request(request: HttpProxyRequest) -> HttpProxyRespon
     }
 }
 
+/// Publishes the server's long-term key fingerprints so operators can hand
+/// them to clients for pinning, plus the next identity's during a rotation.
+async fn identity_fingerprints(identity: web::Data<IdentityStore>) -> HttpResponse {
+    let identity = identity.get();
+    let mut body = serde_json::json!({
+        "fingerprints": identity_fingerprint_map(&identity.current)
+    });
+    if let Some(next) = &identity.next {
+        body["next_fingerprints"] = serde_json::json!(identity_fingerprint_map(&next.identity));
+    }
+    HttpResponse::Ok().json(body)
+}
+
+fn identity_fingerprint_map(identity: &SigningIdentity) -> HashMap<&'static str, String> {
+    identity
+        .algorithms()
+        .filter_map(|algorithm| Some((algorithm.as_str(), identity.fingerprint(algorithm)?)))
+        .collect()
+}
+
+/// Most entries `/transparency/entries` returns at once.
+const MAX_LOG_ENTRIES: u64 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct EntriesQuery {
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct InclusionQuery {
+    leaf_index: u64,
+    tree_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConsistencyQuery {
+    first: u64,
+    second: u64,
+}
+
+/// The transparency log's current root, signed with the current identity.
+async fn transparency_tree_head(
+    transparency: web::Data<TransparencyLog>,
+    identity: web::Data<IdentityStore>,
+) -> HttpResponse {
+    match transparency.tree_head(&identity.get().current) {
+        Ok(tree_head) => HttpResponse::Ok().json(tree_head),
+        Err(e) => {
+            log::error!("Failed to sign tree head: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn transparency_entries(
+    transparency: web::Data<TransparencyLog>,
+    query: web::Query<EntriesQuery>,
+) -> HttpResponse {
+    let end = query.end.min(query.start.saturating_add(MAX_LOG_ENTRIES));
+    HttpResponse::Ok().json(serde_json::json!({
+        "entries": transparency.entries(query.start, end)
+    }))
+}
+
+async fn transparency_inclusion(
+    transparency: web::Data<TransparencyLog>,
+    query: web::Query<InclusionQuery>,
+) -> HttpResponse {
+    match transparency.inclusion_proof(query.leaf_index, query.tree_size) {
+        Some(proof) => HttpResponse::Ok().json(proof),
+        None => HttpResponse::BadRequest().body("no such leaf or tree size"),
+    }
+}
+
+async fn transparency_consistency(
+    transparency: web::Data<TransparencyLog>,
+    query: web::Query<ConsistencyQuery>,
+) -> HttpResponse {
+    match transparency.consistency_proof(query.first, query.second) {
+        Some(proof) => HttpResponse::Ok().json(proof),
+        None => HttpResponse::BadRequest().body("no such tree sizes"),
+    }
+}
+
 fn get_local_ip() -> Option<String> {
     use std::net::TcpStream;
 
@@ -402,13 +1026,152 @@ fn get_local_ip() -> Option<String> {
     None
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let subcommand = match args.first().map(String::as_str) {
+        Some("certs") => Some(cli::certs(&args[1..])),
+        Some("keygen") => Some(cli::keygen(&args[1..])),
+        _ => None,
+    };
+    if let Some(result) = subcommand {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
 
+    let config = ServerConfig::from_env()?;
+    // Read before the runtime starts its threads, since this clears the
+    // variables from the environment.
+    let kek = KeyEncryptionKey::from_env()?;
+    actix_web::rt::System::new().block_on(run(config, kek))
+}
+
+async fn run(config: ServerConfig, kek: Option<KeyEncryptionKey>) -> std::io::Result<()> {
+    let transparency = match &config.transparency_log_path {
+        Some(path) => Some(Arc::new(TransparencyLog::open(path.clone())?)),
+        None => None,
+    };
+    let identity = web::Data::new(
+        IdentityStore::open(&config, kek)?.with_transparency_log(transparency.clone())?,
+    );
+    let identity_reload = identity.clone();
+
+    let authorized_keys = web::Data::new(
+        AuthorizedKeyStore::open(config.authorized_keys_path.clone())?
+            .with_transparency_log(transparency.clone())?,
+    );
+    let authorized_keys_reload = authorized_keys.clone();
+    let ticket_issuer = web::Data::new(TicketIssuer::new(Duration::from_secs(
+        config.ticket_lifetime_secs,
+    )));
+
+    // Pick up enrolled and removed client keys without a restart
+    let reload_interval = Duration::from_secs(config.authorized_keys_reload_secs);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(reload_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = authorized_keys_reload.reload_if_changed() {
+                log::error!("Failed to reload authorized keys: {}", e);
+            }
+        }
+    });
+
+    // Pick up a promoted or newly configured identity without a restart
+    let identity_reload_interval = Duration::from_secs(config.identity_reload_secs);
+    let identity_reconfigure = identity.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(identity_reload_interval);
+        loop {
+            interval.tick().await;
+            if let Err(e) = identity_reload.reload_if_changed() {
+                log::error!("Failed to reload server identity: {}", e);
+            }
+        }
+    });
+
+    // SIGHUP re-reads the config file for its identity settings
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let result = ServerConfig::from_env()
+                .and_then(|config| identity_reconfigure.reconfigure(&config));
+            match result {
+                Ok(true) => log::info!("Reloaded identity settings"),
+                Ok(false) => log::info!("Identity settings unchanged"),
+                Err(e) => log::error!("Failed to reload identity settings: {}", e),
+            }
+        }
+    });
+
     let session_manager = web::Data::new(SessionManager::new());
     let session_manager_cleanup = session_manager.clone();
 
+    let revocations = match (&config.revocation_list_path, &config.revocation_ca_path) {
+        (Some(path), Some(ca_path)) => Some(web::Data::new(
+            RevocationStore::open(path.clone(), CaPublicKey::load(ca_path)?)?
+                .with_transparency_log(transparency.clone())?,
+        )),
+        (None, _) => None,
+        (Some(_), None) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "revocation_list_path needs revocation_ca_path",
+            ))
+        }
+    };
+    if let Some(revocations) = revocations.clone() {
+        // Apply new revocations without a restart, ending live sessions
+        // that authenticated with a revoked key
+        let reload_interval = Duration::from_secs(config.revocation_reload_secs);
+        let session_manager = session_manager.clone();
+        let identity = identity.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reload_interval);
+            let mut first_check = true;
+            loop {
+                interval.tick().await;
+                match revocations.reload_if_changed() {
+                    Ok(changed) if changed || first_check => first_check = false,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        log::error!("Failed to reload revocation list: {}", e);
+                        continue;
+                    }
+                }
+                let terminated = session_manager.terminate_sessions(
+                    |session| {
+                        session
+                            .client_keys
+                            .iter()
+                            .any(|key| revocations.is_revoked(key))
+                    },
+                    "key revoked",
+                );
+                for client in terminated {
+                    log::warn!(
+                        "Terminated session {} for {} from {}: key revoked",
+                        client.id,
+                        client.username,
+                        client.ip
+                    );
+                }
+                let identity = identity.get();
+                for algorithm in identity.current.algorithms() {
+                    if revocations
+                        .is_revoked(identity.current.public_key(algorithm).unwrap_or_default())
+                    {
+                        log::error!("This server's own {} key is revoked", algorithm.as_str());
+                    }
+                }
+            }
+        });
+    }
+
     // Cleanup inactive sessions periodically
     tokio::spawn(async move {
         let cleanup_interval = Duration::from_secs(300); // 5 minutes
@@ -416,19 +1179,67 @@ async fn main() -> std::io::Result<()> {
 
         loop {
             tokio::time::sleep(cleanup_interval).await;
-            session_manager_cleanup.cleanup_inactive_sessions(session_timeout);
+            for client in session_manager_cleanup.cleanup_inactive_sessions(session_timeout) {
+                log::info!(
+                    "Ended idle session {} for {} from {}",
+                    client.id,
+                    client.username,
+                    client.ip
+                );
+            }
         }
     });
 
-    log::info!("Starting VPN server on 0.0.0.0:8000");
+    if config.listeners.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no listeners configured",
+        ));
+    }
+    let config = web::Data::new(config);
+    let listen_config = config.clone();
+    let transparency = transparency.map(web::Data::from);
 
-    HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
             .app_data(session_manager.clone())
+            .app_data(identity.clone())
+            .configure(|cfg| {
+                if let Some(revocations) = &revocations {
+                    cfg.app_data(revocations.clone());
+                }
+            })
+            .app_data(authorized_keys.clone())
+            .app_data(ticket_issuer.clone())
+            .app_data(config.clone())
             .wrap(middleware::Logger::default())
             .service(web::resource("/vpn").route(web::get().to(handle_ws_connection)))
-    })
-    .bind("0.0.0.0:8000")?
-    .run()
-    .await
+            .service(web::resource("/identity").route(web::get().to(identity_fingerprints)))
+            .configure(|cfg| {
+                if let Some(transparency) = &transparency {
+                    cfg.app_data(transparency.clone()).service(
+                        web::scope("/transparency")
+                            .route("/tree-head", web::get().to(transparency_tree_head))
+                            .route("/entries", web::get().to(transparency_entries))
+                            .route("/inclusion", web::get().to(transparency_inclusion))
+                            .route("/consistency", web::get().to(transparency_consistency)),
+                    );
+                }
+            })
+    });
+    for listener in &listen_config.listeners {
+        let suites: Vec<String> = listen_config
+            .suites(listener)
+            .iter()
+            .map(AlgorithmSuite::name)
+            .collect();
+        log::info!(
+            "Starting VPN server on {} (minimum level {}; {})",
+            listener.bind,
+            listen_config.min_security_level(listener, None),
+            suites.join(", ")
+        );
+        server = server.bind(listener.bind)?;
+    }
+    server.run().await
 }
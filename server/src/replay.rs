@@ -0,0 +1,95 @@
+use serde::Serialize;
+
+const WINDOW_WORDS: usize = 32;
+/// How far behind the highest accepted sequence number a frame may arrive
+/// and still be accepted. One word is kept spare so that advancing the
+/// window never clears bits that are still inside it.
+pub const WINDOW_SIZE: u64 = ((WINDOW_WORDS - 1) * 64) as u64;
+
+/// Why a frame was dropped by the replay window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayRejection {
+    /// The sequence number was already accepted.
+    Duplicate,
+    /// The sequence number fell off the back of the window.
+    TooOld,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct ReplayStats {
+    pub duplicates_dropped: u64,
+    pub too_old_dropped: u64,
+}
+
+/// Sliding-bitmap anti-replay window in the style of WireGuard and RFC 6479.
+///
+/// `check` may be called before the frame is authenticated, but `accept`
+/// and `record` only after, so forged frames can never move the window or
+/// inflate the stats.
+pub struct ReplayWindow {
+    highest: u64,
+    bitmap: [u64; WINDOW_WORDS],
+    stats: ReplayStats,
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            highest: 0,
+            bitmap: [0; WINDOW_WORDS],
+            stats: ReplayStats::default(),
+        }
+    }
+
+    pub fn check(&self, seq: u64) -> Result<(), ReplayRejection> {
+        if seq > self.highest {
+            return Ok(());
+        }
+        if self.highest - seq >= WINDOW_SIZE {
+            return Err(ReplayRejection::TooOld);
+        }
+        if self.bitmap[word_index(seq)] & bit(seq) != 0 {
+            return Err(ReplayRejection::Duplicate);
+        }
+        Ok(())
+    }
+
+    /// Counts an authentic frame that `check` rejected.
+    pub fn record(&mut self, rejection: ReplayRejection) {
+        match rejection {
+            ReplayRejection::Duplicate => self.stats.duplicates_dropped += 1,
+            ReplayRejection::TooOld => self.stats.too_old_dropped += 1,
+        }
+    }
+
+    pub fn accept(&mut self, seq: u64) {
+        if seq > self.highest {
+            let current = self.highest / 64;
+            let target = seq / 64;
+            let to_clear = (target - current).min(WINDOW_WORDS as u64);
+            for word in 1..=to_clear {
+                self.bitmap[((current + word) % WINDOW_WORDS as u64) as usize] = 0;
+            }
+            self.highest = seq;
+        }
+        self.bitmap[word_index(seq)] |= bit(seq);
+    }
+
+    pub fn stats(&self) -> ReplayStats {
+        self.stats
+    }
+}
+
+fn word_index(seq: u64) -> usize {
+    ((seq / 64) % WINDOW_WORDS as u64) as usize
+}
+
+fn bit(seq: u64) -> u64 {
+    1 << (seq % 64)
+}
@@ -0,0 +1,412 @@
+use crate::cipher::AeadAlgorithm;
+use crate::crypto::{
+    AlgorithmSuite, CryptoError, CryptoSession, Role, SessionKeys, SignatureAlgorithm, Transcript,
+};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::XChaCha20Poly1305;
+use hkdf::Hkdf;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+const TICKET_AAD: &[u8] = b"pqc-vpn ticket v1";
+const SEAL_NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Length of the ticket nonce and of both resumption nonces.
+const NONCE_LEN: usize = 32;
+
+/// Sent by the server once a session is established. The ticket itself is
+/// opaque to the client; the PSK it stands for is derived on both sides
+/// from the session's exporter and `ticket_nonce`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewSessionTicket {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub ticket_nonce: Vec<u8>,
+    pub ticket: Vec<u8>,
+    pub lifetime_secs: u64,
+}
+
+/// Sent by a reconnecting client instead of a `HandshakeRequest`. `binder`
+/// proves the client holds the ticket's PSK, so a captured ticket alone
+/// cannot be used or burned.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeRequest {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub ticket: Vec<u8>,
+    pub client_nonce: Vec<u8>,
+    pub binder: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeResponse {
+    #[serde(rename = "type")]
+    pub message_type: String,
+    pub suite: AlgorithmSuite,
+    pub aead: AeadAlgorithm,
+    pub server_nonce: Vec<u8>,
+}
+
+/// Who a ticket was issued to and what their original handshake
+/// negotiated. Returned to the server when the ticket is redeemed so the
+/// client can be re-authorized without another signature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TicketClaims {
+    pub username: String,
+    pub signature: SignatureAlgorithm,
+    pub client_signing_key: Vec<u8>,
+    /// The client's Ed25519 key, if it authenticated with dual signatures.
+    #[serde(default)]
+    pub client_ed25519_key: Option<Vec<u8>>,
+    pub suite: AlgorithmSuite,
+    pub aead: AeadAlgorithm,
+}
+
+impl TicketClaims {
+    /// Every public key the client authenticated with.
+    pub fn client_keys(&self) -> Vec<Vec<u8>> {
+        std::iter::once(self.client_signing_key.clone())
+            .chain(self.client_ed25519_key.clone())
+            .collect()
+    }
+}
+
+/// Ticket plaintext, sealed under the server's ticket key.
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct TicketContents {
+    ticket_id: Vec<u8>,
+    psk: Vec<u8>,
+    expires_at: u64,
+    #[zeroize(skip)]
+    claims: TicketClaims,
+}
+
+/// Issues and redeems resumption tickets.
+///
+/// Tickets are stateless: everything needed to resume travels inside the
+/// ticket, sealed with XChaCha20-Poly1305 under a key that only lives in
+/// memory, so a restart invalidates every outstanding ticket. Each ticket
+/// may be redeemed once; redeemed ticket IDs are remembered until the
+/// ticket would have expired anyway.
+pub struct TicketIssuer {
+    cipher: XChaCha20Poly1305,
+    lifetime: Duration,
+    redeemed: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl TicketIssuer {
+    pub fn new(lifetime: Duration) -> Self {
+        let mut key = Zeroizing::new([0u8; 32]);
+        rand::rngs::OsRng.fill_bytes(key.as_mut());
+        Self {
+            cipher: XChaCha20Poly1305::new(key.as_ref().into()),
+            lifetime,
+            redeemed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Seals a ticket for `session`, to be sent to the client over the
+    /// established connection.
+    pub fn issue(
+        &self,
+        session: &CryptoSession,
+        claims: TicketClaims,
+    ) -> Result<NewSessionTicket, CryptoError> {
+        let ticket_nonce = random_nonce();
+        let contents = TicketContents {
+            ticket_id: random_nonce(),
+            psk: ticket_psk(session, &ticket_nonce)?.to_vec(),
+            expires_at: unix_time() + self.lifetime.as_secs(),
+            claims,
+        };
+        let plaintext = Zeroizing::new(
+            serde_json::to_vec(&contents)
+                .map_err(|e| CryptoError::EncryptionError(e.to_string()))?,
+        );
+
+        let mut seal_nonce = [0u8; SEAL_NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut seal_nonce);
+        let sealed = self
+            .cipher
+            .encrypt(
+                &seal_nonce.into(),
+                Payload {
+                    msg: &plaintext,
+                    aad: TICKET_AAD,
+                },
+            )
+            .map_err(|e| CryptoError::EncryptionError(e.to_string()))?;
+        let mut ticket = seal_nonce.to_vec();
+        ticket.extend_from_slice(&sealed);
+
+        Ok(NewSessionTicket {
+            message_type: "new_session_ticket".to_string(),
+            ticket_nonce,
+            ticket,
+            lifetime_secs: self.lifetime.as_secs(),
+        })
+    }
+
+    /// Opens the ticket, checks its lifetime, binder and single use, and
+    /// derives a fresh server session from the ticket's PSK and both nonces.
+    pub fn redeem(
+        &self,
+        request: &ResumeRequest,
+    ) -> Result<(ResumeResponse, CryptoSession, TicketClaims), CryptoError> {
+        if request.ticket.len() < SEAL_NONCE_LEN + TAG_LEN {
+            return Err(CryptoError::TicketError("malformed ticket"));
+        }
+        if request.client_nonce.len() != NONCE_LEN {
+            return Err(CryptoError::TicketError("malformed client nonce"));
+        }
+        let (seal_nonce, sealed) = request.ticket.split_at(SEAL_NONCE_LEN);
+        let plaintext = Zeroizing::new(
+            self.cipher
+                .decrypt(
+                    seal_nonce.into(),
+                    Payload {
+                        msg: sealed,
+                        aad: TICKET_AAD,
+                    },
+                )
+                .map_err(|_| CryptoError::TicketError("unknown or corrupted ticket"))?,
+        );
+        let contents: TicketContents = serde_json::from_slice(&plaintext)
+            .map_err(|_| CryptoError::TicketError("unknown or corrupted ticket"))?;
+
+        let now = unix_time();
+        if now >= contents.expires_at {
+            return Err(CryptoError::TicketError("ticket expired"));
+        }
+        let psk: &[u8; 32] = contents
+            .psk
+            .as_slice()
+            .try_into()
+            .map_err(|_| CryptoError::TicketError("unknown or corrupted ticket"))?;
+        let expected = binder(psk, &request.ticket, &request.client_nonce)?;
+        if !bool::from(expected.ct_eq(&request.binder)) {
+            return Err(CryptoError::TicketError("binder mismatch"));
+        }
+
+        // Checked only after the binder so forged requests cannot burn a ticket.
+        {
+            let mut redeemed = self.redeemed.lock().unwrap();
+            redeemed.retain(|_, expires_at| *expires_at > now);
+            if redeemed.contains_key(&contents.ticket_id) {
+                return Err(CryptoError::TicketError("ticket already used"));
+            }
+            redeemed.insert(contents.ticket_id.clone(), contents.expires_at);
+        }
+
+        let response = ResumeResponse {
+            message_type: "resume_response".to_string(),
+            suite: contents.claims.suite,
+            aead: contents.claims.aead,
+            server_nonce: random_nonce(),
+        };
+        let keys = resumed_keys(psk, request, &response);
+        Ok((
+            response,
+            CryptoSession::new(&keys, Role::Server, contents.claims.aead),
+            contents.claims.clone(),
+        ))
+    }
+}
+
+/// The client's copy of a ticket together with the PSK it stands for.
+pub struct ClientTicket {
+    ticket: Vec<u8>,
+    psk: Zeroizing<[u8; 32]>,
+    aead: AeadAlgorithm,
+    expires_at: SystemTime,
+}
+
+impl fmt::Debug for ClientTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientTicket")
+            .field("aead", &self.aead)
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientTicket {
+    /// Stores a ticket received over `session`. Must be called while the
+    /// session is still alive, since the PSK comes from its exporter.
+    pub fn new(session: &CryptoSession, message: &NewSessionTicket) -> Result<Self, CryptoError> {
+        Ok(Self {
+            ticket: message.ticket.clone(),
+            psk: ticket_psk(session, &message.ticket_nonce)?,
+            aead: session.aead(),
+            expires_at: SystemTime::now() + Duration::from_secs(message.lifetime_secs),
+        })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.expires_at
+    }
+
+    pub fn resume_request(&self) -> Result<ResumeRequest, CryptoError> {
+        let client_nonce = random_nonce();
+        Ok(ResumeRequest {
+            message_type: "resume".to_string(),
+            binder: binder(&self.psk, &self.ticket, &client_nonce)?.to_vec(),
+            ticket: self.ticket.clone(),
+            client_nonce,
+        })
+    }
+
+    pub fn process_resume_response(
+        &self,
+        request: &ResumeRequest,
+        response: &ResumeResponse,
+    ) -> Result<CryptoSession, CryptoError> {
+        if response.aead != self.aead || response.server_nonce.len() != NONCE_LEN {
+            return Err(CryptoError::KeyExchangeError);
+        }
+        let keys = resumed_keys(&self.psk, request, response);
+        Ok(CryptoSession::new(&keys, Role::Client, response.aead))
+    }
+}
+
+/// Per-ticket PSK: exported from the session with the ticket nonce in the
+/// label, so each ticket issued on a session gets an unrelated key.
+fn ticket_psk(
+    session: &CryptoSession,
+    ticket_nonce: &[u8],
+) -> Result<Zeroizing<[u8; 32]>, CryptoError> {
+    if ticket_nonce.len() != NONCE_LEN {
+        return Err(CryptoError::TicketError("malformed ticket nonce"));
+    }
+    let mut label = b"pqc-vpn resumption ".to_vec();
+    label.extend_from_slice(ticket_nonce);
+    let mut psk = Zeroizing::new([0u8; 32]);
+    session.export_keying_material(&label, psk.as_mut())?;
+    Ok(psk)
+}
+
+fn binder(psk: &[u8; 32], ticket: &[u8], client_nonce: &[u8]) -> Result<[u8; 32], CryptoError> {
+    let mut transcript = Transcript::with_label(b"pqc-vpn resume binder v1");
+    transcript.append(ticket);
+    transcript.append(client_nonce);
+    let mut binder = [0u8; 32];
+    Hkdf::<Sha256>::from_prk(psk)
+        .map_err(|_| CryptoError::KeyExchangeError)?
+        .expand(&transcript.hash(), &mut binder)
+        .map_err(|_| CryptoError::KeyExchangeError)?;
+    Ok(binder)
+}
+
+/// Same key schedule as a full handshake, with the PSK as input keying
+/// material and a transcript of the resumption exchange as salt.
+fn resumed_keys(psk: &[u8; 32], request: &ResumeRequest, response: &ResumeResponse) -> SessionKeys {
+    let mut transcript = Transcript::with_label(b"pqc-vpn resume v1");
+    transcript.append(&request.ticket);
+    transcript.append(&request.client_nonce);
+    transcript.append(&request.binder);
+    transcript.append(response.suite.name().as_bytes());
+    transcript.append(response.aead.as_str().as_bytes());
+    transcript.append(&response.server_nonce);
+    SessionKeys::derive(psk, &transcript.hash())
+}
+
+fn random_nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server/client session pair as a full handshake would leave them.
+    fn established() -> (CryptoSession, CryptoSession) {
+        let keys = SessionKeys::derive(&[1; 32], &[2; 32]);
+        (
+            CryptoSession::new(&keys, Role::Server, AeadAlgorithm::ChaCha20Poly1305),
+            CryptoSession::new(&keys, Role::Client, AeadAlgorithm::ChaCha20Poly1305),
+        )
+    }
+
+    fn claims() -> TicketClaims {
+        TicketClaims {
+            username: "alice".to_string(),
+            signature: SignatureAlgorithm::MlDsa65,
+            client_signing_key: vec![3; 8],
+            client_ed25519_key: None,
+            suite: AlgorithmSuite::FIPS,
+            aead: AeadAlgorithm::ChaCha20Poly1305,
+        }
+    }
+
+    #[test]
+    fn ticket_resumes_once() {
+        let issuer = TicketIssuer::new(Duration::from_secs(60));
+        let (server, client) = established();
+        let message = issuer.issue(&server, claims()).unwrap();
+        let ticket = ClientTicket::new(&client, &message).unwrap();
+        drop((server, client));
+
+        let request = ticket.resume_request().unwrap();
+        let (response, mut server, claims) = issuer.redeem(&request).unwrap();
+        assert_eq!(claims.username, "alice");
+        assert_eq!(response.suite, AlgorithmSuite::FIPS);
+        let mut client = ticket.process_resume_response(&request, &response).unwrap();
+
+        let frame = client.encrypt(b"resumed").unwrap();
+        assert_eq!(server.decrypt(&frame).unwrap(), b"resumed");
+        let frame = server.encrypt(b"welcome back").unwrap();
+        assert_eq!(client.decrypt(&frame).unwrap(), b"welcome back");
+
+        // Replaying the request, or a fresh request on the same ticket, fails.
+        assert!(matches!(
+            issuer.redeem(&request),
+            Err(CryptoError::TicketError("ticket already used"))
+        ));
+        assert!(issuer.redeem(&ticket.resume_request().unwrap()).is_err());
+    }
+
+    #[test]
+    fn ticket_rejects_wrong_binder_expiry_and_foreign_issuer() {
+        let issuer = TicketIssuer::new(Duration::from_secs(60));
+        let (server, client) = established();
+        let ticket = ClientTicket::new(&client, &issuer.issue(&server, claims()).unwrap()).unwrap();
+
+        let mut request = ticket.resume_request().unwrap();
+        request.binder[0] ^= 1;
+        assert!(matches!(
+            issuer.redeem(&request),
+            Err(CryptoError::TicketError("binder mismatch"))
+        ));
+        // The forged attempt did not burn the ticket.
+        issuer.redeem(&ticket.resume_request().unwrap()).unwrap();
+
+        let other = TicketIssuer::new(Duration::from_secs(60));
+        let ticket = ClientTicket::new(&client, &issuer.issue(&server, claims()).unwrap()).unwrap();
+        assert!(other.redeem(&ticket.resume_request().unwrap()).is_err());
+
+        let expired = TicketIssuer::new(Duration::ZERO);
+        let ticket =
+            ClientTicket::new(&client, &expired.issue(&server, claims()).unwrap()).unwrap();
+        assert!(ticket.is_expired());
+        assert!(matches!(
+            expired.redeem(&ticket.resume_request().unwrap()),
+            Err(CryptoError::TicketError("ticket expired"))
+        ));
+    }
+}
@@ -0,0 +1,301 @@
+use crate::certs::{unix_time, CaPublicKey, CertificateAuthority};
+use crate::crypto::{CryptoError, SignatureAlgorithm};
+use crate::file_stamp::FileStamp;
+use crate::identity::fingerprint;
+use crate::transparency::TransparencyLog;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Domain separator for revocation list signatures, distinct from the one
+/// on certificates so the CA's two kinds of signature cannot be swapped.
+const REVOCATION_LABEL: &[u8] = b"pqc-vpn revocation list v1";
+
+/// Anything that can say whether a public key has been revoked: a single
+/// [`RevocationList`], or a [`RevocationStore`] that follows one on disk.
+pub trait Revocations: Send + Sync {
+    fn is_revoked(&self, public_key: &[u8]) -> bool;
+}
+
+/// One revoked key, named by the `sha256:` fingerprint `keygen show` and
+/// `/identity` print. Client and server keys share the list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RevokedKey {
+    pub fingerprint: String,
+    /// Unix seconds.
+    pub revoked_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Keys that must no longer be trusted, signed by the CA. `number` goes up
+/// with every list the CA issues, so an older list cannot be replayed over
+/// a newer one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevocationList {
+    pub number: u64,
+    /// Unix seconds.
+    pub issued_at: u64,
+    pub revoked: Vec<RevokedKey>,
+    /// Fingerprint of the issuing CA key.
+    pub issuer: String,
+    pub signature_algorithm: SignatureAlgorithm,
+    pub signature: Vec<u8>,
+    #[serde(skip)]
+    index: HashSet<String>,
+}
+
+/// The fields a revocation list signature covers, in a fixed order.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    number: u64,
+    issued_at: u64,
+    revoked: &'a [RevokedKey],
+    issuer: &'a str,
+    signature_algorithm: SignatureAlgorithm,
+}
+
+impl RevocationList {
+    /// Signs list number `number` over `revoked`.
+    pub fn issue(
+        ca: &CertificateAuthority,
+        number: u64,
+        revoked: Vec<RevokedKey>,
+    ) -> Result<Self, CryptoError> {
+        let public = ca.public();
+        let mut list = Self {
+            number,
+            issued_at: unix_time(),
+            revoked,
+            issuer: public.fingerprint(),
+            signature_algorithm: public.algorithm,
+            signature: Vec::new(),
+            index: HashSet::new(),
+        };
+        list.signature = ca.sign(&list.signed_bytes())?;
+        list.index();
+        Ok(list)
+    }
+
+    /// Reads a list and checks that `ca` signed it.
+    pub fn load(path: &Path, ca: &CaPublicKey) -> io::Result<Self> {
+        let mut list: Self = serde_json::from_slice(&fs::read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        list.verify(ca).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        list.index();
+        Ok(list)
+    }
+
+    /// Writes the list next to `path` and renames it into place, so a
+    /// server polling the file never reads half of it.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut staging = path.as_os_str().to_owned();
+        staging.push(".tmp");
+        fs::write(&staging, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&staging, path)
+    }
+
+    pub fn verify(&self, ca: &CaPublicKey) -> Result<(), CryptoError> {
+        if self.issuer != ca.fingerprint() || self.signature_algorithm != ca.algorithm {
+            return Err(CryptoError::SignatureError);
+        }
+        ca.verify_signature(&self.signed_bytes(), &self.signature)
+    }
+
+    fn index(&mut self) {
+        self.index = self
+            .revoked
+            .iter()
+            .map(|key| key.fingerprint.clone())
+            .collect();
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let fields = SignedFields {
+            number: self.number,
+            issued_at: self.issued_at,
+            revoked: &self.revoked,
+            issuer: &self.issuer,
+            signature_algorithm: self.signature_algorithm,
+        };
+        let mut bytes = REVOCATION_LABEL.to_vec();
+        bytes.extend(serde_json::to_vec(&fields).expect("revocation fields serialize"));
+        bytes
+    }
+}
+
+impl Revocations for RevocationList {
+    fn is_revoked(&self, public_key: &[u8]) -> bool {
+        self.index.contains(&fingerprint(public_key))
+    }
+}
+
+/// Shared, reloadable revocation list at one path.
+///
+/// Like [`AuthorizedKeyStore`](crate::authorized_keys::AuthorizedKeyStore)
+/// it only re-reads the file when it changes on disk. Unlike it,
+/// nothing here ever un-revokes a key by accident: a list that is missing,
+/// fails its signature check or is older than the one loaded leaves the
+/// loaded one in place.
+pub struct RevocationStore {
+    path: PathBuf,
+    ca: CaPublicKey,
+    state: RwLock<(Option<Arc<RevocationList>>, Option<FileStamp>)>,
+    transparency: Option<Arc<TransparencyLog>>,
+}
+
+impl RevocationStore {
+    /// Loads the list at `path`, which must be signed by `ca`. A missing
+    /// file revokes nothing until it is created.
+    pub fn open(path: PathBuf, ca: CaPublicKey) -> io::Result<Self> {
+        let store = Self {
+            path,
+            ca,
+            state: RwLock::new((None, None)),
+            transparency: None,
+        };
+        store.reload_if_changed()?;
+        Ok(store)
+    }
+
+    /// Logs the loaded list's keys to `log`, and from then on every newly
+    /// loaded list's.
+    pub fn with_transparency_log(mut self, log: Option<Arc<TransparencyLog>>) -> io::Result<Self> {
+        if let (Some(log), Some(list)) = (&log, self.get()) {
+            log.record_revocations(&list)?;
+        }
+        self.transparency = log;
+        Ok(self)
+    }
+
+    pub fn get(&self) -> Option<Arc<RevocationList>> {
+        self.state.read().unwrap().0.clone()
+    }
+
+    /// Re-reads the list if it changed on disk. Returns whether a new list
+    /// was loaded.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let stamp = FileStamp::read(&self.path)?;
+        let (current, last_stamp) = self.state.read().unwrap().clone();
+        if stamp.is_none() || stamp == last_stamp {
+            return Ok(false);
+        }
+        let list = RevocationList::load(&self.path, &self.ca)?;
+        if let Some(current) = current.filter(|current| current.number > list.number) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is list {}, older than the loaded list {}",
+                    self.path.display(),
+                    list.number,
+                    current.number
+                ),
+            ));
+        }
+        log::info!(
+            "Loaded revocation list {} with {} revoked key(s) from {}",
+            list.number,
+            list.revoked.len(),
+            self.path.display()
+        );
+        let list = Arc::new(list);
+        *self.state.write().unwrap() = (Some(list.clone()), stamp);
+        // Unlike enrollments, a revocation takes effect even if it cannot
+        // be logged; the next list logs whatever this one missed.
+        if let Some(log) = &self.transparency {
+            log.record_revocations(&list)?;
+        }
+        Ok(true)
+    }
+}
+
+impl Revocations for RevocationStore {
+    fn is_revoked(&self, public_key: &[u8]) -> bool {
+        self.get()
+            .is_some_and(|revocations| revocations.is_revoked(public_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KeyExchange;
+    use crate::identity::SigningIdentity;
+
+    #[test]
+    fn revoked_keys_are_refused_and_lists_only_move_forward() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("revoked.json");
+        let ca = CertificateAuthority::generate(SignatureAlgorithm::Dilithium3);
+        let laptop = SigningIdentity::generate();
+        let laptop_key = laptop.public_key(SignatureAlgorithm::Dilithium2).unwrap();
+
+        let store = RevocationStore::open(path.clone(), ca.public()).unwrap();
+        assert!(store.get().is_none());
+        RevocationList::issue(&ca, 1, Vec::new())
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert!(!store.is_revoked(laptop_key));
+
+        let revoked = vec![RevokedKey {
+            fingerprint: fingerprint(laptop_key),
+            revoked_at: unix_time(),
+            reason: Some("stolen laptop".to_string()),
+        }];
+        let list = RevocationList::issue(&ca, 2, revoked.clone()).unwrap();
+        list.save(&path).unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert!(store.is_revoked(laptop_key));
+
+        // The revoked key no longer verifies, even with a valid signature.
+        let signature = laptop
+            .sign(SignatureAlgorithm::Dilithium2, b"client key")
+            .unwrap();
+        let store = Arc::new(store);
+        let key_exchange = KeyExchange::new().with_revocations(Some(store.clone()));
+        assert!(matches!(
+            key_exchange.verify_client_signature(
+                SignatureAlgorithm::Dilithium2,
+                b"client key",
+                &signature,
+                laptop_key
+            ),
+            Err(CryptoError::RevokedKeyError(_))
+        ));
+
+        // Replaying an older list, forging one, or deleting the file all
+        // leave the key revoked.
+        RevocationList::issue(&ca, 1, Vec::new())
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        assert!(store.reload_if_changed().is_err());
+        let other_ca = CertificateAuthority::generate(SignatureAlgorithm::Dilithium3);
+        RevocationList::issue(&other_ca, 3, Vec::new())
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        assert!(store.reload_if_changed().is_err());
+        let mut tampered = list.clone();
+        tampered.revoked.clear();
+        tampered.number = 3;
+        tampered.save(&path).unwrap();
+        assert!(store.reload_if_changed().is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(!store.reload_if_changed().unwrap());
+        assert!(store.is_revoked(laptop_key));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
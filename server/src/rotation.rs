@@ -0,0 +1,390 @@
+use crate::certs::{unix_time, ServerCertificate};
+use crate::config::ServerConfig;
+use crate::crypto::{endorsement_bytes, CryptoError, NextSigningKey, SignatureAlgorithm};
+use crate::file_stamp::FileStamp;
+use crate::identity::SigningIdentity;
+use crate::keystore::KeyEncryptionKey;
+use crate::transparency::TransparencyLog;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// The identity a server is rotating to, with each of its public keys
+/// endorsed by the current identity's key for the same algorithm.
+#[derive(Debug)]
+pub struct NextIdentity {
+    pub identity: Arc<SigningIdentity>,
+    pub keys: BTreeMap<SignatureAlgorithm, NextSigningKey>,
+}
+
+impl NextIdentity {
+    /// Signs each of `next`'s keys with `current`'s key for the same
+    /// algorithm. Algorithms `current` has no key for are left out.
+    pub fn endorse(
+        current: &SigningIdentity,
+        next: Arc<SigningIdentity>,
+    ) -> Result<Self, CryptoError> {
+        let mut keys = BTreeMap::new();
+        for algorithm in next.algorithms() {
+            if current.public_key(algorithm).is_none() {
+                continue;
+            }
+            let public_key = next.public_key(algorithm).unwrap_or_default().to_vec();
+            let endorsement =
+                current.sign(algorithm, &endorsement_bytes(algorithm, &public_key))?;
+            keys.insert(
+                algorithm,
+                NextSigningKey {
+                    public_key,
+                    endorsement,
+                },
+            );
+        }
+        Ok(Self {
+            identity: next,
+            keys,
+        })
+    }
+}
+
+/// Everything a handshake needs to authenticate the server, as of the last
+/// successful load.
+#[derive(Debug)]
+pub struct ServerIdentity {
+    pub current: Arc<SigningIdentity>,
+    pub next: Option<Arc<NextIdentity>>,
+    pub certificate: Option<Arc<ServerCertificate>>,
+}
+
+/// Where one identity is read from.
+#[derive(Debug, Clone, PartialEq)]
+enum IdentitySource {
+    File(PathBuf),
+    KeyFiles(Vec<PathBuf>),
+}
+
+impl IdentitySource {
+    fn paths(&self) -> &[PathBuf] {
+        match self {
+            IdentitySource::File(path) => std::slice::from_ref(path),
+            IdentitySource::KeyFiles(paths) => paths,
+        }
+    }
+
+    fn load(
+        &self,
+        algorithms: &[SignatureAlgorithm],
+        kek: Option<&KeyEncryptionKey>,
+    ) -> io::Result<SigningIdentity> {
+        match self {
+            IdentitySource::File(path) => SigningIdentity::load_or_generate(path, algorithms, kek),
+            IdentitySource::KeyFiles(paths) => {
+                SigningIdentity::load_key_files(paths, algorithms, kek)
+            }
+        }
+    }
+}
+
+/// The identity-related settings of a [`ServerConfig`].
+#[derive(Debug, Clone, PartialEq)]
+struct IdentitySources {
+    current: IdentitySource,
+    next: Option<IdentitySource>,
+    certificate: Option<PathBuf>,
+    algorithms: Vec<SignatureAlgorithm>,
+}
+
+impl IdentitySources {
+    fn from_config(config: &ServerConfig) -> Self {
+        let current = if config.identity_key_files.is_empty() {
+            IdentitySource::File(config.identity_key_path.clone())
+        } else {
+            IdentitySource::KeyFiles(config.identity_key_files.clone())
+        };
+        let next = if !config.next_identity_key_files.is_empty() {
+            Some(IdentitySource::KeyFiles(
+                config.next_identity_key_files.clone(),
+            ))
+        } else {
+            config
+                .next_identity_key_path
+                .clone()
+                .map(IdentitySource::File)
+        };
+        Self {
+            current,
+            next,
+            certificate: config.certificate_path.clone(),
+            algorithms: config.signature_algorithms(),
+        }
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.current
+            .paths()
+            .iter()
+            .chain(self.next.iter().flat_map(IdentitySource::paths))
+            .chain(&self.certificate)
+    }
+}
+
+struct StoreState {
+    sources: IdentitySources,
+    identity: Arc<ServerIdentity>,
+    stamps: Vec<Option<FileStamp>>,
+}
+
+/// Shared, reloadable server identity: the current signing keys, the next
+/// ones during a rotation, and the certificate.
+///
+/// Like [`AuthorizedKeyStore`](crate::authorized_keys::AuthorizedKeyStore),
+/// `reload_if_changed` only re-reads the files when one of them has
+/// changed, and a load that fails leaves the previous identity in place.
+/// Handshakes already under way keep the identity they started with.
+pub struct IdentityStore {
+    kek: Option<KeyEncryptionKey>,
+    state: RwLock<StoreState>,
+    transparency: Option<Arc<TransparencyLog>>,
+}
+
+impl IdentityStore {
+    /// Loads the identities and certificate `config` names. `kek` is kept
+    /// to open the key files again on reload.
+    pub fn open(config: &ServerConfig, kek: Option<KeyEncryptionKey>) -> io::Result<Self> {
+        let sources = IdentitySources::from_config(config);
+        let identity = load(&sources, kek.as_ref())?;
+        let stamps = source_stamps(&sources)?;
+        Ok(Self {
+            kek,
+            state: RwLock::new(StoreState {
+                sources,
+                identity: Arc::new(identity),
+                stamps,
+            }),
+            transparency: None,
+        })
+    }
+
+    /// Logs the loaded keys to `log`, and from then on every reload's keys
+    /// before the server signs with them. A reload that cannot be logged
+    /// is refused.
+    pub fn with_transparency_log(mut self, log: Option<Arc<TransparencyLog>>) -> io::Result<Self> {
+        if let Some(log) = &log {
+            log.record_server_identity(&self.get())?;
+        }
+        self.transparency = log;
+        Ok(self)
+    }
+
+    pub fn get(&self) -> Arc<ServerIdentity> {
+        self.state.read().unwrap().identity.clone()
+    }
+
+    /// Re-reads the identities if any of their files changed on disk.
+    /// Returns whether they did.
+    pub fn reload_if_changed(&self) -> io::Result<bool> {
+        let sources = self.state.read().unwrap().sources.clone();
+        self.reload(sources, false)
+    }
+
+    /// Switches to the identity settings of a freshly read `config`, e.g.
+    /// on SIGHUP. Returns whether anything was reloaded.
+    pub fn reconfigure(&self, config: &ServerConfig) -> io::Result<bool> {
+        let sources = IdentitySources::from_config(config);
+        let changed = sources != self.state.read().unwrap().sources;
+        self.reload(sources, changed)
+    }
+
+    fn reload(&self, sources: IdentitySources, force: bool) -> io::Result<bool> {
+        let stamps = source_stamps(&sources)?;
+        if !force && stamps == self.state.read().unwrap().stamps {
+            return Ok(false);
+        }
+        let identity = load(&sources, self.kek.as_ref())?;
+        if let Some(log) = &self.transparency {
+            log.record_server_identity(&identity)?;
+        }
+        // Loading may generate or extend an identity file, which is not a
+        // change worth another reload.
+        let stamps = source_stamps(&sources)?;
+        *self.state.write().unwrap() = StoreState {
+            sources,
+            identity: Arc::new(identity),
+            stamps,
+        };
+        Ok(true)
+    }
+}
+
+fn load(sources: &IdentitySources, kek: Option<&KeyEncryptionKey>) -> io::Result<ServerIdentity> {
+    let current = Arc::new(sources.current.load(&sources.algorithms, kek)?);
+    let next = match &sources.next {
+        Some(source) => {
+            let next = Arc::new(source.load(&sources.algorithms, kek)?);
+            let next = NextIdentity::endorse(&current, next)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Some(Arc::new(next))
+        }
+        None => None,
+    };
+    let certificate = match &sources.certificate {
+        Some(path) => Some(Arc::new(load_certificate(path, &current)?)),
+        None => None,
+    };
+    log_fingerprints("Server identity", &current);
+    if let Some(next) = &next {
+        log_fingerprints("Next server identity", &next.identity);
+    }
+    Ok(ServerIdentity {
+        current,
+        next,
+        certificate,
+    })
+}
+
+fn log_fingerprints(label: &str, identity: &SigningIdentity) {
+    for algorithm in identity.algorithms() {
+        if let Some(fingerprint) = identity.fingerprint(algorithm) {
+            log::info!("{} {}: {}", label, algorithm.as_str(), fingerprint);
+        }
+    }
+}
+
+fn load_certificate(path: &Path, identity: &SigningIdentity) -> io::Result<ServerCertificate> {
+    let certificate = ServerCertificate::load(path)?;
+    if !certificate.matches(identity) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} does not certify this server's identity", path.display()),
+        ));
+    }
+    if certificate.not_after <= unix_time() {
+        log::warn!("Server certificate {} has expired", certificate.serial);
+    }
+    log::info!(
+        "Presenting certificate {} for {} from CA {}",
+        certificate.serial,
+        certificate.hostname,
+        certificate.issuer
+    );
+    Ok(certificate)
+}
+
+/// Stamp of each source file, `None` for any that is missing. `promote`
+/// renames the next identity over the current one, keeping its
+/// modification time, so only the inode may tell them apart.
+fn source_stamps(sources: &IdentitySources) -> io::Result<Vec<Option<FileStamp>>> {
+    sources.paths().map(|path| FileStamp::read(path)).collect()
+}
+
+/// Makes the identity file at `next` the current one at `current`. The
+/// outgoing identity is kept at `<current>.retired`. A server polling
+/// these paths switches over, and generates a fresh next identity if
+/// `next_identity_key_path` still names `next`.
+pub fn promote(current: &Path, next: &Path) -> io::Result<PathBuf> {
+    if !next.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no next identity at {}", next.display()),
+        ));
+    }
+    let mut retired = current.as_os_str().to_owned();
+    retired.push(".retired");
+    let retired = PathBuf::from(retired);
+    fs::copy(current, &retired)?;
+    fs::rename(next, current)?;
+    Ok(retired)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn promoting_the_next_identity_rotates_the_store() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let config = ServerConfig {
+            identity_key_path: dir.join("identity.json"),
+            next_identity_key_path: Some(dir.join("next.json")),
+            ..ServerConfig::default()
+        };
+        let store = IdentityStore::open(&config, None).unwrap();
+        let before = store.get();
+        let next = before.next.as_ref().unwrap();
+        let next_key = &next.keys[&SignatureAlgorithm::MlDsa65];
+        assert_ne!(
+            Some(next_key.public_key.as_slice()),
+            before.current.public_key(SignatureAlgorithm::MlDsa65)
+        );
+        assert!(!store.reload_if_changed().unwrap());
+
+        promote(&config.identity_key_path, &dir.join("next.json")).unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        let after = store.get();
+        assert_eq!(
+            after.current.public_key(SignatureAlgorithm::MlDsa65),
+            Some(next_key.public_key.as_slice())
+        );
+        // A fresh next identity takes the promoted one's place.
+        let fresh = &after.next.as_ref().unwrap().keys[&SignatureAlgorithm::MlDsa65];
+        assert_ne!(fresh.public_key, next_key.public_key);
+        assert!(dir.join("identity.json.retired").is_file());
+
+        // Dropping the next identity from the config ends the overlap.
+        let config = ServerConfig {
+            next_identity_key_path: None,
+            ..config
+        };
+        assert!(store.reconfigure(&config).unwrap());
+        assert!(store.get().next.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn promoting_an_identity_with_the_same_mtime_is_reloaded() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let config = ServerConfig {
+            identity_key_path: dir.join("identity.json"),
+            next_identity_key_path: None,
+            ..ServerConfig::default()
+        };
+        let store = IdentityStore::open(&config, None).unwrap();
+        let before = store.get();
+
+        // Staged outside the store's sources, and carrying the current
+        // file's modification time, as a promotion in the same tick would.
+        let staged = dir.join("staged.json");
+        IdentityStore::open(
+            &ServerConfig {
+                identity_key_path: staged.clone(),
+                next_identity_key_path: None,
+                ..ServerConfig::default()
+            },
+            None,
+        )
+        .unwrap();
+        let modified = fs::metadata(&config.identity_key_path)
+            .unwrap()
+            .modified()
+            .unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&staged)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        promote(&config.identity_key_path, &staged).unwrap();
+        assert!(store.reload_if_changed().unwrap());
+        assert_ne!(
+            store.get().current.public_key(SignatureAlgorithm::MlDsa65),
+            before.current.public_key(SignatureAlgorithm::MlDsa65)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
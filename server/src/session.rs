@@ -1,18 +1,27 @@
-use crate::crypto::{CryptoError, CryptoSession};
-use actix_ws::Session;
+use crate::cipher::AeadAlgorithm;
+use crate::crypto::{CryptoError, CryptoSession, Frame};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
+use tokio::sync::watch;
 use uuid::Uuid;
 
+/// Held by the connection serving a session. Changes to `Some(reason)` when
+/// the server ends the session, and reports the sender gone if the session
+/// is removed some other way.
+pub type Termination = watch::Receiver<Option<String>>;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ClientInfo {
     pub id: String,
     pub ip: String,
+    pub username: String,
     pub connected_at: SystemTime,
+    /// When the session last carried an authenticated frame.
+    pub last_active: SystemTime,
     pub bytes_sent: u64,
     pub bytes_received: u64,
 }
@@ -20,8 +29,22 @@ pub struct ClientInfo {
 pub struct VpnSession {
     pub id: String,
     pub client_info: ClientInfo,
+    pub aead: AeadAlgorithm,
     pub crypto: CryptoSession,
-    pub ws: Session,
+    /// Public keys the client authenticated with, so the session can be
+    /// ended if one is revoked.
+    pub client_keys: Vec<Vec<u8>>,
+    terminate: watch::Sender<Option<String>>,
+}
+
+impl VpnSession {
+    /// Decrypts a traffic frame, counting it as activity once it
+    /// authenticates so a busy session is never reaped as idle.
+    pub fn decrypt_frame(&mut self, frame: &[u8]) -> Result<Frame, CryptoError> {
+        let frame = self.crypto.decrypt_frame(frame)?;
+        self.client_info.last_active = SystemTime::now();
+        Ok(frame)
+    }
 }
 
 #[derive(Clone)]
@@ -29,6 +52,12 @@ pub struct SessionManager {
     sessions: Arc<Mutex<HashMap<String, VpnSession>>>,
 }
 
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SessionManager {
     pub fn new() -> Self {
         Self {
@@ -36,41 +65,72 @@ impl SessionManager {
         }
     }
 
+    /// Adds a session and returns its id, plus the signal its connection
+    /// must watch to stop serving it.
     pub fn create_session(
         &self,
         ip: String,
-        shared_key: Vec<u8>,
-        ws: Session,
-    ) -> Result<String, CryptoError> {
+        username: String,
+        crypto: CryptoSession,
+        client_keys: Vec<Vec<u8>>,
+    ) -> (String, Termination) {
         let id = Uuid::new_v4().to_string();
 
+        let now = SystemTime::now();
         let client_info = ClientInfo {
             id: id.clone(),
             ip,
-            connected_at: SystemTime::now(),
+            username,
+            connected_at: now,
+            last_active: now,
             bytes_sent: 0,
             bytes_received: 0,
         };
 
+        let (terminate, termination) = watch::channel(None);
         let session = VpnSession {
             id: id.clone(),
             client_info,
-            crypto: CryptoSession::new(shared_key)?,
-            ws,
+            aead: crypto.aead(),
+            crypto,
+            client_keys,
+            terminate,
         };
 
         self.sessions.lock().unwrap().insert(id.clone(), session);
-        Ok(id)
+        (id, termination)
     }
 
-    pub fn get_session(&self, id: &str) -> Option<VpnSession> {
-        self.sessions.lock().unwrap().remove(id)
+    /// Runs `f` against the session while holding the lock, so the session
+    /// and its crypto state stay in the map.
+    pub fn with_session<R>(&self, id: &str, f: impl FnOnce(&mut VpnSession) -> R) -> Option<R> {
+        self.sessions.lock().unwrap().get_mut(id).map(f)
     }
 
     pub fn remove_session(&self, id: &str) {
         self.sessions.lock().unwrap().remove(id);
     }
 
+    /// Removes every session `predicate` picks and signals its connection
+    /// to close with `reason`, e.g. once a key it authenticated with is
+    /// revoked. Returns their client details.
+    pub fn terminate_sessions(
+        &self,
+        predicate: impl Fn(&VpnSession) -> bool,
+        reason: &str,
+    ) -> Vec<ClientInfo> {
+        let mut terminated = Vec::new();
+        self.sessions.lock().unwrap().retain(|_, session| {
+            if !predicate(session) {
+                return true;
+            }
+            session.terminate.send_replace(Some(reason.to_string()));
+            terminated.push(session.client_info.clone());
+            false
+        });
+        terminated
+    }
+
     pub fn list_sessions(&self) -> Vec<ClientInfo> {
         self.sessions
             .lock()
@@ -80,12 +140,111 @@ impl SessionManager {
             .collect()
     }
 
-    pub fn cleanup_inactive_sessions(&self, timeout: Duration) {
+    /// Ends sessions that have carried no authenticated frame for
+    /// `timeout`, however long ago they connected.
+    pub fn cleanup_inactive_sessions(&self, timeout: Duration) -> Vec<ClientInfo> {
         let now = SystemTime::now();
-        self.sessions.lock().unwrap().retain(|_, session| {
-            now.duration_since(session.client_info.connected_at)
-                .unwrap_or_default()
-                < timeout
-        });
+        self.terminate_sessions(
+            |session| {
+                now.duration_since(session.client_info.last_active)
+                    .unwrap_or_default()
+                    >= timeout
+            },
+            "session idle",
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Role, SessionKeys};
+
+    #[test]
+    fn terminated_sessions_signal_their_connection_and_stop_decrypting() {
+        let keys = SessionKeys::derive(b"shared secret", &[0; 32]);
+        let mut client = CryptoSession::new(&keys, Role::Client, AeadAlgorithm::Aes256Gcm);
+        let manager = SessionManager::new();
+        let (revoked, revoked_termination) = manager.create_session(
+            "127.0.0.1:1".to_string(),
+            "alice".to_string(),
+            CryptoSession::new(&keys, Role::Server, AeadAlgorithm::Aes256Gcm),
+            vec![b"stolen key".to_vec()],
+        );
+        let (kept, kept_termination) = manager.create_session(
+            "127.0.0.1:2".to_string(),
+            "bob".to_string(),
+            CryptoSession::new(&keys, Role::Server, AeadAlgorithm::Aes256Gcm),
+            vec![b"good key".to_vec()],
+        );
+
+        let frame = client.encrypt(b"before").unwrap();
+        assert!(matches!(
+            manager.with_session(&revoked, |session| session.crypto.decrypt(&frame)),
+            Some(Ok(_))
+        ));
+
+        let terminated = manager.terminate_sessions(
+            |session| session.client_keys.contains(&b"stolen key".to_vec()),
+            "key revoked",
+        );
+        assert_eq!(terminated.len(), 1);
+        assert_eq!(terminated[0].username, "alice");
+        assert_eq!(revoked_termination.borrow().as_deref(), Some("key revoked"));
+        assert!(!kept_termination.has_changed().unwrap());
+
+        // The next request on the revoked session finds nothing to serve it.
+        let frame = client.encrypt(b"after").unwrap();
+        assert!(manager
+            .with_session(&revoked, |session| session.crypto.decrypt(&frame))
+            .is_none());
+        assert!(manager.with_session(&kept, |_| ()).is_some());
+    }
+
+    #[test]
+    fn cleanup_keeps_long_lived_sessions_that_are_still_active() {
+        let keys = SessionKeys::derive(b"shared secret", &[0; 32]);
+        let mut client = CryptoSession::new(&keys, Role::Client, AeadAlgorithm::Aes256Gcm);
+        let manager = SessionManager::new();
+        let (active, active_termination) = manager.create_session(
+            "127.0.0.1:1".to_string(),
+            "alice".to_string(),
+            CryptoSession::new(&keys, Role::Server, AeadAlgorithm::Aes256Gcm),
+            Vec::new(),
+        );
+        let (idle, idle_termination) = manager.create_session(
+            "127.0.0.1:2".to_string(),
+            "bob".to_string(),
+            CryptoSession::new(&keys, Role::Server, AeadAlgorithm::Aes256Gcm),
+            Vec::new(),
+        );
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 3600);
+        for id in [&active, &idle] {
+            manager.with_session(id, |session| {
+                session.client_info.connected_at = two_hours_ago;
+                session.client_info.last_active = two_hours_ago;
+            });
+        }
+
+        // A forged frame is not activity; an authenticated one is.
+        assert!(manager
+            .with_session(&active, |session| session.decrypt_frame(b"forged"))
+            .unwrap()
+            .is_err());
+        let frame = client
+            .encrypt_frame(crate::crypto::FrameType::Data, 0, b"still here")
+            .unwrap();
+        assert!(manager
+            .with_session(&active, |session| session.decrypt_frame(&frame))
+            .unwrap()
+            .is_ok());
+
+        let expired = manager.cleanup_inactive_sessions(Duration::from_secs(3600));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].username, "bob");
+        assert!(manager.with_session(&idle, |_| ()).is_none());
+        assert_eq!(idle_termination.borrow().as_deref(), Some("session idle"));
+        assert!(manager.with_session(&active, |_| ()).is_some());
+        assert!(!active_termination.has_changed().unwrap());
     }
 }
@@ -0,0 +1,607 @@
+use crate::authorized_keys::AuthorizedKeys;
+use crate::certs::unix_time;
+use crate::crypto::{signer_for, CryptoError, SignatureAlgorithm};
+use crate::identity::{fingerprint, SigningIdentity};
+use crate::revocation::RevocationList;
+use crate::rotation::ServerIdentity;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+/// Domain separator for tree head signatures, distinct from the handshake
+/// transcript so one can never be passed off as the other.
+const TREE_HEAD_LABEL: &[u8] = b"pqc-vpn tree head v1";
+
+/// A SHA-256 node of the log's Merkle tree.
+pub type NodeHash = [u8; 32];
+
+/// Why a key was logged.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum KeyEvent {
+    /// A client key was enrolled in `authorized_keys` for `username`.
+    Enrolled { username: String },
+    /// The CA's revocation list named the key.
+    Revoked { reason: Option<String> },
+    /// The server started signing handshakes with the key.
+    ServerKey { role: ServerKeyRole },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerKeyRole {
+    Current,
+    /// Published alongside the current key during a rotation.
+    Next,
+}
+
+/// One leaf of the log.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Unix seconds.
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub event: KeyEvent,
+    /// Revocation lists name keys by fingerprint alone, so revocations
+    /// carry no algorithm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<SignatureAlgorithm>,
+    pub fingerprint: String,
+}
+
+impl LogEntry {
+    /// The leaf hash of this entry: its JSON encoding, as stored in the
+    /// log file and served by the entries endpoint.
+    pub fn leaf_hash(&self) -> NodeHash {
+        leaf_hash(&serde_json::to_vec(self).expect("log entries serialize"))
+    }
+}
+
+/// The root of the log at `tree_size` entries, signed by the server with
+/// every key it holds so clients can check it against the key they pin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedTreeHead {
+    pub tree_size: u64,
+    /// Unix seconds.
+    pub timestamp: u64,
+    pub root_hash: NodeHash,
+    pub signatures: BTreeMap<SignatureAlgorithm, Vec<u8>>,
+}
+
+/// The fields a tree head signature covers, in a fixed order.
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    tree_size: u64,
+    timestamp: u64,
+    root_hash: &'a NodeHash,
+}
+
+impl SignedTreeHead {
+    pub fn sign(
+        identity: &SigningIdentity,
+        tree_size: u64,
+        root_hash: NodeHash,
+    ) -> Result<Self, CryptoError> {
+        let mut head = Self {
+            tree_size,
+            timestamp: unix_time(),
+            root_hash,
+            signatures: BTreeMap::new(),
+        };
+        let signed = head.signed_bytes();
+        for algorithm in identity.algorithms() {
+            head.signatures
+                .insert(algorithm, identity.sign(algorithm, &signed)?);
+        }
+        Ok(head)
+    }
+
+    /// Checks the signature for `algorithm` against the server key a
+    /// client pinned.
+    pub fn verify(
+        &self,
+        algorithm: SignatureAlgorithm,
+        public_key: &[u8],
+    ) -> Result<(), CryptoError> {
+        let signature = self
+            .signatures
+            .get(&algorithm)
+            .ok_or(CryptoError::SignatureError)?;
+        signer_for(algorithm).verify(&self.signed_bytes(), signature, public_key)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let fields = SignedFields {
+            tree_size: self.tree_size,
+            timestamp: self.timestamp,
+            root_hash: &self.root_hash,
+        };
+        let mut bytes = TREE_HEAD_LABEL.to_vec();
+        bytes.extend(serde_json::to_vec(&fields).expect("tree head fields serialize"));
+        bytes
+    }
+}
+
+/// Proof that entry `leaf_index` is in the tree of `tree_size` entries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    pub audit_path: Vec<NodeHash>,
+}
+
+impl InclusionProof {
+    /// Whether `entry` sits at `leaf_index` under `root_hash`, following
+    /// RFC 9162 section 2.1.3.2.
+    pub fn verify(&self, entry: &LogEntry, root_hash: &NodeHash) -> bool {
+        if self.leaf_index >= self.tree_size {
+            return false;
+        }
+        let (mut fn_, mut sn) = (self.leaf_index, self.tree_size - 1);
+        let mut r = entry.leaf_hash();
+        for p in &self.audit_path {
+            if sn == 0 {
+                return false;
+            }
+            if fn_ & 1 == 1 || fn_ == sn {
+                r = node_hash(p, &r);
+                while fn_ & 1 == 0 && fn_ != 0 {
+                    fn_ >>= 1;
+                    sn >>= 1;
+                }
+            } else {
+                r = node_hash(&r, p);
+            }
+            fn_ >>= 1;
+            sn >>= 1;
+        }
+        sn == 0 && &r == root_hash
+    }
+}
+
+/// Proof that the tree of `first_size` entries is a prefix of the tree of
+/// `second_size` entries, i.e. that nothing logged was changed or dropped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub first_size: u64,
+    pub second_size: u64,
+    pub proof: Vec<NodeHash>,
+}
+
+impl ConsistencyProof {
+    /// Whether `second_root` extends `first_root`, following RFC 9162
+    /// section 2.1.4.2. Every tree extends the empty one.
+    pub fn verify(&self, first_root: &NodeHash, second_root: &NodeHash) -> bool {
+        let (first, second) = (self.first_size, self.second_size);
+        if first > second {
+            return false;
+        }
+        if first == 0 {
+            return self.proof.is_empty();
+        }
+        if first == second {
+            return self.proof.is_empty() && first_root == second_root;
+        }
+        // A first tree of 2^k leaves is a subtree of the second, so its
+        // root starts the path rather than being sent.
+        let mut path: Vec<&NodeHash> = Vec::new();
+        if first.is_power_of_two() {
+            path.push(first_root);
+        }
+        path.extend(&self.proof);
+        let Some((&start, rest)) = path.split_first() else {
+            return false;
+        };
+
+        let (mut fn_, mut sn) = (first - 1, second - 1);
+        while fn_ & 1 == 1 {
+            fn_ >>= 1;
+            sn >>= 1;
+        }
+        let (mut fr, mut sr) = (*start, *start);
+        for c in rest {
+            if sn == 0 {
+                return false;
+            }
+            if fn_ & 1 == 1 || fn_ == sn {
+                fr = node_hash(c, &fr);
+                sr = node_hash(c, &sr);
+                while fn_ & 1 == 0 && fn_ != 0 {
+                    fn_ >>= 1;
+                    sn >>= 1;
+                }
+            } else {
+                sr = node_hash(&sr, c);
+            }
+            fn_ >>= 1;
+            sn >>= 1;
+        }
+        sn == 0 && &fr == first_root && &sr == second_root
+    }
+}
+
+#[derive(Default)]
+struct LogState {
+    entries: Vec<LogEntry>,
+    leaves: Vec<NodeHash>,
+    /// Every `(event, fingerprint)` already logged, so reloading the same
+    /// keys does not log them again.
+    recorded: HashSet<(KeyEvent, String)>,
+}
+
+/// Append-only Merkle tree log of client key enrollments, revocations and
+/// server keys, kept as one JSON entry per line at one path.
+///
+/// The stores that load these keys record them here before they take
+/// effect, so a key the server has trusted is always in the log. Auditors
+/// fetch signed tree heads and check that each one extends the last; a
+/// server that quietly swaps in a key has to log it or fork its history.
+pub struct TransparencyLog {
+    path: PathBuf,
+    state: RwLock<LogState>,
+}
+
+impl TransparencyLog {
+    /// Reads the log at `path`. A missing file is an empty log.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut state = LogState::default();
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for (index, line) in contents.lines().enumerate() {
+                    let entry: LogEntry = serde_json::from_str(line).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{}:{}: {}", path.display(), index + 1, e),
+                        )
+                    })?;
+                    state.push(entry);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        log::info!(
+            "Loaded transparency log with {} entries from {}",
+            state.entries.len(),
+            path.display()
+        );
+        Ok(Self {
+            path,
+            state: RwLock::new(state),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.state.read().unwrap().leaves.len() as u64
+    }
+
+    /// Entries `start..end`, cut short at the end of the log.
+    pub fn entries(&self, start: u64, end: u64) -> Vec<LogEntry> {
+        let state = self.state.read().unwrap();
+        let end = (end as usize).min(state.entries.len());
+        let start = (start as usize).min(end);
+        state.entries[start..end].to_vec()
+    }
+
+    /// The root hash of the first `tree_size` entries.
+    pub fn root(&self, tree_size: u64) -> Option<NodeHash> {
+        let state = self.state.read().unwrap();
+        let leaves = state.leaves.get(..tree_size as usize)?;
+        Some(merkle_root(leaves))
+    }
+
+    /// The current tree head, signed by `identity`.
+    pub fn tree_head(&self, identity: &SigningIdentity) -> Result<SignedTreeHead, CryptoError> {
+        let (tree_size, root_hash) = {
+            let state = self.state.read().unwrap();
+            (state.leaves.len() as u64, merkle_root(&state.leaves))
+        };
+        SignedTreeHead::sign(identity, tree_size, root_hash)
+    }
+
+    pub fn inclusion_proof(&self, leaf_index: u64, tree_size: u64) -> Option<InclusionProof> {
+        let state = self.state.read().unwrap();
+        if leaf_index >= tree_size {
+            return None;
+        }
+        let leaves = state.leaves.get(..tree_size as usize)?;
+        Some(InclusionProof {
+            leaf_index,
+            tree_size,
+            audit_path: inclusion_path(leaf_index as usize, leaves),
+        })
+    }
+
+    pub fn consistency_proof(&self, first_size: u64, second_size: u64) -> Option<ConsistencyProof> {
+        let state = self.state.read().unwrap();
+        if first_size > second_size {
+            return None;
+        }
+        let leaves = state.leaves.get(..second_size as usize)?;
+        let proof = if first_size == 0 {
+            Vec::new()
+        } else {
+            consistency_path(first_size as usize, leaves, true)
+        };
+        Some(ConsistencyProof {
+            first_size,
+            second_size,
+            proof,
+        })
+    }
+
+    /// Appends an entry unless the same event was already logged for the
+    /// key. Returns whether it did.
+    pub fn record(
+        &self,
+        event: KeyEvent,
+        algorithm: Option<SignatureAlgorithm>,
+        fingerprint: String,
+    ) -> io::Result<bool> {
+        let mut state = self.state.write().unwrap();
+        if state
+            .recorded
+            .contains(&(event.clone(), fingerprint.clone()))
+        {
+            return Ok(false);
+        }
+        let entry = LogEntry {
+            timestamp: unix_time(),
+            event,
+            algorithm,
+            fingerprint,
+        };
+        append_line(&self.path, &serde_json::to_vec(&entry)?)?;
+        log::info!(
+            "Logged {:?} for {} as entry {}",
+            entry.event,
+            entry.fingerprint,
+            state.entries.len()
+        );
+        state.push(entry);
+        Ok(true)
+    }
+
+    /// Logs every enrolled client key. Returns how many were new.
+    pub fn record_enrollments(&self, keys: &AuthorizedKeys) -> io::Result<usize> {
+        let mut logged = 0;
+        for (algorithm, public_key, username) in keys.iter() {
+            let event = KeyEvent::Enrolled {
+                username: username.to_string(),
+            };
+            logged += self.record(event, Some(algorithm), fingerprint(public_key))? as usize;
+        }
+        Ok(logged)
+    }
+
+    /// Logs every key `list` revokes. Returns how many were new.
+    pub fn record_revocations(&self, list: &RevocationList) -> io::Result<usize> {
+        let mut logged = 0;
+        for key in &list.revoked {
+            let event = KeyEvent::Revoked {
+                reason: key.reason.clone(),
+            };
+            logged += self.record(event, None, key.fingerprint.clone())? as usize;
+        }
+        Ok(logged)
+    }
+
+    /// Logs the server's current keys and, during a rotation, the next
+    /// ones. Returns how many were new.
+    pub fn record_server_identity(&self, identity: &ServerIdentity) -> io::Result<usize> {
+        let mut logged = 0;
+        let mut identities = vec![(ServerKeyRole::Current, &*identity.current)];
+        if let Some(next) = &identity.next {
+            identities.push((ServerKeyRole::Next, &*next.identity));
+        }
+        for (role, identity) in identities {
+            for algorithm in identity.algorithms() {
+                let fingerprint = identity.fingerprint(algorithm).unwrap_or_default();
+                let event = KeyEvent::ServerKey { role };
+                logged += self.record(event, Some(algorithm), fingerprint)? as usize;
+            }
+        }
+        Ok(logged)
+    }
+}
+
+impl LogState {
+    fn push(&mut self, entry: LogEntry) {
+        self.leaves.push(entry.leaf_hash());
+        self.recorded
+            .insert((entry.event.clone(), entry.fingerprint.clone()));
+        self.entries.push(entry);
+    }
+}
+
+/// Appends one line and syncs it, so an entry is on disk before the key it
+/// logs is trusted.
+fn append_line(path: &Path, line: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = line.to_vec();
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+fn leaf_hash(data: &[u8]) -> NodeHash {
+    Sha256::new()
+        .chain_update([0x00])
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn node_hash(left: &NodeHash, right: &NodeHash) -> NodeHash {
+    Sha256::new()
+        .chain_update([0x01])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// The largest power of two smaller than `n`, where the tree of `n > 1`
+/// leaves splits into its left and right subtrees.
+fn split(n: usize) -> usize {
+    n.next_power_of_two() / 2
+}
+
+fn merkle_root(leaves: &[NodeHash]) -> NodeHash {
+    match leaves.len() {
+        0 => Sha256::digest([]).into(),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&merkle_root(&leaves[..k]), &merkle_root(&leaves[k..]))
+        }
+    }
+}
+
+/// RFC 9162 section 2.1.3.1.
+fn inclusion_path(index: usize, leaves: &[NodeHash]) -> Vec<NodeHash> {
+    let n = leaves.len();
+    if n <= 1 {
+        return Vec::new();
+    }
+    let k = split(n);
+    if index < k {
+        let mut path = inclusion_path(index, &leaves[..k]);
+        path.push(merkle_root(&leaves[k..]));
+        path
+    } else {
+        let mut path = inclusion_path(index - k, &leaves[k..]);
+        path.push(merkle_root(&leaves[..k]));
+        path
+    }
+}
+
+/// RFC 9162 section 2.1.4.1. `complete` is whether the first `first`
+/// leaves are the whole of the tree the verifier already holds a root for.
+fn consistency_path(first: usize, leaves: &[NodeHash], complete: bool) -> Vec<NodeHash> {
+    let n = leaves.len();
+    if first == n {
+        return if complete {
+            Vec::new()
+        } else {
+            vec![merkle_root(leaves)]
+        };
+    }
+    let k = split(n);
+    if first <= k {
+        let mut path = consistency_path(first, &leaves[..k], complete);
+        path.push(merkle_root(&leaves[k..]));
+        path
+    } else {
+        let mut path = consistency_path(first - k, &leaves[k..], false);
+        path.push(merkle_root(&leaves[..k]));
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_verify_for_every_leaf_and_tree_size() {
+        let entries: Vec<LogEntry> = (0..9)
+            .map(|i| LogEntry {
+                timestamp: i,
+                event: KeyEvent::Revoked { reason: None },
+                algorithm: None,
+                fingerprint: format!("sha256:{:064x}", i),
+            })
+            .collect();
+        let leaves: Vec<NodeHash> = entries.iter().map(LogEntry::leaf_hash).collect();
+
+        for size in 1..=leaves.len() {
+            let root = merkle_root(&leaves[..size]);
+            for (index, entry) in entries[..size].iter().enumerate() {
+                let proof = InclusionProof {
+                    leaf_index: index as u64,
+                    tree_size: size as u64,
+                    audit_path: inclusion_path(index, &leaves[..size]),
+                };
+                assert!(proof.verify(entry, &root));
+                let other = &entries[(index + 1) % size];
+                assert_eq!(proof.verify(other, &root), size == 1);
+            }
+            for first in 1..=size {
+                let first_root = merkle_root(&leaves[..first]);
+                let proof = ConsistencyProof {
+                    first_size: first as u64,
+                    second_size: size as u64,
+                    proof: consistency_path(first, &leaves[..size], true),
+                };
+                assert!(proof.verify(&first_root, &root));
+                // A rewritten first leaf breaks every proof from it.
+                let mut forked = leaves[..first].to_vec();
+                forked[0] = leaf_hash(b"swapped key");
+                assert!(!proof.verify(&merkle_root(&forked), &root));
+            }
+        }
+    }
+
+    #[test]
+    fn logged_keys_survive_a_restart_and_are_not_logged_twice() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("transparency.log");
+        let server = SigningIdentity::generate();
+        let laptop = SigningIdentity::generate();
+        let laptop_key = laptop.public_key(SignatureAlgorithm::MlDsa65).unwrap();
+        let enrolled = KeyEvent::Enrolled {
+            username: "alice".to_string(),
+        };
+
+        let log = TransparencyLog::open(path.clone()).unwrap();
+        assert!(log
+            .record(
+                enrolled.clone(),
+                Some(SignatureAlgorithm::MlDsa65),
+                fingerprint(laptop_key)
+            )
+            .unwrap());
+        let first_head = log.tree_head(&server).unwrap();
+        assert!(!log
+            .record(
+                enrolled,
+                Some(SignatureAlgorithm::MlDsa65),
+                fingerprint(laptop_key)
+            )
+            .unwrap());
+        let revoked = KeyEvent::Revoked {
+            reason: Some("stolen laptop".to_string()),
+        };
+        assert!(log.record(revoked, None, fingerprint(laptop_key)).unwrap());
+        assert_eq!(log.size(), 2);
+
+        let log = TransparencyLog::open(path.clone()).unwrap();
+        let head = log.tree_head(&server).unwrap();
+        assert_eq!(head.tree_size, 2);
+        assert_eq!(Some(head.root_hash), log.root(2));
+        let server_key = server.public_key(SignatureAlgorithm::MlDsa65).unwrap();
+        head.verify(SignatureAlgorithm::MlDsa65, server_key)
+            .unwrap();
+        let mut forged = head.clone();
+        forged.tree_size = 1;
+        assert!(forged
+            .verify(SignatureAlgorithm::MlDsa65, server_key)
+            .is_err());
+
+        let entry = &log.entries(1, 2)[0];
+        let inclusion = log.inclusion_proof(1, 2).unwrap();
+        assert!(inclusion.verify(entry, &head.root_hash));
+        let consistency = log.consistency_proof(1, 2).unwrap();
+        assert!(consistency.verify(&first_head.root_hash, &head.root_hash));
+        assert!(log.inclusion_proof(2, 2).is_none());
+        assert!(log.consistency_proof(1, 3).is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}